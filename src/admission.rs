@@ -0,0 +1,917 @@
+//! Admission control for asynchronous operations
+//!
+//! Many asynchronous backends, such as GPUs or IO devices, can only process a
+//! limited amount of operations at a time. Submitting more work than that is
+//! at best useless and at worst harmful, as it wastes resources and can hurt
+//! the latency of every operation in flight.
+//!
+//! This module provides a limiter which keeps excess operations in the
+//! pending state, along with details on their position in the admission queue,
+//! and only hands them over to the backend as execution slots become free.
+//! Operations are admitted in a round-robin fashion across the tenants which
+//! submitted them, so that a single tenant cannot starve the others, and the
+//! amount of memory used by queued operations can be capped.
+//!
+//! An execution slot is released as soon as the associated operation reaches
+//! a final status, which includes the case where its server is dropped.
+//!
+//! Queued operations which are cancelled leave the queue right away. They are
+//! then launched without an execution slot, so that their server can tell the
+//! client that the operation was cancelled.
+
+use channels::OpChannels;
+use server::{AsyncOpServer, AsyncOpServerConfig};
+use status::{self, AsyncOpStatus, AsyncOpStatusDetails, AsyncOpStatusTraits,
+             NoDetails};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::mem;
+use std::sync::{Arc, Mutex, Weak};
+
+
+/// Identifier of the entity on behalf of which operations are submitted
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct TenantId(pub u64);
+
+
+/// Position of a pending operation in the admission queue
+///
+/// The limiter reports this information through the pending status details of
+/// queued operations, which must therefore be constructible from it. This is
+/// the counterpart of OpenCL's distinction between a command being submitted
+/// to the host driver and being queued on the device.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct QueuePosition {
+    /// Number of operations which will be admitted before this one
+    pub position: usize,
+
+    /// Number of operations from the same tenant which are ahead of this one
+    pub tenant_position: usize,
+}
+//
+impl AsyncOpStatusTraits for QueuePosition {}
+//
+impl From<QueuePosition> for NoDetails {
+    /// Standard statuses simply discard queue position information
+    fn from(_: QueuePosition) -> Self {
+        status::NO_DETAILS
+    }
+}
+
+
+/// Concurrency limiter with a fair admission queue
+///
+/// This object can be cheaply cloned, and all clones share the same execution
+/// slots and admission queue.
+///
+#[derive(Clone)]
+pub struct Limiter {
+    /// State shared between all clones of the limiter and admitted operations
+    shared: Arc<Mutex<LimiterState>>,
+}
+//
+impl Limiter {
+    /// Create a limiter which lets at most `max_running` operations run
+    /// concurrently, and at most `max_queued_bytes` bytes worth of operations
+    /// wait in the admission queue.
+    pub fn new(max_running: usize, max_queued_bytes: usize) -> Self {
+        assert!(max_running > 0, "At least one operation must be able to run");
+        Limiter {
+            shared: Arc::new(Mutex::new(
+                LimiterState {
+                    max_running,
+                    max_queued_bytes,
+                    running: 0,
+                    queued_bytes: 0,
+                    tenant_queues: VecDeque::new(),
+                    next_ticket: 0,
+                    departed: HashMap::new(),
+                    admitted: 0,
+                    rejected: 0,
+                }
+            )),
+        }
+    }
+
+    /// Submit an operation for execution on behalf of some tenant
+    ///
+    /// If an execution slot is available, the operation is admitted at once,
+    /// and `launch` is called on the current thread. Otherwise, the operation
+    /// is queued, kept in the pending state with queue position details, and
+    /// `launch` will later be called by whichever thread frees up a slot for
+    /// it. In both cases, `launch` should only start the operation (e.g. send
+    /// it to a worker thread or device queue), and return quickly.
+    ///
+    /// Queued operations are notified whenever their queue position changes.
+    /// These status updates are sent without holding the limiter's internal
+    /// lock, so clients may react to them by cancelling the operation.
+    ///
+    /// If the memory budget of the admission queue would be exceeded by
+    /// queuing the operation, it is handed back to the caller.
+    ///
+    pub fn submit<Config, F>(
+        &self,
+        tenant: TenantId,
+        server: AsyncOpServer<Config>,
+        launch: F
+    ) -> Result<(), QueueFull<Config, F>>
+        where Config: AsyncOpServerConfig + Send + 'static,
+              Config::StatusDetails: 'static,
+              <Config::StatusDetails as AsyncOpStatusDetails>::PendingDetails:
+                  From<QueuePosition>,
+              F: FnOnce(AsyncOpServer<AdmittedServerConfig<Config>>)
+                 + Send + 'static
+    {
+        // Operations which have already reached a final status do not need an
+        // execution slot, and would never release it anyway.
+        if server.is_final() {
+            launch(admit(server, None));
+            return Ok(());
+        }
+
+        // Check if we can admit the operation right away
+        let mut state = self.shared.lock().unwrap();
+        if state.running < state.max_running && state.tenant_queues.is_empty() {
+            state.running += 1;
+            state.admitted += 1;
+            mem::drop(state);
+            let slot = Slot { shared: self.shared.clone() };
+            launch(admit(server, Some(slot)));
+            return Ok(());
+        }
+
+        // Otherwise, check that the admission queue has room for it...
+        let footprint = mem::size_of::<QueuedOpImpl<Config, F>>();
+        if state.queued_bytes + footprint > state.max_queued_bytes {
+            state.rejected += 1;
+            return Err(QueueFull { server, launch });
+        }
+
+        // ...and queue it on behalf of the active tenant
+        let cancellation = server.cancellation();
+        let queued_op = Box::new(QueuedOpImpl { server, launch });
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.queued_bytes += footprint;
+        let index = state.enqueue(
+            tenant,
+            QueueEntry {
+                op: Some(queued_op),
+                footprint,
+                ticket,
+                position: None,
+            }
+        );
+        let reports = state.report_positions(index);
+        mem::drop(state);
+        Self::deliver(&self.shared, reports);
+
+        // If the operation is cancelled while queued, withdraw it at once
+        let shared = Arc::downgrade(&self.shared);
+        cancellation.on_cancel(move || {
+            Self::withdraw(&shared, tenant, ticket);
+        });
+        Ok(())
+    }
+
+    /// Query the current activity of the limiter
+    pub fn metrics(&self) -> LimiterMetrics {
+        let state = self.shared.lock().unwrap();
+        let queue_depth_per_tenant =
+            state.tenant_queues
+                 .iter()
+                 .map(|queue| (queue.tenant, queue.ops.len()))
+                 .collect::<HashMap<_, _>>();
+        LimiterMetrics {
+            running: state.running,
+            max_running: state.max_running,
+            queue_depth: queue_depth_per_tenant.values().sum(),
+            queue_depth_per_tenant,
+            queued_bytes: state.queued_bytes,
+            max_queued_bytes: state.max_queued_bytes,
+            admitted: state.admitted,
+            rejected: state.rejected,
+        }
+    }
+
+    /// Take a cancelled operation out of the admission queue, if it is still
+    /// there, and launch it without an execution slot
+    fn withdraw(shared: &Weak<Mutex<LimiterState>>,
+                tenant: TenantId,
+                ticket: u64) {
+        let shared = match shared.upgrade() {
+            Some(shared) => shared,
+            None => return,
+        };
+        let (op, reports) = {
+            let mut state = shared.lock().unwrap();
+            let (entry, index) = match state.remove(tenant, ticket) {
+                Some(removed) => removed,
+                None => return,
+            };
+            if entry.op.is_none() {
+                state.departed.insert(ticket, false);
+            }
+            (entry.op, state.report_positions(index))
+        };
+        Self::deliver(&shared, reports);
+        if let Some(op) = op {
+            op.admit(None);
+        }
+    }
+
+    /// Send queue position reports to their operations, without holding the
+    /// limiter's lock, then put the operations back in the admission queue
+    ///
+    /// While an operation is away, its queue position may change again, in
+    /// which case it is reported again, or the operation may leave the queue,
+    /// in which case it is launched here.
+    ///
+    fn deliver(shared: &Arc<Mutex<LimiterState>>,
+               mut reports: Vec<PositionReport>) {
+        while !reports.is_empty() {
+            for report in &mut reports {
+                report.op.report_position(report.position);
+            }
+            let mut departed = Vec::new();
+            {
+                let mut state = shared.lock().unwrap();
+                let mut outdated = Vec::new();
+                for mut report in reports {
+                    let entry = match state.find(report.tenant, report.ticket) {
+                        Some(entry) => entry,
+                        None => {
+                            let has_slot =
+                                state.departed.remove(&report.ticket).unwrap();
+                            departed.push((report.op, has_slot));
+                            continue;
+                        },
+                    };
+                    let position = entry.position.unwrap();
+                    if position == report.position {
+                        entry.op = Some(report.op);
+                    } else {
+                        report.position = position;
+                        outdated.push(report);
+                    }
+                }
+                reports = outdated;
+            }
+            for (op, has_slot) in departed {
+                let slot = if has_slot {
+                    Some(Slot { shared: shared.clone() })
+                } else {
+                    None
+                };
+                op.admit(slot);
+            }
+        }
+    }
+}
+
+
+/// Snapshot of a limiter's activity
+#[derive(Clone, Debug, PartialEq)]
+pub struct LimiterMetrics {
+    /// Number of operations which currently hold an execution slot
+    pub running: usize,
+
+    /// Maximal number of operations which can run concurrently
+    pub max_running: usize,
+
+    /// Number of operations waiting in the admission queue
+    pub queue_depth: usize,
+
+    /// Number of operations waiting in the admission queue, per tenant
+    pub queue_depth_per_tenant: HashMap<TenantId, usize>,
+
+    /// Amount of memory used by queued operations, in bytes
+    pub queued_bytes: usize,
+
+    /// Maximal amount of memory which queued operations may use, in bytes
+    pub max_queued_bytes: usize,
+
+    /// Number of operations which were admitted so far
+    pub admitted: u64,
+
+    /// Number of operations which were rejected due to a full queue so far
+    pub rejected: u64,
+}
+
+
+/// Error returned when an operation does not fit in the admission queue
+///
+/// The server and launch function are handed back to the caller, who may try
+/// again later or report an error to the client.
+///
+pub struct QueueFull<Config: AsyncOpServerConfig, F> {
+    /// Server of the rejected operation
+    pub server: AsyncOpServer<Config>,
+
+    /// Launch function of the rejected operation
+    pub launch: F,
+}
+//
+impl<Config: AsyncOpServerConfig, F> fmt::Debug for QueueFull<Config, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "QueueFull {{ .. }}")
+    }
+}
+//
+impl<Config: AsyncOpServerConfig, F> fmt::Display for QueueFull<Config, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The admission queue is full")
+    }
+}
+//
+impl<Config: AsyncOpServerConfig, F> Error for QueueFull<Config, F> {}
+
+
+/// Server configuration of admitted operations, which releases the execution
+/// slot once the operation has reached a final status
+pub struct AdmittedServerConfig<Config: AsyncOpServerConfig> {
+    /// Configuration of the underlying operation
+    inner: Config,
+
+    /// Execution slot held by the operation, if any
+    slot: Option<Slot>,
+}
+//
+impl<Config: AsyncOpServerConfig> AsyncOpServerConfig
+    for AdmittedServerConfig<Config>
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Config::StatusDetails;
+
+    /// Method used to send status updates to the client
+    fn update(&mut self, status: AsyncOpStatus<Self::StatusDetails>) {
+        let is_final = status::is_final(&status);
+        self.inner.update(status);
+        if is_final {
+            self.slot.take();
+        }
+    }
+
+    /// Side channels shared with the client
+    fn channels(&self) -> &OpChannels {
+        self.inner.channels()
+    }
+}
+
+
+/// Admit an operation by wrapping its configuration with an execution slot,
+/// if it needs one
+fn admit<Config: AsyncOpServerConfig>(
+    server: AsyncOpServer<Config>,
+    slot: Option<Slot>
+) -> AsyncOpServer<AdmittedServerConfig<Config>> {
+    server.map_config(|inner| AdmittedServerConfig {
+        inner: inner.into_inner(),
+        slot,
+    })
+}
+
+
+/// Execution slot, which admits the next queued operation(s) when released
+struct Slot {
+    /// Limiter from which the slot was obtained
+    shared: Arc<Mutex<LimiterState>>,
+}
+//
+impl Drop for Slot {
+    fn drop(&mut self) {
+        // Release the slot, and pick the operations that can be admitted.
+        // Those which are away for a position report are launched on return.
+        let mut admitted = Vec::new();
+        let reports = {
+            let mut state = self.shared.lock().unwrap();
+            state.running -= 1;
+            let mut admissions = 0;
+            while state.running < state.max_running {
+                match state.dequeue() {
+                    Some(entry) => {
+                        state.running += 1;
+                        state.admitted += 1;
+                        state.queued_bytes -= entry.footprint;
+                        admissions += 1;
+                        match entry.op {
+                            Some(op) => admitted.push(op),
+                            None => {
+                                state.departed.insert(entry.ticket, true);
+                            },
+                        }
+                    },
+                    None => break,
+                }
+            }
+            // Every queued operation moves up by the number of admissions
+            if admissions > 0 {
+                state.report_positions(0)
+            } else {
+                Vec::new()
+            }
+        };
+
+        // Notify and launch them outside of the lock, so that they may use the
+        // limiter
+        Limiter::deliver(&self.shared, reports);
+        for op in admitted {
+            op.admit(Some(Slot { shared: self.shared.clone() }));
+        }
+    }
+}
+
+
+/// Internal state of the limiter
+struct LimiterState {
+    /// Maximal number of operations which can run concurrently
+    max_running: usize,
+
+    /// Maximal amount of memory which queued operations may use
+    max_queued_bytes: usize,
+
+    /// Number of operations which currently hold an execution slot
+    running: usize,
+
+    /// Amount of memory currently used by queued operations
+    queued_bytes: usize,
+
+    /// Per-tenant operation queues, in round-robin admission order. Tenants
+    /// are removed from this list when their queue becomes empty.
+    tenant_queues: VecDeque<TenantQueue>,
+
+    /// Ticket of the next queued operation, which identifies it in the queue
+    next_ticket: u64,
+
+    /// Operations which left the queue while away for a position report, and
+    /// whether they were given an execution slot
+    departed: HashMap<u64, bool>,
+
+    /// Number of operations which were admitted so far
+    admitted: u64,
+
+    /// Number of operations which were rejected so far
+    rejected: u64,
+}
+//
+impl LimiterState {
+    /// Add an operation at the end of a tenant's queue, and return its index
+    /// in that queue
+    fn enqueue(&mut self, tenant: TenantId, entry: QueueEntry) -> usize {
+        match self.tenant_queues.iter_mut().find(|q| q.tenant == tenant) {
+            Some(queue) => {
+                queue.ops.push_back(entry);
+                queue.ops.len() - 1
+            },
+            None => {
+                let mut ops = VecDeque::new();
+                ops.push_back(entry);
+                self.tenant_queues.push_back(TenantQueue { tenant, ops });
+                0
+            }
+        }
+    }
+
+    /// Find an operation in a tenant's queue, if it is still there
+    fn find(&mut self,
+            tenant: TenantId,
+            ticket: u64) -> Option<&mut QueueEntry> {
+        self.tenant_queues
+            .iter_mut()
+            .find(|q| q.tenant == tenant)?
+            .ops
+            .iter_mut()
+            .find(|e| e.ticket == ticket)
+    }
+
+    /// Remove an operation from a tenant's queue, if it is still there, and
+    /// tell where it was in that queue
+    fn remove(&mut self,
+              tenant: TenantId,
+              ticket: u64) -> Option<(QueueEntry, usize)> {
+        let queue_idx =
+            self.tenant_queues.iter().position(|q| q.tenant == tenant)?;
+        let queue = &mut self.tenant_queues[queue_idx];
+        let index = queue.ops.iter().position(|e| e.ticket == ticket)?;
+        let entry = queue.ops.remove(index)?;
+        if queue.ops.is_empty() {
+            self.tenant_queues.remove(queue_idx);
+        }
+        self.queued_bytes -= entry.footprint;
+        Some((entry, index))
+    }
+
+    /// Extract the next operation to be admitted, if any
+    fn dequeue(&mut self) -> Option<QueueEntry> {
+        // Take the first operation from the tenant whose turn it is...
+        let mut queue = self.tenant_queues.pop_front()?;
+        let entry = queue.ops.pop_front();
+
+        // ...then move that tenant to the back of the line
+        if !queue.ops.is_empty() {
+            self.tenant_queues.push_back(queue);
+        }
+        entry
+    }
+
+    /// Update the queue position of queued operations, given the index in
+    /// their tenant's queue from which positions may have changed, and take
+    /// out the operations which must be told about it
+    fn report_positions(&mut self,
+                        first_changed: usize) -> Vec<PositionReport> {
+        // Under round-robin admission, operations are admitted in rounds, each
+        // of which takes the N-th operation of every tenant which has enough
+        // of them, in tenant order. Earlier rounds are left untouched.
+        let mut position = self.tenant_queues
+                               .iter()
+                               .map(|q| q.ops.len().min(first_changed))
+                               .sum::<usize>();
+        let mut round = first_changed;
+        let mut reports = Vec::new();
+        let mut active = self.tenant_queues
+                             .iter_mut()
+                             .filter(|q| q.ops.len() > round)
+                             .collect::<Vec<_>>();
+        while !active.is_empty() {
+            for queue in active.iter_mut() {
+                let new_position =
+                    QueuePosition { position, tenant_position: round };
+                let entry = &mut queue.ops[round];
+                if entry.position != Some(new_position) {
+                    entry.position = Some(new_position);
+                    // Operations which are already away for a report will be
+                    // told about their new position upon return
+                    if let Some(op) = entry.op.take() {
+                        reports.push(PositionReport {
+                            tenant: queue.tenant,
+                            ticket: entry.ticket,
+                            op,
+                            position: new_position,
+                        });
+                    }
+                }
+                position += 1;
+            }
+            round += 1;
+            active.retain(|q| q.ops.len() > round);
+        }
+        reports
+    }
+}
+
+
+/// Queue of operations submitted by a given tenant
+struct TenantQueue {
+    /// Tenant which submitted the operations
+    tenant: TenantId,
+
+    /// Operations waiting for admission, in submission order
+    ops: VecDeque<QueueEntry>,
+}
+
+
+/// Operation waiting for admission, with its memory footprint
+struct QueueEntry {
+    /// Type-erased operation, unless it is away for a position report
+    op: Option<Box<dyn QueuedOp>>,
+
+    /// Amount of queue memory used by the operation
+    footprint: usize,
+
+    /// Identifier of the operation within the admission queue
+    ticket: u64,
+
+    /// Last queue position of the operation, if it was computed already
+    position: Option<QueuePosition>,
+}
+
+
+/// Queue position which must be reported to an operation, outside of the
+/// limiter's lock
+struct PositionReport {
+    /// Tenant which submitted the operation
+    tenant: TenantId,
+
+    /// Identifier of the operation within the admission queue
+    ticket: u64,
+
+    /// Operation which was taken out of the queue for the report
+    op: Box<dyn QueuedOp>,
+
+    /// Queue position to be reported
+    position: QueuePosition,
+}
+
+
+/// Type-erased interface to queued operations
+trait QueuedOp: Send {
+    /// Report the operation's queue position to its client
+    fn report_position(&mut self, position: QueuePosition);
+
+    /// Admit the operation, handing it over to its launch function along
+    /// with an execution slot, unless it was cancelled while queued
+    fn admit(self: Box<Self>, slot: Option<Slot>);
+}
+//
+struct QueuedOpImpl<Config: AsyncOpServerConfig, F> {
+    /// Server of the queued operation
+    server: AsyncOpServer<Config>,
+
+    /// Function used to start the operation once admitted
+    launch: F,
+}
+//
+impl<Config, F> QueuedOp for QueuedOpImpl<Config, F>
+    where Config: AsyncOpServerConfig + Send,
+          <Config::StatusDetails as AsyncOpStatusDetails>::PendingDetails:
+              From<QueuePosition>,
+          F: FnOnce(AsyncOpServer<AdmittedServerConfig<Config>>) + Send
+{
+    /// Report the operation's queue position to its client
+    fn report_position(&mut self, position: QueuePosition) {
+        self.server.update(AsyncOpStatus::Pending(position.into()));
+    }
+
+    /// Admit the operation, handing it over to its launch function along
+    /// with an execution slot, unless it was cancelled while queued
+    fn admit(self: Box<Self>, slot: Option<Slot>) {
+        let this = *self;
+        (this.launch)(admit(this.server, slot));
+    }
+}
+
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use admission::*;
+    use cancellation::CancellationToken;
+    use client::IAsyncOpClient;
+    use executor::inline::InlineCallbackExecutor;
+    use multithread::callback;
+    use multithread::polling::{self, AsyncOp, AsyncOpClient};
+    use status::NO_DETAILS;
+    use std::sync::{Arc, Mutex};
+
+    /// Status details reporting queue positions
+    #[derive(Clone, Debug, PartialEq)]
+    struct QueueDetails {}
+    //
+    impl AsyncOpStatusDetails for QueueDetails {
+        type PendingDetails = QueuePosition;
+        type RunningDetails = NoDetails;
+        type DoneDetails = NoDetails;
+        type CancelledDetails = NoDetails;
+        type ErrorDetails = NoDetails;
+    }
+    //
+    impl AsyncOpStatusTraits for QueueDetails {}
+
+    /// Admitted operation servers, in order of admission
+    type Admitted = Arc<Mutex<Vec<
+        AsyncOpServer<AdmittedServerConfig<
+            polling::PollingServerConfig<QueueDetails>
+        >>
+    >>>;
+
+    /// Create a new operation, initially pending at the front of the queue
+    fn new_op() -> AsyncOp<QueueDetails> {
+        AsyncOp::new(
+            AsyncOpStatus::Pending(QueuePosition { position: 0,
+                                                   tenant_position: 0 })
+        )
+    }
+
+    /// Submit a new operation to a limiter, recording its admission
+    fn submit(limiter: &Limiter,
+              tenant: u64,
+              admitted: &Admitted) -> AsyncOpClient<QueueDetails> {
+        let (server, client) = new_op().split();
+        let admitted = admitted.clone();
+        limiter.submit(TenantId(tenant),
+                       server,
+                       move |server| admitted.lock().unwrap().push(server))
+               .unwrap();
+        client
+    }
+
+    /// Check the queue position reported by a pending operation
+    fn check_position(client: &mut AsyncOpClient<QueueDetails>,
+                      position: usize,
+                      tenant_position: usize) {
+        assert_eq!(
+            *client.status(),
+            AsyncOpStatus::Pending(QueuePosition { position, tenant_position })
+        );
+    }
+
+    /// Check that operations are admitted immediately when slots are free
+    #[test]
+    fn immediate_admission() {
+        let limiter = Limiter::new(2, 1024);
+        let admitted = Admitted::default();
+        let _client1 = submit(&limiter, 0, &admitted);
+        let _client2 = submit(&limiter, 1, &admitted);
+        assert_eq!(admitted.lock().unwrap().len(), 2);
+
+        let metrics = limiter.metrics();
+        assert_eq!(metrics.running, 2);
+        assert_eq!(metrics.queue_depth, 0);
+        assert_eq!(metrics.queued_bytes, 0);
+        assert_eq!(metrics.admitted, 2);
+    }
+
+    /// Check that excess operations are queued and admitted later on
+    #[test]
+    fn queuing() {
+        // Fill the only execution slot, then queue two more operations
+        let limiter = Limiter::new(1, 1024);
+        let admitted = Admitted::default();
+        let mut client1 = submit(&limiter, 0, &admitted);
+        let mut client2 = submit(&limiter, 0, &admitted);
+        let mut client3 = submit(&limiter, 0, &admitted);
+        assert_eq!(admitted.lock().unwrap().len(), 1);
+        check_position(&mut client2, 0, 0);
+        check_position(&mut client3, 1, 1);
+
+        let metrics = limiter.metrics();
+        assert_eq!(metrics.running, 1);
+        assert_eq!(metrics.queue_depth, 2);
+        assert_eq!(metrics.queue_depth_per_tenant[&TenantId(0)], 2);
+        assert!(metrics.queued_bytes > 0);
+
+        // Completing the running operation should admit the next one
+        let mut server1 = admitted.lock().unwrap().remove(0);
+        server1.update(AsyncOpStatus::Running(NO_DETAILS));
+        assert_eq!(admitted.lock().unwrap().len(), 0);
+        server1.update(AsyncOpStatus::Done(NO_DETAILS));
+        assert_eq!(*client1.status(), AsyncOpStatus::Done(NO_DETAILS));
+        assert_eq!(admitted.lock().unwrap().len(), 1);
+        check_position(&mut client3, 0, 0);
+
+        // Killing a server should release its slot as well
+        let server2 = admitted.lock().unwrap().remove(0);
+        ::std::mem::drop(server2);
+        assert_eq!(admitted.lock().unwrap().len(), 1);
+        let metrics = limiter.metrics();
+        assert_eq!(metrics.running, 1);
+        assert_eq!(metrics.queue_depth, 0);
+        assert_eq!(metrics.queued_bytes, 0);
+        assert_eq!(metrics.admitted, 3);
+    }
+
+    /// Check that tenants are served in a round-robin fashion
+    #[test]
+    fn fairness() {
+        // Let tenant 0 flood the queue, then have tenant 1 submit some work
+        let limiter = Limiter::new(1, 1024);
+        let admitted = Admitted::default();
+        let _running = submit(&limiter, 0, &admitted);
+        let mut a1 = submit(&limiter, 0, &admitted);
+        let mut a2 = submit(&limiter, 0, &admitted);
+        let mut a3 = submit(&limiter, 0, &admitted);
+        let mut b1 = submit(&limiter, 1, &admitted);
+        let mut b2 = submit(&limiter, 1, &admitted);
+
+        // Tenant 1 should not have to wait for all of tenant 0's work
+        check_position(&mut a1, 0, 0);
+        check_position(&mut b1, 1, 0);
+        check_position(&mut a2, 2, 1);
+        check_position(&mut b2, 3, 1);
+        check_position(&mut a3, 4, 2);
+
+        // After an admission, tenant 1 should come first
+        let mut server = admitted.lock().unwrap().remove(0);
+        server.update(AsyncOpStatus::Done(NO_DETAILS));
+        check_position(&mut b1, 0, 0);
+        check_position(&mut a2, 1, 0);
+        check_position(&mut b2, 2, 1);
+        check_position(&mut a3, 3, 1);
+    }
+
+    /// Check that cancelled operations leave the admission queue at once
+    #[test]
+    fn cancellation() {
+        // Fill the only execution slot, then queue three more operations
+        let limiter = Limiter::new(1, 1024);
+        let admitted = Admitted::default();
+        let _running = submit(&limiter, 0, &admitted);
+        let mut client1 = submit(&limiter, 0, &admitted);
+        let mut client2 = submit(&limiter, 1, &admitted);
+        let mut client3 = submit(&limiter, 0, &admitted);
+        check_position(&mut client3, 2, 1);
+
+        // Cancelling a queued operation should launch it without a slot...
+        client1.cancel();
+        assert_eq!(admitted.lock().unwrap().len(), 2);
+        let mut server1 = admitted.lock().unwrap().remove(1);
+        assert!(server1.cancelled());
+        server1.update(AsyncOpStatus::Cancelled(NO_DETAILS));
+        assert_eq!(*client1.status(), AsyncOpStatus::Cancelled(NO_DETAILS));
+
+        // ...and move the other operations up the queue
+        check_position(&mut client3, 0, 0);
+        check_position(&mut client2, 1, 0);
+        let metrics = limiter.metrics();
+        assert_eq!(metrics.running, 1);
+        assert_eq!(metrics.queue_depth, 2);
+        assert_eq!(metrics.admitted, 1);
+
+        // Cancelling an admitted operation should not affect the queue
+        let mut server0 = admitted.lock().unwrap().remove(0);
+        server0.update(AsyncOpStatus::Done(NO_DETAILS));
+        assert_eq!(admitted.lock().unwrap().len(), 1);
+        client3.cancel();
+        assert_eq!(admitted.lock().unwrap().len(), 1);
+        check_position(&mut client2, 0, 0);
+        assert_eq!(limiter.metrics().queue_depth, 1);
+    }
+
+    /// Check that clients may cancel operations in reaction to queue position
+    /// updates, which are sent without holding the limiter's lock
+    #[test]
+    fn cancel_from_callback() {
+        // Fill the only execution slot, then queue two more operations
+        let limiter = Limiter::new(1, 1024);
+        let admitted = Admitted::default();
+        let _running = submit(&limiter, 0, &admitted);
+        let mut client1 = submit(&limiter, 0, &admitted);
+
+        // The second one gives up as soon as it reaches the front of the queue
+        let token = CancellationToken::new();
+        let statuses = Arc::new(Mutex::new(Vec::new()));
+        let (server2, _client2) = {
+            let canceller = token.clone();
+            let statuses = statuses.clone();
+            callback::new_send_async_op_with_token(
+                move |status: AsyncOpStatus<QueueDetails>| {
+                    if let AsyncOpStatus::Pending(position) = status {
+                        if position.position == 0 {
+                            canceller.cancel();
+                        }
+                    }
+                    statuses.lock().unwrap().push(status);
+                },
+                &mut InlineCallbackExecutor::new(),
+                AsyncOpStatus::Pending(QueuePosition { position: 0,
+                                                       tenant_position: 0 }),
+                &token
+            ).split()
+        };
+        let launched = Arc::new(Mutex::new(Vec::new()));
+        {
+            let launched = launched.clone();
+            limiter.submit(TenantId(1), server2, move |server| {
+                launched.lock().unwrap().push(server)
+            }).unwrap();
+        }
+        assert_eq!(launched.lock().unwrap().len(), 0);
+
+        // Cancelling the first one should move it up, and get it cancelled
+        client1.cancel();
+        let mut server2 = launched.lock().unwrap().pop().unwrap();
+        assert!(server2.cancelled());
+        server2.update(AsyncOpStatus::Cancelled(NO_DETAILS));
+        assert_eq!(
+            *statuses.lock().unwrap(),
+            vec![AsyncOpStatus::Pending(QueuePosition { position: 1,
+                                                        tenant_position: 0 }),
+                 AsyncOpStatus::Pending(QueuePosition { position: 0,
+                                                        tenant_position: 0 }),
+                 AsyncOpStatus::Cancelled(NO_DETAILS)]
+        );
+        let metrics = limiter.metrics();
+        assert_eq!(metrics.running, 1);
+        assert_eq!(metrics.queue_depth, 0);
+        assert_eq!(metrics.queued_bytes, 0);
+    }
+
+    /// Check that the memory budget of the admission queue is enforced
+    #[test]
+    fn memory_cap() {
+        // Measure the footprint of a queued operation
+        let limiter = Limiter::new(1, 1024);
+        let admitted = Admitted::default();
+        let _running = submit(&limiter, 0, &admitted);
+        let _queued = submit(&limiter, 0, &admitted);
+        let footprint = limiter.metrics().queued_bytes;
+
+        // Only leave room for one queued operation
+        let limiter = Limiter::new(1, footprint);
+        let _running = submit(&limiter, 0, &admitted);
+        let _queued = submit(&limiter, 0, &admitted);
+        let (server, mut client) = new_op().split();
+        let result = limiter.submit(TenantId(1), server, |_| {});
+
+        // The rejected operation should be handed back, and still be usable
+        let QueueFull { mut server, .. } = result.unwrap_err();
+        server.update(AsyncOpStatus::Done(NO_DETAILS));
+        assert_eq!(*client.status(), AsyncOpStatus::Done(NO_DETAILS));
+
+        let metrics = limiter.metrics();
+        assert_eq!(metrics.queue_depth, 1);
+        assert_eq!(metrics.rejected, 1);
+    }
+}
@@ -0,0 +1,351 @@
+//! Cancellation tokens, shared by many asynchronous operations
+//!
+//! Every asynchronous operation has a cancellation flag, which its client sets
+//! in order to request the cancellation of the operation, and which its server
+//! checks. Sometimes, however, a whole batch of operations must be cancelled at
+//! once, for example when a user logs out or a window is closed.
+//!
+//! Cancellation tokens address this need. They are organized as trees: a token
+//! can have child tokens, which are cancelled whenever their parent is. The
+//! cancellation flag of each operation is itself a token, which can be created
+//! as the child of a user-provided token, so that cancelling that token also
+//! cancels every operation which was linked to it. Conversely, cancelling an
+//! operation or a child token does not affect its parent.
+//!
+//! Cancellation is propagated eagerly, so checking whether a token has been
+//! cancelled is as cheap as reading an atomic flag. Code which cannot afford
+//! to periodically check the token, such as a server thread which is blocked
+//! in a system call, can instead wait for the cancellation or register a
+//! callback which will be invoked as soon as the token is cancelled.
+
+use std::fmt;
+use std::ptr;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+
+/// Cancellation token, possibly shared by many asynchronous operations
+///
+/// Cloning a token produces another handle to the same token, whereas calling
+/// child() produces a new token which is cancelled along with its parent.
+///
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    /// Shared state of the token
+    node: Arc<TokenNode>,
+}
+//
+impl CancellationToken {
+    /// Create a new token, which is not cancelled
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a child token, which will be cancelled when this one is
+    pub fn child(&self) -> Self {
+        let mut state = self.node.state.lock().unwrap();
+
+        // Forget about children which don't exist anymore, while making sure
+        // that this process is amortized over many insertions
+        if state.children.len() == state.children.capacity() {
+            state.children.retain(|child| child.strong_count() > 0);
+        }
+
+        // Create the child token, cancelled if we are. Checking our own flag
+        // while holding the lock ensures we cannot miss a concurrent cancel().
+        let child = CancellationToken {
+            node: Arc::new(TokenNode {
+                cancelled: AtomicBool::new(self.is_cancelled()),
+                ..TokenNode::default()
+            }),
+        };
+        state.children.push(Arc::downgrade(&child.node));
+        child
+    }
+
+    /// Cancel this token and all of its descendants
+    pub fn cancel(&self) {
+        self.node.cancel();
+    }
+
+    /// Check whether this token has been cancelled
+    pub fn is_cancelled(&self) -> bool {
+        self.node.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Register a callback to be invoked when this token is cancelled
+    ///
+    /// The callback runs on the thread which cancels the token, or right away
+    /// if the token is already cancelled, so it should be short and must not
+    /// block. A typical use is to interrupt a blocking operation.
+    ///
+    pub fn on_cancel<F: FnOnce() + Send + 'static>(&self, callback: F) {
+        {
+            let mut state = self.node.state.lock().unwrap();
+            if !self.is_cancelled() {
+                state.callbacks.push(Box::new(callback));
+                return;
+            }
+        }
+        callback();
+    }
+
+    /// Block until this token is cancelled
+    pub fn wait(&self) {
+        let mut state = self.node.state.lock().unwrap();
+        while !self.is_cancelled() {
+            state = self.node.cancel_cv.wait(state).unwrap();
+        }
+    }
+
+    /// Block until this token is cancelled or a timeout has elapsed, and tell
+    /// whether the token was cancelled
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.node.state.lock().unwrap();
+        while !self.is_cancelled() {
+            let now = Instant::now();
+            if now >= deadline { return false; }
+            state = self.node.cancel_cv.wait_timeout(state, deadline - now)
+                                       .unwrap()
+                                       .0;
+        }
+        true
+    }
+}
+//
+impl fmt::Debug for CancellationToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CancellationToken")
+         .field("cancelled", &self.is_cancelled())
+         .finish()
+    }
+}
+
+
+/// Callback invoked when a token is cancelled
+type CancelCallback = Box<dyn FnOnce() + Send>;
+
+
+/// Set of tokens whose cancellation wakes up the waiters of some object
+///
+/// Blocking primitives which can be interrupted by whichever token they are
+/// given must register a wakeup callback with every such token, but only once
+/// per token, lest callbacks pile up on tokens which are used for many waits.
+///
+#[derive(Debug, Default)]
+pub(crate) struct CancelWakeups {
+    /// Tokens with which a wakeup callback was registered
+    tokens: Mutex<Vec<Weak<TokenNode>>>,
+}
+//
+impl CancelWakeups {
+    /// Make sure that waiters are woken up when a token is cancelled, by
+    /// registering a wakeup callback unless this was already done for it
+    pub(crate) fn register<F>(&self, token: &CancellationToken, wakeup: F)
+        where F: FnOnce() + Send + 'static
+    {
+        {
+            let mut tokens = self.tokens.lock().unwrap();
+            tokens.retain(|node| node.strong_count() > 0);
+            let known = tokens.iter().any(|node| {
+                ptr::eq(node.as_ptr(), Arc::as_ptr(&token.node))
+            });
+            if known { return; }
+            tokens.push(Arc::downgrade(&token.node));
+        }
+        token.on_cancel(wakeup);
+    }
+}
+
+
+/// Shared state of a cancellation token
+#[derive(Default)]
+struct TokenNode {
+    /// Whether the token has been cancelled
+    cancelled: AtomicBool,
+
+    /// Things to be done when the token is cancelled
+    state: Mutex<TokenState>,
+
+    /// Condition variable used to wake up threads waiting for cancellation
+    cancel_cv: Condvar,
+}
+//
+impl TokenNode {
+    /// Cancel this token and all of its descendants
+    fn cancel(&self) {
+        // Cancelling a token twice has no effect
+        if self.cancelled.swap(true, Ordering::AcqRel) { return; }
+
+        // Children and callbacks cannot be added anymore without seeing the
+        // cancellation, so we can take them away, then wake up waiters...
+        let state = {
+            let mut state = self.state.lock().unwrap();
+            self.cancel_cv.notify_all();
+            ::std::mem::take(&mut *state)
+        };
+
+        // ...and take care of children and callbacks without holding the lock
+        for child in state.children.iter().filter_map(Weak::upgrade) {
+            child.cancel();
+        }
+        for callback in state.callbacks {
+            callback();
+        }
+    }
+}
+
+
+/// Things to be done when a token is cancelled
+#[derive(Default)]
+struct TokenState {
+    /// Child tokens, which must be cancelled along with this one
+    children: Vec<Weak<TokenNode>>,
+
+    /// Callbacks, which must be invoked when this token is cancelled
+    callbacks: Vec<CancelCallback>,
+}
+
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use cancellation::*;
+    use client::IAsyncOpClient;
+    use executor::inline::InlineCallbackExecutor;
+    use multithread::{blocking, callback, polling};
+    use status::{self, StandardAsyncOpStatus};
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+    use timeout;
+
+    /// Check that cancellation propagates from parents to children only
+    #[test]
+    fn token_tree() {
+        let root = CancellationToken::new();
+        let child = root.child();
+        let grandchild = child.child();
+        let sibling = root.child();
+        assert!(!root.is_cancelled() && !child.is_cancelled());
+
+        // Cancelling a child should only affect its descendants
+        child.cancel();
+        assert!(child.is_cancelled() && grandchild.is_cancelled());
+        assert!(!root.is_cancelled() && !sibling.is_cancelled());
+
+        // Cancelling the root should affect everyone, including new children
+        root.clone().cancel();
+        assert!(root.is_cancelled() && sibling.is_cancelled());
+        assert!(root.child().is_cancelled());
+    }
+
+    /// Check that dead children are eventually forgotten
+    #[test]
+    fn pruning() {
+        let root = CancellationToken::new();
+        for _ in 0..1000 {
+            root.child();
+        }
+        assert!(root.node.state.lock().unwrap().children.len() < 1000);
+    }
+
+    /// Check that tokens can be linked to operations in every mode
+    #[test]
+    fn linked_ops() {
+        // Link one operation of each kind to a token
+        let token = CancellationToken::new();
+        let (polling_server, mut polling_client) =
+            polling::AsyncOp::with_token(status::PENDING, &token).split();
+        let (blocking_server, _blocking_client) =
+            blocking::AsyncOp::with_token(status::PENDING, &token).split();
+        let mut executor = InlineCallbackExecutor::new();
+        let (callback_server, _callback_client) =
+            callback::new_async_op_with_token(
+                |_: StandardAsyncOpStatus| {},
+                &mut executor,
+                status::PENDING,
+                &token
+            ).split();
+
+        // Cancelling one operation should not affect the others
+        polling_client.cancel();
+        assert!(polling_server.cancelled());
+        assert!(!blocking_server.cancelled());
+        assert!(!callback_server.cancelled());
+
+        // Cancelling the token should affect all of them
+        token.cancel();
+        assert!(blocking_server.cancelled());
+        assert!(callback_server.cancelled());
+    }
+
+    /// Check that cancellation callbacks are invoked exactly once
+    #[test]
+    fn callbacks() {
+        // Callbacks registered before cancellation run upon cancellation
+        let token = CancellationToken::new();
+        let counter = Arc::new(AtomicUsize::new(0));
+        let c_counter = counter.clone();
+        let child = token.child();
+        child.on_cancel(move || {
+            c_counter.fetch_add(1, Ordering::Relaxed);
+        });
+        assert_eq!(counter.load(Ordering::Relaxed), 0);
+        token.cancel();
+        token.cancel();
+        assert_eq!(counter.load(Ordering::Relaxed), 1);
+
+        // Callbacks registered after cancellation run right away
+        let c_counter = counter.clone();
+        token.on_cancel(move || {
+            c_counter.fetch_add(1, Ordering::Relaxed);
+        });
+        assert_eq!(counter.load(Ordering::Relaxed), 2);
+    }
+
+    /// Check that wakeups are registered once with every token
+    #[test]
+    fn wakeups() {
+        let wakeups = CancelWakeups::default();
+        let counter = Arc::new(AtomicUsize::new(0));
+        let register = |token: &CancellationToken| {
+            let c_counter = counter.clone();
+            wakeups.register(token, move || {
+                c_counter.fetch_add(1, Ordering::Relaxed);
+            });
+        };
+        let token_a = CancellationToken::new();
+        let token_b = CancellationToken::new();
+        register(&token_a);
+        register(&token_a);
+        register(&token_b);
+        assert_eq!(token_a.node.state.lock().unwrap().callbacks.len(), 1);
+        token_b.cancel();
+        assert_eq!(counter.load(Ordering::Relaxed), 1);
+        token_a.cancel();
+        assert_eq!(counter.load(Ordering::Relaxed), 2);
+    }
+
+    /// Check that servers can wait for cancellation instead of polling
+    #[test]
+    fn waiting() {
+        // A server can wait for its client to cancel the operation
+        let (server, mut client) =
+            blocking::AsyncOp::new(status::RUNNING).split();
+        let cancellation = server.cancellation();
+        assert!(!cancellation.wait_timeout(Duration::from_millis(1)));
+        let waiter = thread::spawn(move || {
+            cancellation.wait();
+            server
+        });
+        client.cancel();
+        assert!(waiter.join().unwrap().cancelled());
+
+        // Operation deadlines also wake up waiting servers
+        let (server, _client) = polling::AsyncOp::new(status::RUNNING).split();
+        let server = timeout::with_timeout(server, Duration::from_millis(10));
+        assert!(server.cancellation().wait_timeout(Duration::from_secs(10)));
+    }
+}
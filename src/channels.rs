@@ -0,0 +1,220 @@
+//! Side channels shared by the client and server of an operation
+//!
+//! Besides the status channel, through which the server reports the status of
+//! an operation, the client and server of an operation share a number of side
+//! channels: a cancellation token, a pause flag, a control channel and a
+//! result stream. This module bundles them into a single handle, so that
+//! monitoring modes and server configuration wrappers only have one object to
+//! carry around and forward.
+//!
+//! Every operation needs a cancellation token, so it is created right away.
+//! The other channels are only created the first time they are used, so that
+//! operations which are never paused, controlled or streamed do not pay for
+//! them. Wrappers which need a token of their own, for example in order to
+//! cancel the operation when a deadline expires, can derive a handle which
+//! uses another token but shares the other channels.
+//!
+//! Clients hold their side channels through a ClientChannels handle, which
+//! keeps track of how many clients are still around. Once the last one is
+//! gone, the result stream is closed, so that servers do not wait forever for
+//! someone to make room in it.
+
+use cancellation::CancellationToken;
+use control::ControlChannel;
+use pause::PauseFlag;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use stream::StreamChannel;
+
+
+/// Handle to the side channels of an asynchronous operation
+#[derive(Clone, Debug, Default)]
+pub struct OpChannels {
+    /// Token which is cancelled when the client cancels the operation
+    cancellation: CancellationToken,
+
+    /// Channels which are created on first use
+    lazy: Arc<LazyChannels>,
+}
+//
+impl OpChannels {
+    /// Create the side channels of a new operation
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create the side channels of a new operation, with a certain token
+    pub fn with_token(cancellation: CancellationToken) -> Self {
+        OpChannels {
+            cancellation,
+            lazy: Arc::default(),
+        }
+    }
+
+    /// Derive a handle which shares every channel with this one, except for
+    /// the cancellation token
+    pub fn with_cancellation(&self, cancellation: CancellationToken) -> Self {
+        OpChannels {
+            cancellation,
+            lazy: self.lazy.clone(),
+        }
+    }
+
+    /// Token which is cancelled when the client cancels the operation
+    pub fn cancellation(&self) -> &CancellationToken {
+        &self.cancellation
+    }
+
+    /// Flag which is set while the client wants the operation to be paused
+    pub fn pause_flag(&self) -> &PauseFlag {
+        self.lazy.pause.get_or_init(PauseFlag::new)
+    }
+
+    /// Check whether the client wants the operation to be paused, without
+    /// creating the pause flag if nobody ever used it
+    pub fn pause_requested(&self) -> bool {
+        self.lazy.pause.get().is_some_and(PauseFlag::is_paused)
+    }
+
+    /// Channel through which the client sends control messages
+    pub(crate) fn control_channel(&self) -> &ControlChannel {
+        self.lazy.control.get_or_init(ControlChannel::new)
+    }
+
+    /// Receive the oldest control message which was not received yet, if any,
+    /// without creating the control channel if nobody ever used it
+    pub(crate) fn try_receive_control<M: Send + 'static>(&self) -> Option<M> {
+        self.lazy.control.get().and_then(ControlChannel::try_receive)
+    }
+
+    /// Channel through which partial results are streamed to the client
+    pub(crate) fn stream_channel(&self) -> &StreamChannel {
+        if let Some(stream) = self.lazy.stream.get() {
+            return stream;
+        }
+
+        // The stream may have been closed before it was created, in which case
+        // it must be created closed. Checking this while holding the lock
+        // ensures that we cannot miss a concurrent close_stream().
+        let closed = self.lazy.stream_closed.lock().unwrap();
+        self.lazy.stream.get_or_init(|| {
+            let stream = StreamChannel::new();
+            if *closed { stream.close(); }
+            stream
+        })
+    }
+
+    /// Mark the end of the result stream, once the operation is over
+    pub fn close_stream(&self) {
+        let mut closed = self.lazy.stream_closed.lock().unwrap();
+        *closed = true;
+        if let Some(stream) = self.lazy.stream.get() {
+            stream.close();
+        }
+    }
+}
+
+
+/// Client-side handle to the side channels of an asynchronous operation
+///
+/// Every client of an operation holds one of these, and cloning it accounts
+/// for a new client. The result stream is closed when the last one is dropped.
+///
+#[derive(Debug)]
+pub struct ClientChannels {
+    /// Side channels of the operation
+    channels: OpChannels,
+}
+//
+impl ClientChannels {
+    /// Register a new client of an operation, given its side channels
+    pub fn new(channels: &OpChannels) -> Self {
+        channels.lazy.clients.fetch_add(1, Ordering::Relaxed);
+        ClientChannels { channels: channels.clone() }
+    }
+}
+//
+impl Clone for ClientChannels {
+    fn clone(&self) -> Self {
+        Self::new(&self.channels)
+    }
+}
+//
+impl Deref for ClientChannels {
+    type Target = OpChannels;
+
+    fn deref(&self) -> &OpChannels {
+        &self.channels
+    }
+}
+//
+impl Drop for ClientChannels {
+    /// Close the result stream once nobody can read it anymore
+    fn drop(&mut self) {
+        if self.channels.lazy.clients.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.channels.close_stream();
+        }
+    }
+}
+
+
+/// Side channels of an operation which are created on first use
+#[derive(Debug, Default)]
+struct LazyChannels {
+    /// Pause flag, if it was used
+    pause: OnceLock<PauseFlag>,
+
+    /// Control channel, if it was used
+    control: OnceLock<ControlChannel>,
+
+    /// Result stream, if it was used
+    stream: OnceLock<StreamChannel>,
+
+    /// Whether the result stream was closed, which must be checked when it is
+    /// created, under protection of this mutex
+    stream_closed: Mutex<bool>,
+
+    /// Number of clients which are still around
+    clients: AtomicUsize,
+}
+
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use channels::*;
+
+    /// Check that channels are only created when they are used
+    #[test]
+    fn lazy_creation() {
+        let channels = OpChannels::new();
+        assert!(!channels.pause_requested());
+        assert_eq!(channels.try_receive_control::<u8>(), None);
+        channels.close_stream();
+        assert!(channels.lazy.pause.get().is_none());
+        assert!(channels.lazy.control.get().is_none());
+        assert!(channels.lazy.stream.get().is_none());
+
+        // Streams which are created after the operation is over are closed
+        assert!(channels.stream_channel().is_finished());
+    }
+
+    /// Check that derived handles share every channel but the token
+    #[test]
+    fn with_cancellation() {
+        let channels = OpChannels::new();
+        let derived =
+            channels.with_cancellation(channels.cancellation().child());
+        derived.pause_flag().pause();
+        assert!(channels.pause_requested());
+        channels.control_channel().send(42u8);
+        assert_eq!(derived.try_receive_control(), Some(42u8));
+
+        // Cancelling the derived token does not cancel the original one
+        derived.cancellation().cancel();
+        assert!(!channels.cancellation().is_cancelled());
+        channels.cancellation().cancel();
+        assert!(derived.cancellation().is_cancelled());
+    }
+}
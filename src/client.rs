@@ -16,10 +16,249 @@
 //! It is highly recommended that implementors of asynchronous operation servers
 //! periodically check such cancellation requests, and adjust their behaviour
 //! accordingly by performing early termination, whenever reasonable feasible.
+//!
+//! Clients can also request an operation to pause and resume. These requests
+//! follow the same philosophy as cancellation requests.
+//!
+//! Beyond these standard requests, clients of operations whose status details
+//! specify a control message type can send such messages to the server, as
+//! described in the control module.
+//!
+//! Similarly, clients of operations whose status details specify a stream item
+//! type can consume the partial results of the operation, as described in the
+//! stream module.
+//!
+//! Clients which are able to tell the current status of the asynchronous
+//! operation, such as polling and blocking clients, additionally implement a
+//! status query interface, which allows writing code that works with either.
+//! Those which are also able to wait for status updates, such as blocking
+//! clients, implement a waiting interface on top of it.
+//!
+//! Libraries which do not want to commit to a specific monitoring mode can
+//! return boxed clients, which implement the same interfaces.
+
+use control::AsyncOpControlDetails;
+use status::{self, AsyncOpCloneableDetails, AsyncOpStatus};
+use stream::AsyncOpStreamDetails;
 
 
 /// Features which all asynchronous operation clients are expected to share
 pub trait IAsyncOpClient {
     /// Request the cancellation of the active asynchronous operation
+    ///
+    /// If the operation has children (see the hierarchy module), all of its
+    /// descendants will be asked to stop as well.
+    ///
     fn cancel(&mut self);
+
+    /// Request the active asynchronous operation to pause
+    ///
+    /// Like cancellation, this is only a request, which is honored by servers
+    /// when they reach a point where they can be paused (see the pause module).
+    ///
+    /// Clients which cannot forward such requests ignore them, which is what
+    /// this default implementation does.
+    ///
+    fn pause(&mut self) {}
+
+    /// Request a paused asynchronous operation to resume
+    ///
+    /// Like pause requests, this is ignored by default.
+    ///
+    fn resume(&mut self) {}
+}
+
+
+/// Features of asynchronous operation clients which can query the status
+pub trait IAsyncOpStatusClient: IAsyncOpClient {
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails: AsyncOpCloneableDetails;
+
+    /// Query the current asynchronous operation status
+    fn current_status(&mut self) -> AsyncOpStatus<Self::StatusDetails>;
+
+    /// Check whether the asynchronous operation has reached a final status
+    fn is_final(&mut self) -> bool {
+        status::is_final(&self.current_status())
+    }
+}
+
+
+/// Features of asynchronous operation clients which can wait for status updates
+///
+/// Such clients keep track of which status they have read. Querying the current
+/// status through the IAsyncOpStatusClient interface marks it as read.
+///
+pub trait IAsyncOpWaitClient: IAsyncOpStatusClient {
+    /// Wait for either a status update or a final operation status, and mark
+    /// it as read
+    fn wait(&mut self) -> AsyncOpStatus<Self::StatusDetails>;
+
+    /// Return the operation status if it was updated since it was last read
+    /// or if it is final, like wait() would, or None instead of blocking
+    fn try_wait(&mut self) -> Option<AsyncOpStatus<Self::StatusDetails>>;
+}
+
+
+/// Client of an asynchronous operation, whose monitoring mode is unknown
+pub type DynAsyncOpClient<Details> =
+    Box<dyn IAsyncOpStatusClient<StatusDetails = Details> + Send>;
+
+
+/// Client of an asynchronous operation which can wait for status updates, but
+/// whose monitoring mode is otherwise unknown
+pub type DynAsyncOpWaitClient<Details> =
+    Box<dyn IAsyncOpWaitClient<StatusDetails = Details> + Send>;
+
+
+/// Boxed clients implement the same features as the clients which they contain
+impl<C: IAsyncOpClient + ?Sized> IAsyncOpClient for Box<C> {
+    /// Request the cancellation of the active asynchronous operation
+    fn cancel(&mut self) {
+        (**self).cancel()
+    }
+
+    /// Request the active asynchronous operation to pause
+    fn pause(&mut self) {
+        (**self).pause()
+    }
+
+    /// Request a paused asynchronous operation to resume
+    fn resume(&mut self) {
+        (**self).resume()
+    }
+}
+//
+impl<C: IAsyncOpStatusClient + ?Sized> IAsyncOpStatusClient for Box<C> {
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = C::StatusDetails;
+
+    /// Query the current asynchronous operation status
+    fn current_status(&mut self) -> AsyncOpStatus<Self::StatusDetails> {
+        (**self).current_status()
+    }
+
+    /// Check whether the asynchronous operation has reached a final status
+    fn is_final(&mut self) -> bool {
+        (**self).is_final()
+    }
+}
+//
+impl<C: IAsyncOpWaitClient + ?Sized> IAsyncOpWaitClient for Box<C> {
+    /// Wait for either a status update or a final operation status
+    fn wait(&mut self) -> AsyncOpStatus<Self::StatusDetails> {
+        (**self).wait()
+    }
+
+    /// Return the operation status if it was updated or is final, if any
+    fn try_wait(&mut self) -> Option<AsyncOpStatus<Self::StatusDetails>> {
+        (**self).try_wait()
+    }
+}
+
+
+/// Features of asynchronous operation clients which can send control messages
+pub trait IAsyncOpControlClient: IAsyncOpClient {
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails: AsyncOpControlDetails;
+
+    /// Send a control message to the server of the asynchronous operation
+    fn send_control(
+        &mut self,
+        message: <Self::StatusDetails as AsyncOpControlDetails>::ControlMessage
+    );
+}
+
+
+/// Features of asynchronous operation clients which can consume partial results
+pub trait IAsyncOpStreamClient: IAsyncOpClient {
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails: AsyncOpStreamDetails;
+
+    /// Consume the oldest partial result which was not consumed yet, if any,
+    /// without blocking
+    fn try_next_item(&mut self) -> Option<StreamItemOf<Self::StatusDetails>>;
+
+    /// Consume the oldest partial result which was not consumed yet, blocking
+    /// until one is available, or return None once the stream is over
+    fn next_item(&mut self) -> Option<StreamItemOf<Self::StatusDetails>>;
+
+    /// Check whether the operation is over and all of its partial results
+    /// have been consumed
+    fn stream_finished(&mut self) -> bool;
+}
+
+
+/// Partial results of operations with certain status details
+pub type StreamItemOf<Details> =
+    <Details as AsyncOpStreamDetails>::StreamItem;
+
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use client::*;
+    use multithread::{blocking, parking, polling};
+    use std::thread;
+
+    /// Function which completes a test operation
+    type Finish = Box<dyn FnOnce() + Send>;
+
+    /// Library function which does not tell how its operation is monitored
+    fn start(waitable: bool) -> (Finish, DynAsyncOpClient<status::NoDetails>) {
+        if waitable {
+            let (mut server, client) =
+                blocking::AsyncOp::new(status::PENDING).split();
+            (Box::new(move || server.update(status::DONE)), Box::new(client))
+        } else {
+            let (mut server, client) =
+                polling::AsyncOp::new(status::PENDING).split();
+            (Box::new(move || server.update(status::DONE)), Box::new(client))
+        }
+    }
+
+    /// Check that boxed clients can be used without knowing their mode
+    #[test]
+    fn dyn_client() {
+        for &waitable in &[false, true] {
+            let (finish, mut client) = start(waitable);
+            assert!(!client.is_final());
+            finish();
+            assert!(client.is_final());
+            assert_eq!(client.current_status(), status::DONE);
+            client.cancel();
+        }
+    }
+
+    /// Check that waiting clients can be used interchangeably
+    #[test]
+    fn wait_client() {
+        let clients: Vec<(Finish, DynAsyncOpWaitClient<_>)> = vec![
+            {
+                let (mut server, client) =
+                    blocking::AsyncOp::new(status::PENDING).split();
+                (Box::new(move || server.update(status::DONE)),
+                 Box::new(client))
+            },
+            {
+                let (mut server, client) =
+                    parking::AsyncOp::new(status::PENDING).split();
+                (Box::new(move || server.update(status::DONE)),
+                 Box::new(client))
+            },
+        ];
+        for (finish, mut client) in clients {
+            // The initial status is unread, and then there is nothing new
+            assert_eq!(client.try_wait(), Some(status::PENDING));
+            assert_eq!(client.try_wait(), None);
+
+            // Waiting should block until the next update
+            let worker = thread::spawn(finish);
+            assert_eq!(client.wait(), status::DONE);
+            worker.join().unwrap();
+
+            // Final statuses can be waited for at any time
+            assert_eq!(client.try_wait(), Some(status::DONE));
+        }
+    }
 }
@@ -0,0 +1,258 @@
+//! Rate limiting of progress updates
+//!
+//! Servers which report their progress in tight loops can flood their clients
+//! with status updates: blocking clients are woken up over and over again, and
+//! callback executors are asked to run a callback for every single update.
+//! This module puts a lower bound on the interval between two Running updates
+//! which reach the client, coalescing the updates which come in between.
+//!
+//! Coalescing keeps the latest Running update, which is delivered once the
+//! interval has elapsed, unless it is superseded by a newer one by then.
+//! Other updates are never delayed: the first Running update after another
+//! status and the final status are delivered immediately. Before any of them,
+//! a delayed Running update is delivered as well, so that the client always
+//! sees the last progress report which the server made before completing.
+//!
+//! Delayed updates are delivered by the shared timer thread, so that clients
+//! see them even if the server does not send anything else in a while.
+
+use channels::OpChannels;
+use server::{AsyncOpServer, AsyncOpServerConfig};
+use status::{self, AsyncOpStatus};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use timer::{self, TimerId};
+
+
+/// Make sure that Running updates from a server reach its client at most once
+/// per `min_interval`, coalescing the updates which come in between
+pub fn with_min_interval<Config>(
+    server: AsyncOpServer<Config>,
+    min_interval: Duration
+) -> AsyncOpServer<CoalescingServerConfig<Config>>
+    where Config: AsyncOpServerConfig + Send + 'static
+{
+    server.map_config(move |config| {
+        CoalescingServerConfig {
+            channels: config.channels().clone(),
+            state: Arc::new(Mutex::new(CoalescingState {
+                inner: config.into_inner(),
+                min_interval,
+                last_running: None,
+                delayed: None,
+                timer: None,
+            })),
+        }
+    })
+}
+
+
+/// Server configuration wrapper which coalesces frequent Running updates
+pub struct CoalescingServerConfig<Config: AsyncOpServerConfig> {
+    /// State shared with the timer thread
+    state: Arc<Mutex<CoalescingState<Config>>>,
+
+    /// Side channels of the wrapped configuration, which is behind the mutex
+    channels: OpChannels,
+}
+//
+impl<Config> AsyncOpServerConfig for CoalescingServerConfig<Config>
+    where Config: AsyncOpServerConfig + Send + 'static
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Config::StatusDetails;
+
+    /// Forward status updates to the client, delaying frequent Running ones
+    fn update(&mut self, status: AsyncOpStatus<Self::StatusDetails>) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match status {
+            AsyncOpStatus::Running(_) => {
+                // Running updates which come too soon are delayed. If none was
+                // delayed yet, the timer must be told to deliver this one.
+                if let Some(last_running) = state.last_running {
+                    let due = last_running + state.min_interval;
+                    if now < due {
+                        if state.delayed.is_none() {
+                            state.timer = Some(self.schedule_flush(due));
+                        }
+                        state.delayed = Some(status);
+                        return;
+                    }
+                }
+
+                // Otherwise, this update supersedes any delayed one
+                if let Some(timer) = state.timer.take() {
+                    timer::cancel(timer);
+                }
+                state.delayed = None;
+                state.last_running = Some(now);
+            },
+            _ => {
+                // Other updates flush delayed Running updates, and reset the
+                // interval so that the next Running update is immediate
+                state.flush();
+                state.last_running = None;
+            },
+        }
+        state.inner.update(status);
+    }
+
+    /// Side channels shared with the client
+    fn channels(&self) -> &OpChannels {
+        &self.channels
+    }
+}
+//
+impl<Config> CoalescingServerConfig<Config>
+    where Config: AsyncOpServerConfig + Send + 'static
+{
+    /// Have the timer thread deliver the delayed Running update, if any, at
+    /// some point in time
+    fn schedule_flush(&self, due: Instant) -> TimerId {
+        let timer_state = self.state.clone();
+        timer::schedule(due, move || {
+            // This timed action may have been superseded by a newer one after
+            // it was taken off the timer's schedule, in which case the delayed
+            // update, if any, is not due yet and must be left alone.
+            let mut state = timer_state.lock().unwrap();
+            let now = Instant::now();
+            let is_due = state.last_running.map_or(true, |last_running| {
+                now >= last_running + state.min_interval
+            });
+            if state.delayed.is_some() && is_due {
+                state.flush();
+                state.last_running = Some(now);
+            }
+        })
+    }
+}
+
+
+/// State of an operation with coalesced updates, shared with the timer thread
+struct CoalescingState<Config: AsyncOpServerConfig> {
+    /// Server configuration which we are wrapping
+    inner: Config,
+
+    /// Minimal interval between two Running updates
+    min_interval: Duration,
+
+    /// Time at which the last Running update was delivered, if the client's
+    /// current status is a Running one
+    last_running: Option<Instant>,
+
+    /// Latest Running update which was delayed, if any
+    delayed: Option<AsyncOpStatus<Config::StatusDetails>>,
+
+    /// Timed action which will deliver the delayed update, if any
+    timer: Option<TimerId>,
+}
+//
+impl<Config: AsyncOpServerConfig> CoalescingState<Config> {
+    /// Deliver the delayed Running update right away, if any
+    fn flush(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            timer::cancel(timer);
+        }
+        if let Some(delayed) = self.delayed.take() {
+            debug_assert!(!status::is_final(&delayed));
+            self.inner.update(delayed);
+        }
+    }
+}
+
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use coalesce::*;
+    use middleware::{self, History};
+    use multithread::{blocking, polling};
+    use status::{AsyncOpStatusDetails, AsyncOpStatusTraits, NoDetails};
+    use std::thread;
+
+    /// Status details of operations which report their progress
+    #[derive(Clone, Debug, PartialEq)]
+    struct ProgressDetails {}
+    //
+    impl AsyncOpStatusDetails for ProgressDetails {
+        type PendingDetails = NoDetails;
+        type RunningDetails = Progress;
+        type DoneDetails = NoDetails;
+        type CancelledDetails = NoDetails;
+        type ErrorDetails = NoDetails;
+    }
+    //
+    impl AsyncOpStatusTraits for ProgressDetails {}
+    //
+    #[derive(Clone, Debug, PartialEq)]
+    struct Progress(u32);
+    //
+    impl AsyncOpStatusTraits for Progress {}
+
+    /// Status of an operation which has made some progress
+    fn running(progress: u32) -> AsyncOpStatus<ProgressDetails> {
+        AsyncOpStatus::Running(Progress(progress))
+    }
+
+    /// Status of an operation which is done
+    fn done() -> AsyncOpStatus<ProgressDetails> {
+        AsyncOpStatus::Done(status::NO_DETAILS)
+    }
+
+    /// Check that frequent Running updates are coalesced, and that the final
+    /// status flushes the latest one
+    #[test]
+    fn final_flush() {
+        let history = History::new();
+        let (server, _client) = polling::AsyncOp::new(
+            AsyncOpStatus::Pending(status::NO_DETAILS)
+        ).split();
+        let server = middleware::builder(server).recorded(&history).build();
+        let mut server = with_min_interval(server, Duration::from_secs(60));
+        for progress in 0..100 {
+            server.update(running(progress));
+        }
+        server.update(done());
+        assert_eq!(history.statuses(), vec![running(0), running(99), done()]);
+    }
+
+    /// Check that delayed updates are delivered once the interval elapses
+    #[test]
+    fn delayed_delivery() {
+        let (server, mut client) = blocking::AsyncOp::new(running(0)).split();
+        let mut server = with_min_interval(server, Duration::from_millis(20));
+        assert_eq!(client.wait(), running(0));
+        server.update(running(1));
+        server.update(running(2));
+        server.update(running(3));
+        assert_eq!(client.wait(), running(1));
+
+        // The latest of the coalesced updates should be delivered later on
+        let start = Instant::now();
+        assert_eq!(client.wait(), running(3));
+        assert!(start.elapsed() >= Duration::from_millis(10));
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(client.status(), running(3));
+    }
+
+    /// Check that transitions to Running are delivered immediately
+    #[test]
+    fn transitions() {
+        let history = History::new();
+        let (server, _client) = polling::AsyncOp::new(
+            AsyncOpStatus::Pending(status::NO_DETAILS)
+        ).split();
+        let server = middleware::builder(server).recorded(&history).build();
+        let mut server = with_min_interval(server, Duration::from_secs(60));
+        server.update(running(0));
+        server.update(running(1));
+        server.update(AsyncOpStatus::Pending(status::NO_DETAILS));
+        server.update(running(2));
+        assert_eq!(history.statuses(),
+                   vec![running(0),
+                        running(1),
+                        AsyncOpStatus::Pending(status::NO_DETAILS),
+                        running(2)]);
+    }
+}
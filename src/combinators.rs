@@ -0,0 +1,858 @@
+//! Combinators deriving new asynchronous operations from existing ones
+//!
+//! It is frequently useful to treat a group of asynchronous operations as a
+//! single one, for example in order to wait for several operations to complete
+//! (ALL), or for the first of them to complete (ANY). It is also useful to
+//! transform the result of an operation, or to chain operations together.
+//!
+//! The combinators from this module take ownership of the clients of existing
+//! operations, and produce a client for a derived operation, whose status is
+//! computed from the status of its inputs whenever it is queried. Derived
+//! operations can themselves be used as inputs to other combinators.
+//!
+//! Cancelling a derived operation cancels its inputs. Conversely, once the
+//! outcome of a derived operation is known, the inputs which are still running
+//! are cancelled, since their results are not needed anymore.
+
+use client::{IAsyncOpClient, IAsyncOpStatusClient};
+use status::{self, AsyncOpError, AsyncOpStatus, AsyncOpStatusDetails,
+             AsyncOpStatusTraits};
+use std::error::Error;
+use std::fmt::{self, Debug};
+use std::marker::PhantomData;
+
+
+/// Shorthand for the status details of a client
+type DetailsOf<Client> = <Client as IAsyncOpStatusClient>::StatusDetails;
+
+
+/// Something which originates from either the first or the second input of a
+/// derived operation
+#[derive(Clone, Debug, PartialEq)]
+pub enum Either<A, B> {
+    /// This comes from the first input
+    First(A),
+
+    /// This comes from the second input
+    Second(B),
+}
+//
+impl<A: AsyncOpStatusTraits, B: AsyncOpStatusTraits> AsyncOpStatusTraits
+    for Either<A, B> {}
+//
+impl<A: Debug, B: Debug> fmt::Display for Either<A, B> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Either::First(ref a) => write!(f, "First input: {:?}", a),
+            Either::Second(ref b) => write!(f, "Second input: {:?}", b),
+        }
+    }
+}
+//
+impl<A: Debug, B: Debug> Error for Either<A, B> {}
+
+
+/// Convert an error to another status details type with the same error details
+fn convert_error<From, To>(error: AsyncOpError<From>) -> AsyncOpError<To>
+    where From: AsyncOpStatusDetails,
+          To: AsyncOpStatusDetails<ErrorDetails=From::ErrorDetails>
+{
+    match error {
+        AsyncOpError::ServerKilled => AsyncOpError::ServerKilled,
+        AsyncOpError::TimedOut => AsyncOpError::TimedOut,
+        AsyncOpError::Stalled => AsyncOpError::Stalled,
+        AsyncOpError::ResultTaken => AsyncOpError::ResultTaken,
+        AsyncOpError::CustomError(details) =>
+            AsyncOpError::CustomError(details),
+    }
+}
+
+
+/// Status details of operations which complete when both inputs are done
+///
+/// While the operation is pending or running, and when it is cancelled, the
+/// details are the statuses of both inputs. The operation is pending as long
+/// as both inputs are, and running until a final status is reached.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct JoinDetails<DA, DB>(PhantomData<(DA, DB)>);
+//
+impl<DA: AsyncOpStatusDetails, DB: AsyncOpStatusDetails> AsyncOpStatusDetails
+    for JoinDetails<DA, DB>
+{
+    type PendingDetails = (AsyncOpStatus<DA>, AsyncOpStatus<DB>);
+    type RunningDetails = (AsyncOpStatus<DA>, AsyncOpStatus<DB>);
+    type DoneDetails = (DA::DoneDetails, DB::DoneDetails);
+    type CancelledDetails = (AsyncOpStatus<DA>, AsyncOpStatus<DB>);
+    type ErrorDetails = Either<AsyncOpError<DA>, AsyncOpError<DB>>;
+}
+//
+impl<DA: AsyncOpStatusDetails, DB: AsyncOpStatusDetails> AsyncOpStatusTraits
+    for JoinDetails<DA, DB> {}
+
+
+/// Derived operation which is done when both of its inputs are done
+pub struct Join<A: IAsyncOpStatusClient, B: IAsyncOpStatusClient> {
+    /// First input
+    first: A,
+
+    /// Second input
+    second: B,
+
+    /// Final status of the derived operation, once known
+    final_status: Option<AsyncOpStatus<JoinDetails<DetailsOf<A>,
+                                                   DetailsOf<B>>>>,
+}
+//
+/// Build an operation which is done when both inputs are done, and fails or is
+/// cancelled as soon as either input fails or is cancelled
+pub fn join<A, B>(first: A, second: B) -> Join<A, B>
+    where A: IAsyncOpStatusClient,
+          B: IAsyncOpStatusClient
+{
+    Join { first, second, final_status: None }
+}
+//
+impl<A: IAsyncOpStatusClient, B: IAsyncOpStatusClient> IAsyncOpClient
+    for Join<A, B>
+{
+    /// Request the cancellation of both inputs
+    fn cancel(&mut self) {
+        self.first.cancel();
+        self.second.cancel();
+    }
+
+    /// Request both operations to pause
+    fn pause(&mut self) {
+        self.first.pause();
+        self.second.pause();
+    }
+
+    /// Request both operations to resume
+    fn resume(&mut self) {
+        self.first.resume();
+        self.second.resume();
+    }
+}
+//
+impl<A: IAsyncOpStatusClient, B: IAsyncOpStatusClient> IAsyncOpStatusClient
+    for Join<A, B>
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = JoinDetails<DetailsOf<A>, DetailsOf<B>>;
+
+    /// Query the current asynchronous operation status
+    fn current_status(&mut self) -> AsyncOpStatus<Self::StatusDetails> {
+        use status::AsyncOpStatus::*;
+        if let Some(ref status) = self.final_status {
+            return status.clone();
+        }
+        let status = match (self.first.current_status(),
+                            self.second.current_status()) {
+            (Error(e), _) => Error(AsyncOpError::CustomError(Either::First(e))),
+            (_, Error(e)) =>
+                Error(AsyncOpError::CustomError(Either::Second(e))),
+            (a @ Cancelled(_), b) | (a, b @ Cancelled(_)) => Cancelled((a, b)),
+            (Done(a), Done(b)) => Done((a, b)),
+            (a @ Pending(_), b @ Pending(_)) => Pending((a, b)),
+            (a, b) => Running((a, b)),
+        };
+        if status::is_final(&status) {
+            self.cancel();
+            self.final_status = Some(status.clone());
+        }
+        status
+    }
+}
+
+
+/// Status details of operations which complete when all inputs are done
+///
+/// This is the counterpart of JoinDetails for any number of inputs of the
+/// same type, whose statuses are listed in the order of the inputs.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct JoinAllDetails<D>(PhantomData<D>);
+//
+impl<D: AsyncOpStatusDetails> AsyncOpStatusDetails for JoinAllDetails<D> {
+    type PendingDetails = Vec<AsyncOpStatus<D>>;
+    type RunningDetails = Vec<AsyncOpStatus<D>>;
+    type DoneDetails = Vec<D::DoneDetails>;
+    type CancelledDetails = Vec<AsyncOpStatus<D>>;
+    type ErrorDetails = JoinAllError<D>;
+}
+//
+impl<D: AsyncOpStatusDetails> AsyncOpStatusTraits for JoinAllDetails<D> {}
+
+
+/// Failure of one of the inputs of a JoinAll operation
+#[derive(Debug)]
+pub struct JoinAllError<D: AsyncOpStatusDetails> {
+    /// Index of the input which failed
+    pub index: usize,
+
+    /// Error which the input failed with
+    pub error: AsyncOpError<D>,
+}
+//
+impl<D: AsyncOpStatusDetails> Clone for JoinAllError<D>
+    where AsyncOpError<D>: Clone
+{
+    fn clone(&self) -> Self {
+        JoinAllError { index: self.index, error: self.error.clone() }
+    }
+}
+//
+impl<D: AsyncOpStatusDetails> PartialEq for JoinAllError<D>
+    where AsyncOpError<D>: PartialEq
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index && self.error == other.error
+    }
+}
+//
+impl<D: AsyncOpStatusDetails> fmt::Display for JoinAllError<D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Input {} failed: {:?}", self.index, self.error)
+    }
+}
+//
+impl<D: AsyncOpStatusDetails> Error for JoinAllError<D> {}
+//
+impl<D: AsyncOpStatusDetails> AsyncOpStatusTraits for JoinAllError<D> {}
+
+
+/// Derived operation which is done when all of its inputs are done
+pub struct JoinAll<C: IAsyncOpStatusClient> {
+    /// Inputs of the operation
+    inputs: Vec<C>,
+
+    /// Final status of the derived operation, once known
+    final_status: Option<AsyncOpStatus<JoinAllDetails<DetailsOf<C>>>>,
+}
+//
+/// Build an operation which is done when all inputs are done, and fails or is
+/// cancelled as soon as any input fails or is cancelled
+pub fn join_all<C, I>(inputs: I) -> JoinAll<C>
+    where C: IAsyncOpStatusClient,
+          I: IntoIterator<Item=C>
+{
+    JoinAll { inputs: inputs.into_iter().collect(), final_status: None }
+}
+//
+impl<C: IAsyncOpStatusClient> IAsyncOpClient for JoinAll<C> {
+    /// Request the cancellation of all inputs
+    fn cancel(&mut self) {
+        for input in &mut self.inputs {
+            input.cancel();
+        }
+    }
+
+    /// Request all operations to pause
+    fn pause(&mut self) {
+        for input in &mut self.inputs {
+            input.pause();
+        }
+    }
+
+    /// Request all operations to resume
+    fn resume(&mut self) {
+        for input in &mut self.inputs {
+            input.resume();
+        }
+    }
+}
+//
+impl<C: IAsyncOpStatusClient> IAsyncOpStatusClient for JoinAll<C> {
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = JoinAllDetails<DetailsOf<C>>;
+
+    /// Query the current asynchronous operation status
+    fn current_status(&mut self) -> AsyncOpStatus<Self::StatusDetails> {
+        use status::AsyncOpStatus::*;
+        if let Some(ref status) = self.final_status {
+            return status.clone();
+        }
+        let statuses = self.inputs
+                           .iter_mut()
+                           .map(|input| input.current_status())
+                           .collect::<Vec<_>>();
+        let error = statuses.iter().position(|s| matches!(*s, Error(_)));
+        let status = if let Some(index) = error {
+            match statuses.into_iter().nth(index) {
+                Some(Error(error)) =>
+                    Error(AsyncOpError::CustomError(
+                        JoinAllError { index, error }
+                    )),
+                _ => unreachable!(),
+            }
+        } else if statuses.iter().any(|s| matches!(*s, Cancelled(_))) {
+            Cancelled(statuses)
+        } else if statuses.iter().all(|s| matches!(*s, Done(_))) {
+            Done(statuses.into_iter()
+                         .map(|s| match s {
+                             Done(details) => details,
+                             _ => unreachable!(),
+                         })
+                         .collect())
+        } else if statuses.iter().all(|s| matches!(*s, Pending(_))) {
+            Pending(statuses)
+        } else {
+            Running(statuses)
+        };
+        if status::is_final(&status) {
+            self.cancel();
+            self.final_status = Some(status.clone());
+        }
+        status
+    }
+}
+
+
+/// Status details of operations which complete when either input completes
+///
+/// While the operation is pending or running, the details are the statuses of
+/// both inputs. The final status is that of the first input to complete. If
+/// both inputs have completed by the time the derived operation's status is
+/// queried, the first input wins.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct RaceDetails<DA, DB>(PhantomData<(DA, DB)>);
+//
+impl<DA: AsyncOpStatusDetails, DB: AsyncOpStatusDetails> AsyncOpStatusDetails
+    for RaceDetails<DA, DB>
+{
+    type PendingDetails = (AsyncOpStatus<DA>, AsyncOpStatus<DB>);
+    type RunningDetails = (AsyncOpStatus<DA>, AsyncOpStatus<DB>);
+    type DoneDetails = Either<DA::DoneDetails, DB::DoneDetails>;
+    type CancelledDetails = Either<DA::CancelledDetails, DB::CancelledDetails>;
+    type ErrorDetails = Either<AsyncOpError<DA>, AsyncOpError<DB>>;
+}
+//
+impl<DA: AsyncOpStatusDetails, DB: AsyncOpStatusDetails> AsyncOpStatusTraits
+    for RaceDetails<DA, DB> {}
+
+
+/// Derived operation which completes when either of its inputs completes
+pub struct Race<A: IAsyncOpStatusClient, B: IAsyncOpStatusClient> {
+    /// First input
+    first: A,
+
+    /// Second input
+    second: B,
+
+    /// Final status of the derived operation, once known
+    final_status: Option<AsyncOpStatus<RaceDetails<DetailsOf<A>,
+                                                   DetailsOf<B>>>>,
+}
+//
+/// Build an operation which completes like the first input to complete
+pub fn race<A, B>(first: A, second: B) -> Race<A, B>
+    where A: IAsyncOpStatusClient,
+          B: IAsyncOpStatusClient
+{
+    Race { first, second, final_status: None }
+}
+//
+impl<A: IAsyncOpStatusClient, B: IAsyncOpStatusClient> IAsyncOpClient
+    for Race<A, B>
+{
+    /// Request the cancellation of both inputs
+    fn cancel(&mut self) {
+        self.first.cancel();
+        self.second.cancel();
+    }
+
+    /// Request both operations to pause
+    fn pause(&mut self) {
+        self.first.pause();
+        self.second.pause();
+    }
+
+    /// Request both operations to resume
+    fn resume(&mut self) {
+        self.first.resume();
+        self.second.resume();
+    }
+}
+//
+impl<A: IAsyncOpStatusClient, B: IAsyncOpStatusClient> IAsyncOpStatusClient
+    for Race<A, B>
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = RaceDetails<DetailsOf<A>, DetailsOf<B>>;
+
+    /// Query the current asynchronous operation status
+    fn current_status(&mut self) -> AsyncOpStatus<Self::StatusDetails> {
+        use status::AsyncOpStatus::*;
+        if let Some(ref status) = self.final_status {
+            return status.clone();
+        }
+        let status = match self.first.current_status() {
+            Done(a) => Done(Either::First(a)),
+            Cancelled(a) => Cancelled(Either::First(a)),
+            Error(e) => Error(AsyncOpError::CustomError(Either::First(e))),
+            a => match (a, self.second.current_status()) {
+                (_, Done(b)) => Done(Either::Second(b)),
+                (_, Cancelled(b)) => Cancelled(Either::Second(b)),
+                (_, Error(e)) =>
+                    Error(AsyncOpError::CustomError(Either::Second(e))),
+                (a @ Pending(_), b @ Pending(_)) => Pending((a, b)),
+                (a, b) => Running((a, b)),
+            },
+        };
+        if status::is_final(&status) {
+            self.cancel();
+            self.final_status = Some(status.clone());
+        }
+        status
+    }
+}
+
+
+/// Status details of operations whose result is transformed into a T
+#[derive(Clone, Debug, PartialEq)]
+pub struct MapDetails<D, T>(PhantomData<(D, T)>);
+//
+impl<D: AsyncOpStatusDetails, T: AsyncOpStatusTraits> AsyncOpStatusDetails
+    for MapDetails<D, T>
+{
+    type PendingDetails = D::PendingDetails;
+    type RunningDetails = D::RunningDetails;
+    type DoneDetails = T;
+    type CancelledDetails = D::CancelledDetails;
+    type ErrorDetails = D::ErrorDetails;
+}
+//
+impl<D: AsyncOpStatusDetails, T: AsyncOpStatusTraits> AsyncOpStatusTraits
+    for MapDetails<D, T> {}
+
+
+/// Derived operation whose result is a transformation of its input's result
+pub struct Map<C, F, T>
+    where C: IAsyncOpStatusClient,
+          T: AsyncOpStatusTraits + Clone
+{
+    /// Input of the operation
+    input: C,
+
+    /// Transformation to be applied to the input's result
+    transform: Option<F>,
+
+    /// Final status of the derived operation, once known
+    final_status: Option<AsyncOpStatus<MapDetails<DetailsOf<C>, T>>>,
+}
+//
+/// Build an operation which transforms the result of its input once it's done
+pub fn map<C, F, T>(input: C, transform: F) -> Map<C, F, T>
+    where C: IAsyncOpStatusClient,
+          F: FnOnce(<DetailsOf<C> as AsyncOpStatusDetails>::DoneDetails) -> T,
+          T: AsyncOpStatusTraits + Clone
+{
+    Map { input, transform: Some(transform), final_status: None }
+}
+//
+impl<C, F, T> IAsyncOpClient for Map<C, F, T>
+    where C: IAsyncOpStatusClient,
+          T: AsyncOpStatusTraits + Clone
+{
+    /// Request the cancellation of the input
+    fn cancel(&mut self) {
+        self.input.cancel();
+    }
+
+    /// Request the input operation to pause
+    fn pause(&mut self) {
+        self.input.pause();
+    }
+
+    /// Request the input operation to resume
+    fn resume(&mut self) {
+        self.input.resume();
+    }
+}
+//
+impl<C, F, T> IAsyncOpStatusClient for Map<C, F, T>
+    where C: IAsyncOpStatusClient,
+          F: FnOnce(<DetailsOf<C> as AsyncOpStatusDetails>::DoneDetails) -> T,
+          T: AsyncOpStatusTraits + Clone
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = MapDetails<DetailsOf<C>, T>;
+
+    /// Query the current asynchronous operation status
+    fn current_status(&mut self) -> AsyncOpStatus<Self::StatusDetails> {
+        use status::AsyncOpStatus::*;
+        if let Some(ref status) = self.final_status {
+            return status.clone();
+        }
+        let status = match self.input.current_status() {
+            Pending(details) => Pending(details),
+            Running(details) => Running(details),
+            Done(details) => {
+                let transform = self.transform.take().unwrap();
+                Done(transform(details))
+            },
+            Cancelled(details) => Cancelled(details),
+            Error(error) => Error(convert_error(error)),
+        };
+        if status::is_final(&status) {
+            self.final_status = Some(status.clone());
+        }
+        status
+    }
+}
+
+
+/// Status details of operations made of two operations run one after another
+///
+/// The operation is pending as long as the first operation is pending, and
+/// running until the second operation has reached a final status. While the
+/// second operation is pending or running, its full status is reported.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct AndThenDetails<DA, DB>(PhantomData<(DA, DB)>);
+//
+impl<DA: AsyncOpStatusDetails, DB: AsyncOpStatusDetails> AsyncOpStatusDetails
+    for AndThenDetails<DA, DB>
+{
+    type PendingDetails = DA::PendingDetails;
+    type RunningDetails = Either<DA::RunningDetails, AsyncOpStatus<DB>>;
+    type DoneDetails = DB::DoneDetails;
+    type CancelledDetails = Either<DA::CancelledDetails, DB::CancelledDetails>;
+    type ErrorDetails = Either<AsyncOpError<DA>, AsyncOpError<DB>>;
+}
+//
+impl<DA: AsyncOpStatusDetails, DB: AsyncOpStatusDetails> AsyncOpStatusTraits
+    for AndThenDetails<DA, DB> {}
+
+
+/// Derived operation which starts a second operation once a first one is done
+pub struct AndThen<A, B, F>
+    where A: IAsyncOpStatusClient,
+          B: IAsyncOpStatusClient
+{
+    /// Operation which is currently being monitored
+    stage: AndThenStage<A, B, F>,
+
+    /// Whether the client requested the cancellation of the operation
+    cancel_requested: bool,
+
+    /// Whether the client currently wants the operation to be paused
+    pause_requested: bool,
+
+    /// Final status of the derived operation, once known
+    final_status: Option<AsyncOpStatus<AndThenDetails<DetailsOf<A>,
+                                                      DetailsOf<B>>>>,
+}
+//
+/// Stage of an AndThen operation
+enum AndThenStage<A, B, F> {
+    /// Waiting for the first operation, which will be followed by F
+    First(A, F),
+
+    /// Waiting for the second operation
+    Second(B),
+
+    /// Transition between the two stages
+    Switching,
+}
+//
+/// Build an operation which, once its input is done, starts a second operation
+/// based on the input's result, then completes like that second operation
+pub fn and_then<A, B, F>(input: A, next: F) -> AndThen<A, B, F>
+    where A: IAsyncOpStatusClient,
+          B: IAsyncOpStatusClient,
+          F: FnOnce(<DetailsOf<A> as AsyncOpStatusDetails>::DoneDetails) -> B
+{
+    AndThen {
+        stage: AndThenStage::First(input, next),
+        cancel_requested: false,
+        pause_requested: false,
+        final_status: None,
+    }
+}
+//
+impl<A, B, F> IAsyncOpClient for AndThen<A, B, F>
+    where A: IAsyncOpStatusClient,
+          B: IAsyncOpStatusClient
+{
+    /// Request the cancellation of the active stage of the operation
+    fn cancel(&mut self) {
+        self.cancel_requested = true;
+        match self.stage {
+            AndThenStage::First(ref mut first, _) => first.cancel(),
+            AndThenStage::Second(ref mut second) => second.cancel(),
+            AndThenStage::Switching => {},
+        }
+    }
+
+    /// Request the active stage of the operation to pause
+    fn pause(&mut self) {
+        self.pause_requested = true;
+        match self.stage {
+            AndThenStage::First(ref mut first, _) => first.pause(),
+            AndThenStage::Second(ref mut second) => second.pause(),
+            AndThenStage::Switching => {},
+        }
+    }
+
+    /// Request the active stage of the operation to resume
+    fn resume(&mut self) {
+        self.pause_requested = false;
+        match self.stage {
+            AndThenStage::First(ref mut first, _) => first.resume(),
+            AndThenStage::Second(ref mut second) => second.resume(),
+            AndThenStage::Switching => {},
+        }
+    }
+}
+//
+impl<A, B, F> IAsyncOpStatusClient for AndThen<A, B, F>
+    where A: IAsyncOpStatusClient,
+          B: IAsyncOpStatusClient,
+          F: FnOnce(<DetailsOf<A> as AsyncOpStatusDetails>::DoneDetails) -> B
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = AndThenDetails<DetailsOf<A>, DetailsOf<B>>;
+
+    /// Query the current asynchronous operation status
+    fn current_status(&mut self) -> AsyncOpStatus<Self::StatusDetails> {
+        use status::AsyncOpStatus::*;
+        if let Some(ref status) = self.final_status {
+            return status.clone();
+        }
+
+        // Monitor the first operation, until it is done
+        let mut first_result = None;
+        if let AndThenStage::First(ref mut first, _) = self.stage {
+            let status = match first.current_status() {
+                Pending(details) => Some(Pending(details)),
+                Running(details) => Some(Running(Either::First(details))),
+                Done(details) => {
+                    first_result = Some(details);
+                    None
+                },
+                Cancelled(details) => Some(Cancelled(Either::First(details))),
+                Error(e) =>
+                    Some(Error(AsyncOpError::CustomError(Either::First(e)))),
+            };
+            if let Some(status) = status {
+                if status::is_final(&status) {
+                    self.final_status = Some(status.clone());
+                }
+                return status;
+            }
+        }
+
+        // Once it is done, start the second operation...
+        if let Some(result) = first_result {
+            let stage = ::std::mem::replace(&mut self.stage,
+                                            AndThenStage::Switching);
+            if let AndThenStage::First(_, next) = stage {
+                let mut second = next(result);
+                if self.cancel_requested {
+                    second.cancel();
+                }
+                if self.pause_requested {
+                    second.pause();
+                }
+                self.stage = AndThenStage::Second(second);
+            }
+        }
+
+        // ...and monitor it
+        let status = match self.stage {
+            AndThenStage::Second(ref mut second) => {
+                match second.current_status() {
+                    Done(details) => Done(details),
+                    Cancelled(details) => Cancelled(Either::Second(details)),
+                    Error(e) =>
+                        Error(AsyncOpError::CustomError(Either::Second(e))),
+                    other => Running(Either::Second(other)),
+                }
+            },
+            _ => unreachable!(),
+        };
+        if status::is_final(&status) {
+            self.final_status = Some(status.clone());
+        }
+        status
+    }
+}
+
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use combinators::*;
+    use status::{self, NoDetails, NO_DETAILS};
+    use multithread::polling;
+    use user_event;
+
+    /// Status details of operations which compute an integer
+    #[derive(Clone, Debug, PartialEq)]
+    struct IntDetails {}
+    //
+    impl AsyncOpStatusDetails for IntDetails {
+        type PendingDetails = NoDetails;
+        type RunningDetails = NoDetails;
+        type DoneDetails = Int;
+        type CancelledDetails = NoDetails;
+        type ErrorDetails = NoDetails;
+    }
+    //
+    impl AsyncOpStatusTraits for IntDetails {}
+    //
+    #[derive(Clone, Debug, PartialEq)]
+    struct Int(u32);
+    //
+    impl AsyncOpStatusTraits for Int {}
+
+    /// Check that joined operations are done when both inputs are done
+    #[test]
+    fn join_done() {
+        let (mut event_a, client_a) = user_event::new(status::PENDING);
+        let (event_b, client_b) = user_event::new(status::PENDING);
+        let mut joined = join(client_a, client_b);
+        assert_eq!(joined.current_status(),
+                   AsyncOpStatus::Pending((status::PENDING, status::PENDING)));
+
+        event_a.set_running(NO_DETAILS);
+        assert_eq!(joined.current_status(),
+                   AsyncOpStatus::Running((status::RUNNING, status::PENDING)));
+
+        event_b.complete(NO_DETAILS);
+        assert_eq!(joined.current_status(),
+                   AsyncOpStatus::Running((status::RUNNING, status::DONE)));
+
+        event_a.complete(NO_DETAILS);
+        assert_eq!(joined.current_status(),
+                   AsyncOpStatus::Done((NO_DETAILS, NO_DETAILS)));
+    }
+
+    /// Check that joined operations fail as soon as an input fails
+    #[test]
+    fn join_error() {
+        let (event_a, client_a) = user_event::new(status::RUNNING);
+        let (event_b, client_b) = user_event::new(status::RUNNING);
+        let mut joined = join(client_a, client_b);
+        event_b.fail(NO_DETAILS);
+        let expected = AsyncOpStatus::Error(AsyncOpError::CustomError(
+            Either::Second(AsyncOpError::CustomError(NO_DETAILS))
+        ));
+        assert_eq!(joined.current_status(), expected);
+
+        // The other input should have been cancelled
+        assert!(event_a.cancel_requested());
+
+        // And the final status should not change anymore
+        event_a.complete(NO_DETAILS);
+        assert_eq!(joined.current_status(), expected);
+    }
+
+    /// Check that joining many operations works, including cancellation
+    #[test]
+    fn join_many() {
+        // Joining nothing should be done right away
+        let mut empty =
+            join_all(Vec::<polling::AsyncOpClient<NoDetails>>::new());
+        assert_eq!(empty.current_status(), AsyncOpStatus::Done(vec![]));
+
+        // Joining several operations should wait for all of them
+        let (events, clients): (Vec<_>, Vec<_>) =
+            (0..3).map(|_| user_event::new(status::PENDING)).unzip();
+        let mut joined = join_all(clients);
+        assert_eq!(joined.current_status(),
+                   AsyncOpStatus::Pending(vec![status::PENDING; 3]));
+
+        // Cancelling the joined operation should cancel all inputs
+        joined.cancel();
+        assert!(events.iter().all(|event| event.cancel_requested()));
+        let mut events = events.into_iter();
+        events.next().unwrap().cancel(NO_DETAILS);
+        assert_eq!(joined.current_status(),
+                   AsyncOpStatus::Cancelled(vec![status::CANCELLED,
+                                                 status::PENDING,
+                                                 status::PENDING]));
+
+        // Failures should be reported with the index of the failed input
+        let (events, clients): (Vec<_>, Vec<_>) =
+            (0..3).map(|_| user_event::new(status::PENDING)).unzip();
+        let mut joined = join_all(clients);
+        let mut events = events.into_iter();
+        let _first = events.next();
+        ::std::mem::drop(events.next());
+        assert_eq!(joined.current_status(),
+                   AsyncOpStatus::Error(AsyncOpError::CustomError(
+                       JoinAllError { index: 1,
+                                      error: AsyncOpError::ServerKilled }
+                   )));
+    }
+
+    /// Check that races complete like their first input to complete
+    #[test]
+    fn race_first() {
+        let (mut event_a, client_a) = user_event::new(status::PENDING);
+        let (event_b, client_b) = user_event::new(status::PENDING);
+        let mut raced = race(client_a, client_b);
+        assert_eq!(raced.current_status(),
+                   AsyncOpStatus::Pending((status::PENDING, status::PENDING)));
+
+        event_a.set_running(NO_DETAILS);
+        assert_eq!(raced.current_status(),
+                   AsyncOpStatus::Running((status::RUNNING, status::PENDING)));
+
+        // The winner should decide the status, and the loser be cancelled
+        event_b.complete(NO_DETAILS);
+        assert_eq!(raced.current_status(),
+                   AsyncOpStatus::Done(Either::Second(NO_DETAILS)));
+        assert!(event_a.cancel_requested());
+    }
+
+    /// Check that results can be transformed
+    #[test]
+    fn map_result() {
+        let (mut event, client) = user_event::new(status::PENDING);
+        let mut mapped = map(client, |_| Int(42));
+        assert_eq!(mapped.current_status(), AsyncOpStatus::Pending(NO_DETAILS));
+        event.set_running(NO_DETAILS);
+        assert_eq!(mapped.current_status(), AsyncOpStatus::Running(NO_DETAILS));
+        event.complete(NO_DETAILS);
+        assert_eq!(mapped.current_status(), AsyncOpStatus::Done(Int(42)));
+        assert_eq!(mapped.current_status(), AsyncOpStatus::Done(Int(42)));
+    }
+
+    /// Check that operations can be chained
+    #[test]
+    fn and_then_chain() {
+        // Chain an operation computing a number with one doubling it
+        let (event_a, client_a) = user_event::new::<IntDetails>(
+            AsyncOpStatus::Pending(NO_DETAILS)
+        );
+        let mut chained = and_then(client_a, |Int(x)| {
+            map(user_event::ready::<IntDetails>(AsyncOpStatus::Done(Int(x))),
+                |Int(y)| Int(2 * y))
+        });
+        assert_eq!(chained.current_status(),
+                   AsyncOpStatus::Pending(NO_DETAILS));
+
+        // Completing the first operation should complete the chain
+        event_a.complete(Int(21));
+        assert_eq!(chained.current_status(), AsyncOpStatus::Done(Int(42)));
+
+        // Cancellation of the first stage should be propagated
+        let (event_a, client_a) = user_event::new::<IntDetails>(
+            AsyncOpStatus::Running(NO_DETAILS)
+        );
+        let mut chained = and_then(
+            client_a,
+            |_| -> polling::AsyncOpClient<IntDetails> { unreachable!() }
+        );
+        chained.cancel();
+        assert!(event_a.cancel_requested());
+        event_a.cancel(NO_DETAILS);
+        assert_eq!(chained.current_status(),
+                   AsyncOpStatus::Cancelled(Either::First(NO_DETAILS)));
+    }
+}
@@ -0,0 +1,559 @@
+//! Command queues
+//!
+//! Command queues are a very common way to submit work to asynchronous
+//! backends, and come in many subtly incompatible variants. This module
+//! provides a canonical implementation, in which each command is an
+//! asynchronous operation that gets executed on the queue's worker threads.
+//!
+//! Two flavours of command queue are available:
+//!
+//! - In-order queues execute commands sequentially, in submission order.
+//! - Out-of-order queues execute commands concurrently, and only honor the
+//!   dependencies which were explicitly specified through wait lists.
+//!
+//! Commands are batched on the client side, and only issued to the worker
+//! threads when the queue is flushed, either explicitly or as part of waiting
+//! for the completion of previously enqueued commands. The queue also supports
+//! markers, which complete once all previously enqueued commands have reached
+//! a final status, and barriers, which additionally prevent subsequent commands
+//! from starting until then.
+//!
+//! Wait lists are resolved through a dependency graph, which may be shared
+//! between multiple queues and other operations.
+
+use channels::OpChannels;
+use dependency::{DependencyGraph, OpId, DependencyFailure, SubmitError,
+                 TrackedServerConfig};
+use multithread::blocking;
+use server::{AsyncOpServer, AsyncOpServerConfig};
+use status::{self, AsyncOpStatus, AsyncOpStatusDetails};
+use std::collections::{BTreeMap, BTreeSet};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+
+/// Server interface through which commands report their status
+pub type CommandServer<Config> =
+    AsyncOpServer<TrackedServerConfig<CommandServerConfig<Config>>>;
+
+
+/// Command queue, executing asynchronous operations on worker threads
+pub struct CommandQueue {
+    /// State shared with the worker threads and enqueued commands
+    shared: Arc<QueueShared>,
+
+    /// Dependency graph used to resolve wait lists
+    graph: DependencyGraph,
+}
+//
+impl CommandQueue {
+    /// Create an in-order command queue, with a single worker thread
+    pub fn in_order(graph: &DependencyGraph) -> Self {
+        Self::new(graph, true, 1)
+    }
+
+    /// Create an out-of-order command queue with a number of worker threads
+    pub fn out_of_order(graph: &DependencyGraph, num_workers: usize) -> Self {
+        assert!(num_workers > 0, "At least one worker thread is needed");
+        Self::new(graph, false, num_workers)
+    }
+
+    /// Enqueue a command, which will be executed once its wait list is done
+    ///
+    /// The command is a function which receives the operation's server and
+    /// is in charge of performing the associated work. It will only be called
+    /// once the queue has been flushed, all operations from the wait list are
+    /// done, and the queue's ordering constraints allow for it. If an operation
+    /// from the wait list fails or is cancelled, the command will not be
+    /// called, and its operation will fail or be cancelled in the same way.
+    ///
+    /// If some operations from the wait list are not part of the queue's
+    /// dependency graph, the operation's server is handed back along with the
+    /// error, and the command is dropped without being called.
+    ///
+    pub fn enqueue<Config, F>(
+        &self,
+        server: AsyncOpServer<Config>,
+        wait_list: &[OpId],
+        command: F
+    ) -> Result<OpId, SubmitError<Config>>
+        where Config: AsyncOpServerConfig + Send + 'static,
+              <Config::StatusDetails as AsyncOpStatusDetails>::CancelledDetails:
+                  From<DependencyFailure>,
+              <Config::StatusDetails as AsyncOpStatusDetails>::ErrorDetails:
+                  From<DependencyFailure>,
+              F: FnOnce(CommandServer<Config>) + Send + 'static
+    {
+        // Register the command, then hand it over to the dependency graph,
+        // which will make it ready for execution once its wait list is done.
+        let (seq, server) = self.register(server, false);
+        let shared = self.shared.clone();
+        self.graph.submit(server, wait_list, move |server| {
+            let mut state = shared.state.lock().unwrap();
+            state.ready.insert(seq, Box::new(move || command(server)));
+            shared.cv.notify_all();
+        }).map_err(|SubmitError { error, server }| {
+            // Forget about the rejected command, and unwrap its server
+            self.shared.complete(seq);
+            SubmitError {
+                error,
+                server: server.map_config(|config| config.into_inner().inner),
+            }
+        })
+    }
+
+    /// Enqueue a marker, whose operation will complete once all previously
+    /// enqueued commands have reached a final status
+    pub fn enqueue_marker<Config>(&self, server: AsyncOpServer<Config>) -> OpId
+        where Config: AsyncOpServerConfig + Send + 'static,
+              <Config::StatusDetails as AsyncOpStatusDetails>::DoneDetails:
+                  Default
+    {
+        self.enqueue_sync_point(server, false)
+    }
+
+    /// Enqueue a barrier, which acts like a marker but also prevents commands
+    /// that are enqueued after it from starting until it has completed
+    pub fn enqueue_barrier<Config>(&self, server: AsyncOpServer<Config>) -> OpId
+        where Config: AsyncOpServerConfig + Send + 'static,
+              <Config::StatusDetails as AsyncOpStatusDetails>::DoneDetails:
+                  Default
+    {
+        self.enqueue_sync_point(server, true)
+    }
+
+    /// Issue all previously enqueued commands to the worker threads
+    pub fn flush(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.flushed = state.next_seq;
+        self.shared.cv.notify_all();
+    }
+
+    /// Flush the queue, then block until all previously enqueued commands have
+    /// reached a final status
+    pub fn finish(&self) {
+        let (server, mut client) =
+            blocking::AsyncOp::new(status::PENDING).split();
+        self.enqueue_marker(server);
+        self.flush();
+        while !status::is_final(&client.wait()) {}
+    }
+
+    /// Set up a command queue and its worker threads
+    fn new(graph: &DependencyGraph,
+           in_order: bool,
+           num_workers: usize) -> Self {
+        let shared = Arc::new(
+            QueueShared {
+                state: Mutex::new(
+                    QueueState {
+                        next_seq: 0,
+                        flushed: 0,
+                        incomplete: BTreeSet::new(),
+                        barriers: BTreeSet::new(),
+                        ready: BTreeMap::new(),
+                        sync_points: BTreeMap::new(),
+                        shutting_down: false,
+                    }
+                ),
+                cv: Condvar::new(),
+                in_order,
+            }
+        );
+        for _ in 0..num_workers {
+            let worker_shared = shared.clone();
+            thread::spawn(move || worker_shared.run_worker());
+        }
+        CommandQueue { shared, graph: graph.clone() }
+    }
+
+    /// Assign a sequence number to a new command, and wrap its server so
+    /// that the queue gets notified when it completes
+    fn register<Config: AsyncOpServerConfig>(
+        &self,
+        server: AsyncOpServer<Config>,
+        is_barrier: bool
+    ) -> (u64, AsyncOpServer<CommandServerConfig<Config>>) {
+        let seq = {
+            let mut state = self.shared.state.lock().unwrap();
+            let seq = state.next_seq;
+            state.next_seq += 1;
+            state.incomplete.insert(seq);
+            if is_barrier {
+                state.barriers.insert(seq);
+            }
+            seq
+        };
+        let shared = self.shared.clone();
+        (seq, server.map_config(|inner| CommandServerConfig {
+            inner: inner.into_inner(),
+            shared,
+            seq,
+        }))
+    }
+
+    /// Enqueue a marker or barrier
+    fn enqueue_sync_point<Config>(&self,
+                                  server: AsyncOpServer<Config>,
+                                  is_barrier: bool) -> OpId
+        where Config: AsyncOpServerConfig + Send + 'static,
+              <Config::StatusDetails as AsyncOpStatusDetails>::DoneDetails:
+                  Default
+    {
+        // Register the sync point, and make it visible to wait lists
+        let (seq, server) = self.register(server, is_barrier);
+        let (id, mut server) = self.graph.track(server);
+
+        // Schedule its completion, which may be possible right away
+        let completions = {
+            let mut state = self.shared.state.lock().unwrap();
+            state.sync_points.insert(seq, Box::new(move || {
+                server.update(AsyncOpStatus::Done(Default::default()))
+            }));
+            state.take_completed_sync_points()
+        };
+        for completion in completions {
+            completion();
+        }
+        id
+    }
+}
+//
+impl Drop for CommandQueue {
+    /// Flush the queue and let the worker threads exit once all commands have
+    /// reached a final status
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.flushed = state.next_seq;
+        state.shutting_down = true;
+        self.shared.cv.notify_all();
+    }
+}
+
+
+/// Server configuration of enqueued commands, which notifies the queue once
+/// the command has reached a final status
+pub struct CommandServerConfig<Config: AsyncOpServerConfig> {
+    /// Configuration of the underlying operation
+    inner: Config,
+
+    /// Command queue to be notified
+    shared: Arc<QueueShared>,
+
+    /// Sequence number of the command within the queue
+    seq: u64,
+}
+//
+impl<Config: AsyncOpServerConfig> AsyncOpServerConfig
+    for CommandServerConfig<Config>
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Config::StatusDetails;
+
+    /// Method used to send status updates to the client
+    fn update(&mut self, status: AsyncOpStatus<Self::StatusDetails>) {
+        let is_final = status::is_final(&status);
+        self.inner.update(status);
+        if is_final {
+            self.shared.complete(self.seq);
+        }
+    }
+
+    /// Side channels shared with the client
+    fn channels(&self) -> &OpChannels {
+        self.inner.channels()
+    }
+}
+
+
+/// Type-erased command or marker completion, ready to be executed
+type Job = Box<dyn FnOnce() + Send>;
+
+
+/// State shared between a command queue, its workers and its commands
+struct QueueShared {
+    /// Mutable queue state
+    state: Mutex<QueueState>,
+
+    /// Condition variable used to wake up workers when commands can start,
+    /// or when the queue is shutting down
+    cv: Condvar,
+
+    /// Whether commands must be executed in submission order
+    in_order: bool,
+}
+//
+impl QueueShared {
+    /// Record that a command will not run anymore, and complete the markers
+    /// and barriers which were waiting for it
+    fn complete(&self, seq: u64) {
+        let completions = {
+            let mut state = self.state.lock().unwrap();
+            state.incomplete.remove(&seq);
+            state.barriers.remove(&seq);
+            self.cv.notify_all();
+            state.take_completed_sync_points()
+        };
+        for completion in completions {
+            completion();
+        }
+    }
+
+    /// Main loop of the worker threads
+    fn run_worker(&self) {
+        loop {
+            // Wait for a command to be runnable, or for the queue to be done
+            let job = {
+                let mut state = self.state.lock().unwrap();
+                loop {
+                    if let Some(seq) = state.next_runnable(self.in_order) {
+                        break state.ready.remove(&seq).unwrap();
+                    }
+                    if state.shutting_down && state.incomplete.is_empty() {
+                        return;
+                    }
+                    state = self.cv.wait(state).unwrap();
+                }
+            };
+
+            // Run it. If it panics, its server will be dropped, which notifies
+            // the client, so there is no reason to kill the worker as well.
+            let _ = panic::catch_unwind(AssertUnwindSafe(job));
+        }
+    }
+}
+
+
+/// Mutable command queue state
+struct QueueState {
+    /// Sequence number of the next enqueued command
+    next_seq: u64,
+
+    /// Commands whose sequence number is below this have been flushed
+    flushed: u64,
+
+    /// Commands, markers and barriers which have not reached a final status
+    incomplete: BTreeSet<u64>,
+
+    /// Barriers which have not reached a final status
+    barriers: BTreeSet<u64>,
+
+    /// Commands whose wait list is done, and which were not started yet
+    ready: BTreeMap<u64, Job>,
+
+    /// Markers and barriers which have not been completed yet
+    sync_points: BTreeMap<u64, Job>,
+
+    /// Whether the command queue was dropped
+    shutting_down: bool,
+}
+//
+impl QueueState {
+    /// Find the next command that can be started, if any
+    fn next_runnable(&self, in_order: bool) -> Option<u64> {
+        // Every start condition is monotonic in the sequence number, so only
+        // the oldest ready command needs to be checked
+        let seq = *self.ready.keys().next()?;
+        let flushed = seq < self.flushed;
+        let after_barrier =
+            self.barriers.iter().next().is_some_and(|&b| b < seq);
+        let in_turn =
+            !in_order || self.incomplete.iter().next() == Some(&seq);
+        if flushed && !after_barrier && in_turn {
+            Some(seq)
+        } else {
+            None
+        }
+    }
+
+    /// Extract the markers and barriers whose predecessors are all complete
+    fn take_completed_sync_points(&mut self) -> Vec<Job> {
+        let completed = self.sync_points
+                            .keys()
+                            .cloned()
+                            .take_while(|&seq| {
+                                self.incomplete.range(..seq).next().is_none()
+                            })
+                            .collect::<Vec<_>>();
+        completed.into_iter()
+                 .map(|seq| self.sync_points.remove(&seq).unwrap())
+                 .collect()
+    }
+}
+
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use command_queue::*;
+    use dependency::DependencyError;
+    use multithread::polling::{AsyncOp, AsyncOpClient, AsyncOpServer,
+                               PollingServerConfig};
+    use status::NoDetails;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Create a new pending operation
+    fn new_op() -> (AsyncOpServer<NoDetails>, AsyncOpClient<NoDetails>) {
+        AsyncOp::new(status::PENDING).split()
+    }
+
+    /// Command which reports its execution, then completes
+    fn report(log: &Arc<Mutex<Vec<u32>>>, id: u32)
+        -> impl FnOnce(CommandServer<PollingServerConfig<NoDetails>>)
+                + Send + 'static
+    {
+        let log = log.clone();
+        move |mut server| {
+            log.lock().unwrap().push(id);
+            server.update(status::DONE);
+        }
+    }
+
+    /// Check that in-order queues run commands sequentially, after flushing
+    #[test]
+    fn in_order() {
+        // Enqueue a slow command, then a fast one
+        let graph = DependencyGraph::new();
+        let queue = CommandQueue::in_order(&graph);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (server1, mut client1) = new_op();
+        let slow_log = log.clone();
+        queue.enqueue(server1, &[], move |mut server| {
+            thread::sleep(Duration::from_millis(50));
+            slow_log.lock().unwrap().push(1);
+            server.update(status::DONE);
+        }).unwrap();
+        let (server2, mut client2) = new_op();
+        queue.enqueue(server2, &[], report(&log, 2)).unwrap();
+
+        // Nothing should happen until the queue is flushed
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(*client1.status(), status::PENDING);
+        assert!(log.lock().unwrap().is_empty());
+
+        // Then commands should run in order
+        queue.finish();
+        assert_eq!(*log.lock().unwrap(), vec![1, 2]);
+        assert_eq!(*client1.status(), status::DONE);
+        assert_eq!(*client2.status(), status::DONE);
+    }
+
+    /// Check that out-of-order queues honor wait lists, and only them
+    #[test]
+    fn out_of_order() {
+        // Enqueue a command which blocks until we let it go...
+        let graph = DependencyGraph::new();
+        let queue = CommandQueue::out_of_order(&graph, 2);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (release, released) = mpsc::channel::<()>();
+        let (server1, _client1) = new_op();
+        let blocked_log = log.clone();
+        let first = queue.enqueue(server1, &[], move |mut server| {
+            released.recv().unwrap();
+            blocked_log.lock().unwrap().push(1);
+            server.update(status::DONE);
+        }).unwrap();
+
+        // ...a command which depends on it, and an independent command
+        let (server2, mut client2) = new_op();
+        queue.enqueue(server2, &[first], report(&log, 2)).unwrap();
+        let (server3, mut client3) = new_op();
+        queue.enqueue(server3, &[], report(&log, 3)).unwrap();
+        queue.flush();
+
+        // The independent command should be able to run concurrently
+        while *client3.status() != status::DONE {
+            thread::yield_now();
+        }
+        assert_eq!(*client2.status(), status::PENDING);
+
+        // The dependent command should run once the first one is done
+        release.send(()).unwrap();
+        queue.finish();
+        assert_eq!(*log.lock().unwrap(), vec![3, 1, 2]);
+        assert_eq!(*client2.status(), status::DONE);
+    }
+
+    /// Check the behaviour of markers and barriers
+    #[test]
+    fn markers_and_barriers() {
+        // Block the queue with a command which waits for our go
+        let graph = DependencyGraph::new();
+        let queue = CommandQueue::out_of_order(&graph, 2);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (release, released) = mpsc::channel::<()>();
+        let (server1, _client1) = new_op();
+        queue.enqueue(server1, &[], move |mut server| {
+            released.recv().unwrap();
+            server.update(status::DONE);
+        }).unwrap();
+
+        // Markers should not prevent subsequent commands from running...
+        let (marker_server, mut marker_client) = new_op();
+        queue.enqueue_marker(marker_server);
+        let (server2, mut client2) = new_op();
+        queue.enqueue(server2, &[], report(&log, 2)).unwrap();
+
+        // ...but barriers should
+        let (barrier_server, mut barrier_client) = new_op();
+        queue.enqueue_barrier(barrier_server);
+        let (server3, mut client3) = new_op();
+        queue.enqueue(server3, &[], report(&log, 3)).unwrap();
+        queue.flush();
+        while *client2.status() != status::DONE {
+            thread::yield_now();
+        }
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(*marker_client.status(), status::PENDING);
+        assert_eq!(*barrier_client.status(), status::PENDING);
+        assert_eq!(*client3.status(), status::PENDING);
+
+        // Once the first command is done, everything should complete
+        release.send(()).unwrap();
+        queue.finish();
+        assert_eq!(*marker_client.status(), status::DONE);
+        assert_eq!(*barrier_client.status(), status::DONE);
+        assert_eq!(*client3.status(), status::DONE);
+        assert_eq!(*log.lock().unwrap(), vec![2, 3]);
+    }
+
+    /// Check that failed wait lists and panicking commands are handled
+    #[test]
+    fn failures() {
+        // Enqueue a command which panics, and one which depends on it
+        let graph = DependencyGraph::new();
+        let queue = CommandQueue::in_order(&graph);
+        let (server1, mut client1) = new_op();
+        let first = queue.enqueue(server1, &[], |_| panic!("Oops")).unwrap();
+        let (server2, mut client2) = new_op();
+        queue.enqueue(server2, &[first], |_| unreachable!()).unwrap();
+
+        // The queue should survive, and report the failures to clients
+        queue.finish();
+        assert_eq!(*client1.status(), status::ERROR_SERVER_KILLED);
+        assert_eq!(*client2.status(),
+                   AsyncOpStatus::Error(status::AsyncOpError::CustomError(
+                       NoDetails {}
+                   )));
+
+        // Operations from other dependency graphs should be rejected
+        let other_queue = CommandQueue::in_order(&DependencyGraph::new());
+        let (server3, mut client3) = new_op();
+        let error = other_queue.enqueue(server3, &[first], |_| {})
+                               .unwrap_err();
+        assert_eq!(error.error, DependencyError::UnknownOp(first));
+        assert_eq!(*client3.status(), status::PENDING);
+
+        // The rejected command should not hold back markers
+        let mut server3 = error.server;
+        let (marker_server, mut marker_client) = new_op();
+        other_queue.enqueue_marker(marker_server);
+        other_queue.finish();
+        assert_eq!(*marker_client.status(), status::DONE);
+        server3.update(status::DONE);
+        assert_eq!(*client3.status(), status::DONE);
+    }
+}
@@ -0,0 +1,225 @@
+//! Control messages, sent by clients to the servers of running operations
+//!
+//! Cancellation and pausing are the only requests which every client can send
+//! to a server. Applications often need more than that: changing the priority
+//! of an operation, raising its verbosity level, or sending it custom commands
+//! while it is running. This module provides a typed control channel for this
+//! purpose, which flows in the opposite direction of the status channel.
+//!
+//! The type of the messages which an operation accepts is specified by its
+//! status details, through the AsyncOpControlDetails trait. Clients send them
+//! through the IAsyncOpControlClient interface, which is implemented in every
+//! monitoring mode, and servers receive them in the order where they were sent
+//! using either a non-blocking or a blocking method.
+//!
+//! Like status updates, control messages are only a means of communication: it
+//! is up to the server to decide when to check for them, and what to do.
+
+use cancellation::{CancelWakeups, CancellationToken};
+use status::AsyncOpStatusDetails;
+use std::any::Any;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+
+
+/// Status details of operations which accept control messages
+pub trait AsyncOpControlDetails: AsyncOpStatusDetails {
+    /// Messages which clients can send to the operation's server
+    type ControlMessage: Send + 'static;
+}
+
+
+/// Channel through which control messages flow from a client to a server
+///
+/// The channel itself is not typed, so that it can be shared by the clients and
+/// servers of any operation. Type safety is instead enforced by the client and
+/// server interfaces, which only let the ControlMessage type of the operation
+/// go through, and are the only way to reach the channel from outside of this
+/// crate.
+///
+#[derive(Clone, Default)]
+pub(crate) struct ControlChannel {
+    /// Shared state of the channel
+    shared: Arc<ControlShared>,
+}
+//
+impl ControlChannel {
+    /// Create a new channel, with no pending message
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send a message to the server
+    pub fn send<M: Send + 'static>(&self, message: M) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.push_back(Box::new(message));
+        self.shared.message_cv.notify_all();
+    }
+
+    /// Receive the oldest message which the server has not received yet, if
+    /// any, without blocking
+    pub fn try_receive<M: Send + 'static>(&self) -> Option<M> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.pop_front().map(Self::downcast)
+    }
+
+    /// Block until a message is received or the token is cancelled, and return
+    /// the message if any
+    pub fn receive<M: Send + 'static>(
+        &self,
+        cancellation: &CancellationToken
+    ) -> Option<M> {
+        // Make sure that we will be woken up if the token is cancelled
+        let shared = self.shared.clone();
+        self.shared.wakeups.register(cancellation, move || {
+            let _lock = shared.queue.lock().unwrap();
+            shared.message_cv.notify_all();
+        });
+
+        // Wait for a message, giving up on cancellation
+        let mut queue = self.shared.queue.lock().unwrap();
+        loop {
+            if let Some(message) = queue.pop_front() {
+                return Some(Self::downcast(message));
+            }
+            if cancellation.is_cancelled() { return None; }
+            queue = self.shared.message_cv.wait(queue).unwrap();
+        }
+    }
+
+    /// Recover the concrete type of a message
+    fn downcast<M: Send + 'static>(message: Box<dyn Any + Send>) -> M {
+        *message.downcast::<M>()
+                .expect("Control messages should have the operation's type")
+    }
+}
+//
+impl fmt::Debug for ControlChannel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ControlChannel")
+         .field("pending", &self.shared.queue.lock().unwrap().len())
+         .finish()
+    }
+}
+
+
+/// Shared state of a control channel
+#[derive(Default)]
+struct ControlShared {
+    /// Messages which were sent, but not received yet
+    queue: Mutex<VecDeque<Box<dyn Any + Send>>>,
+
+    /// Condition variable used to wake up servers waiting for a message
+    message_cv: Condvar,
+
+    /// Tokens whose cancellation wakes up waiting servers
+    wakeups: CancelWakeups,
+}
+
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use client::{IAsyncOpClient, IAsyncOpControlClient};
+    use control::*;
+    use executor::inline::InlineCallbackExecutor;
+    use multithread::{blocking, callback, polling};
+    use status::{self, AsyncOpStatus, AsyncOpStatusTraits, NoDetails};
+    use std::thread;
+    use std::time::Duration;
+
+    /// Commands which can be sent to our test operations
+    #[derive(Debug, PartialEq)]
+    enum Command {
+        SetPriority(u8),
+        SetVerbosity(u32),
+    }
+
+    /// Status details of operations which accept commands
+    #[derive(Clone, Debug, PartialEq)]
+    struct CommandDetails {}
+    //
+    impl AsyncOpStatusDetails for CommandDetails {
+        type PendingDetails = NoDetails;
+        type RunningDetails = NoDetails;
+        type DoneDetails = NoDetails;
+        type CancelledDetails = NoDetails;
+        type ErrorDetails = NoDetails;
+    }
+    //
+    impl AsyncOpStatusTraits for CommandDetails {}
+    //
+    impl AsyncOpControlDetails for CommandDetails {
+        type ControlMessage = Command;
+    }
+
+    /// Running status of our test operations
+    fn running() -> AsyncOpStatus<CommandDetails> {
+        AsyncOpStatus::Running(status::NO_DETAILS)
+    }
+
+    /// Check that messages are delivered in order in every mode
+    #[test]
+    fn delivery() {
+        // Polling mode
+        let (mut server, mut client) = polling::AsyncOp::new(running()).split();
+        assert_eq!(server.try_receive_control(), None);
+        client.send_control(Command::SetPriority(3));
+        client.send_control(Command::SetVerbosity(2));
+        assert_eq!(server.try_receive_control(), Some(Command::SetPriority(3)));
+        assert_eq!(server.try_receive_control(),
+                   Some(Command::SetVerbosity(2)));
+        assert_eq!(server.try_receive_control(), None);
+
+        // Blocking mode
+        let (mut server, mut client) =
+            blocking::AsyncOp::new(running()).split();
+        client.send_control(Command::SetPriority(1));
+        assert_eq!(server.try_receive_control(), Some(Command::SetPriority(1)));
+
+        // Callback mode
+        let mut executor = InlineCallbackExecutor::new();
+        let (mut server, mut client) =
+            callback::new_async_op(|_: AsyncOpStatus<CommandDetails>| {},
+                                   &mut executor,
+                                   running()).split();
+        client.send_control(Command::SetVerbosity(7));
+        assert_eq!(server.try_receive_control(),
+                   Some(Command::SetVerbosity(7)));
+    }
+
+    /// Check that servers can block until a message arrives
+    #[test]
+    fn receive() {
+        let (mut server, mut client) =
+            blocking::AsyncOp::new(running()).split();
+        let receiver = thread::spawn(move || {
+            let message = server.receive_control();
+            (server, message)
+        });
+        thread::sleep(Duration::from_millis(10));
+        client.send_control(Command::SetPriority(9));
+        let (mut server, message) = receiver.join().unwrap();
+        assert_eq!(message, Some(Command::SetPriority(9)));
+
+        // Cancellation should wake up waiting servers
+        let receiver = thread::spawn(move || server.receive_control());
+        thread::sleep(Duration::from_millis(10));
+        client.cancel();
+        assert_eq!(receiver.join().unwrap(), None);
+
+        // So should the cancellation of tokens which were not used before
+        let channel = ControlChannel::new();
+        channel.send(1u8);
+        assert_eq!(channel.receive(&CancellationToken::new()), Some(1u8));
+        let token = CancellationToken::new();
+        let c_token = token.clone();
+        let receiver = thread::spawn(move || {
+            channel.receive::<u8>(&c_token)
+        });
+        thread::sleep(Duration::from_millis(10));
+        token.cancel();
+        assert_eq!(receiver.join().unwrap(), None);
+    }
+}
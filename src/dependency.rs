@@ -0,0 +1,868 @@
+//! Dependencies between asynchronous operations
+//!
+//! A common need when orchestrating asynchronous work is to only start an
+//! operation once some other operations have successfully completed. OpenCL
+//! calls this an event wait list, task-based runtimes call it a dependency
+//! graph, but the idea is always the same.
+//!
+//! This module provides a dependency graph in which operations of any kind can
+//! be registered. Operations with prerequisites are kept in the pending state,
+//! and only launched once all of their prerequisites are done. If one of these
+//! prerequisites fails or is cancelled instead, the dependent operation fails
+//! or is cancelled as well, with details telling which prerequisite is to
+//! blame. This failure then propagates further down the graph.
+//!
+//! Since waiting for a dependency cycle would hang forever, the graph refuses
+//! to create one, and reports the offending cycle instead. For debugging
+//! purposes, the graph can also be exported in Graphviz's DOT format.
+
+use channels::OpChannels;
+use server::{AsyncOpServer, AsyncOpServerConfig};
+use status::{self, AsyncOpError, AsyncOpStatus, AsyncOpStatusDetails,
+             AsyncOpStatusTraits, NoDetails};
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::Entry;
+use std::error::Error;
+use std::fmt::{self, Write};
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+
+/// Identifier of an operation within a dependency graph
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct OpId(u64);
+//
+impl fmt::Display for OpId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+
+/// Details on why an operation could not run due to one of its dependencies
+///
+/// Operations which are submitted with prerequisites must have cancellation
+/// and error details which can be constructed from this information.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct DependencyFailure {
+    /// Prerequisite which did not complete successfully
+    pub dependency: OpId,
+}
+//
+impl fmt::Display for DependencyFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Dependency {} did not complete successfully",
+               self.dependency)
+    }
+}
+//
+impl Error for DependencyFailure {}
+//
+impl AsyncOpStatusTraits for DependencyFailure {}
+//
+impl From<DependencyFailure> for NoDetails {
+    /// Standard statuses simply discard dependency failure information
+    fn from(_: DependencyFailure) -> Self {
+        status::NO_DETAILS
+    }
+}
+
+
+/// Error which can occur when manipulating a dependency graph
+#[derive(Clone, Debug, PartialEq)]
+pub enum DependencyError {
+    /// The specified operation is not part of the dependency graph
+    UnknownOp(OpId),
+
+    /// The specified operation was already launched, so it is too late to
+    /// give it extra prerequisites
+    AlreadyStarted(OpId),
+
+    /// Adding this dependency would create the following cycle
+    Cycle(Vec<OpId>),
+}
+//
+impl fmt::Display for DependencyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DependencyError::UnknownOp(id) =>
+                write!(f, "Operation {} is not part of the graph", id),
+            DependencyError::AlreadyStarted(id) =>
+                write!(f, "Operation {} was already started", id),
+            DependencyError::Cycle(ref cycle) => {
+                write!(f, "Dependency cycle detected:")?;
+                for id in cycle {
+                    write!(f, " {}", id)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+//
+impl Error for DependencyError {}
+
+
+/// Error returned when an operation could not be submitted to a dependency
+/// graph, handing its server back to the caller
+pub struct SubmitError<Config: AsyncOpServerConfig> {
+    /// Reason why the operation was rejected
+    pub error: DependencyError,
+
+    /// Server of the rejected operation
+    pub server: AsyncOpServer<Config>,
+}
+//
+impl<Config: AsyncOpServerConfig> fmt::Debug for SubmitError<Config> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SubmitError {{ error: {:?}, .. }}", self.error)
+    }
+}
+//
+impl<Config: AsyncOpServerConfig> fmt::Display for SubmitError<Config> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+//
+impl<Config: AsyncOpServerConfig> Error for SubmitError<Config> {}
+
+
+/// Graph of dependencies between asynchronous operations
+///
+/// This object can be cheaply cloned, and all clones refer to the same graph.
+///
+#[derive(Clone, Default)]
+pub struct DependencyGraph {
+    /// State shared between all clones of the graph and tracked operations
+    shared: Arc<Mutex<GraphState>>,
+}
+//
+impl DependencyGraph {
+    /// Create an empty dependency graph
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start tracking an operation which can run right away, so that other
+    /// operations may depend on it
+    ///
+    /// The operation must not have reached a final status yet. It should be
+    /// driven through the returned server, which notifies the graph when the
+    /// operation completes.
+    ///
+    pub fn track<Config: AsyncOpServerConfig>(
+        &self,
+        server: AsyncOpServer<Config>
+    ) -> (OpId, AsyncOpServer<TrackedServerConfig<Config>>) {
+        debug_assert!(!server.is_final(),
+                      "Cannot tell how a completed operation ended");
+        let id = self.shared.lock().unwrap().add_node(NodeState::Running);
+        (id, self.wrap(id, server))
+    }
+
+    /// Submit an operation which should only be launched once all of its
+    /// prerequisites are done
+    ///
+    /// Until then, the operation stays pending. Once its prerequisites are
+    /// done, `launch` is called on whichever thread completed the last of them
+    /// (or on the current thread if they are done already), and should only
+    /// start the operation and return quickly. If a prerequisite fails or is
+    /// cancelled, the operation is failed or cancelled without being launched.
+    ///
+    /// If some prerequisites are not part of the graph, the operation's server
+    /// is handed back along with the error, and nothing is launched.
+    ///
+    pub fn submit<Config, F>(
+        &self,
+        server: AsyncOpServer<Config>,
+        prerequisites: &[OpId],
+        launch: F
+    ) -> Result<OpId, SubmitError<Config>>
+        where Config: AsyncOpServerConfig + Send + 'static,
+              <Config::StatusDetails as AsyncOpStatusDetails>::CancelledDetails:
+                  From<DependencyFailure>,
+              <Config::StatusDetails as AsyncOpStatusDetails>::ErrorDetails:
+                  From<DependencyFailure>,
+              F: FnOnce(AsyncOpServer<TrackedServerConfig<Config>>)
+                 + Send + 'static
+    {
+        // Register the new operation, after checking its prerequisites
+        let (id, action) = {
+            let mut state = self.shared.lock().unwrap();
+            if let Some(&unknown) =
+                prerequisites.iter().find(|id| !state.nodes.contains_key(id))
+            {
+                return Err(SubmitError {
+                    error: DependencyError::UnknownOp(unknown),
+                    server,
+                });
+            }
+            let id = state.add_node(NodeState::Running);
+            let waiting_op = Box::new(
+                WaitingOpImpl { server: self.wrap(id, server), launch }
+            );
+            state.nodes.get_mut(&id).unwrap().state =
+                NodeState::Waiting { remaining: 0, op: waiting_op };
+            let mut action = None;
+            for &prerequisite in prerequisites {
+                action = action.or(state.add_edge(id, prerequisite));
+            }
+            (id, action.or_else(|| state.take_if_ready(id)))
+        };
+
+        // Launch or fail the operation if it does not need to wait
+        if let Some(action) = action {
+            action.run();
+        }
+        Ok(id)
+    }
+
+    /// Make an operation which has not been launched yet wait for another
+    pub fn add_dependency(&self,
+                          op: OpId,
+                          prerequisite: OpId) -> Result<(), DependencyError> {
+        let action = {
+            let mut state = self.shared.lock().unwrap();
+
+            // Check that both operations exist and that op is still waiting
+            match state.nodes.get(&op) {
+                Some(&Node { state: NodeState::Waiting { .. }, .. }) => {},
+                Some(_) => return Err(DependencyError::AlreadyStarted(op)),
+                None => return Err(DependencyError::UnknownOp(op)),
+            }
+            if !state.nodes.contains_key(&prerequisite) {
+                return Err(DependencyError::UnknownOp(prerequisite));
+            }
+
+            // Check that the new dependency would not create a cycle
+            if let Some(mut path) = state.find_path(prerequisite, op) {
+                path.insert(0, op);
+                return Err(DependencyError::Cycle(path));
+            }
+
+            // Add the dependency. The prerequisite may have failed already.
+            state.add_edge(op, prerequisite)
+        };
+        if let Some(action) = action {
+            action.run();
+        }
+        Ok(())
+    }
+
+    /// Give a human-readable label to an operation, for debugging purposes
+    pub fn set_label(&self,
+                     op: OpId,
+                     label: &str) -> Result<(), DependencyError> {
+        let mut state = self.shared.lock().unwrap();
+        let node = state.nodes
+                        .get_mut(&op)
+                        .ok_or(DependencyError::UnknownOp(op))?;
+        node.label = Some(label.to_owned());
+        Ok(())
+    }
+
+    /// Forget about operations which have reached a final status
+    ///
+    /// Operations which are forgotten cannot be used as prerequisites anymore.
+    /// Calling this method periodically avoids unbounded graph growth.
+    ///
+    pub fn clear_finished(&self) {
+        let mut state = self.shared.lock().unwrap();
+        state.nodes.retain(|_, node| {
+            !matches!(node.state, NodeState::Finished(_))
+        });
+        let nodes = &mut state.nodes;
+        let ids = nodes.keys().cloned().collect::<Vec<_>>();
+        for id in ids {
+            let (prerequisites, dependents) = {
+                let node = &nodes[&id];
+                (node.prerequisites.clone(), node.dependents.clone())
+            };
+            let prerequisites = prerequisites.into_iter()
+                                             .filter(|p| nodes.contains_key(p))
+                                             .collect();
+            let dependents = dependents.into_iter()
+                                       .filter(|d| nodes.contains_key(d))
+                                       .collect();
+            let node = nodes.get_mut(&id).unwrap();
+            node.prerequisites = prerequisites;
+            node.dependents = dependents;
+        }
+    }
+
+    /// Export the dependency graph in Graphviz's DOT format
+    ///
+    /// Edges go from each prerequisite to the operations which depend on it,
+    /// and nodes are labeled with the current state of the operation.
+    ///
+    pub fn to_dot(&self) -> String {
+        let state = self.shared.lock().unwrap();
+        let mut dot = String::from("digraph dependencies {\n");
+        for (id, node) in &state.nodes {
+            let mut label = id.to_string();
+            if let Some(ref name) = node.label {
+                label.push(' ');
+                label.push_str(name);
+            }
+            let state_name = match node.state {
+                NodeState::Waiting { .. } => "waiting",
+                NodeState::Running => "running",
+                NodeState::Finished(Outcome::Done) => "done",
+                NodeState::Finished(Outcome::Cancelled) => "cancelled",
+                NodeState::Finished(Outcome::Error) => "error",
+            };
+            writeln!(dot, "    op{} [label=\"{} ({})\"];",
+                     id.0,
+                     label.replace('\\', "\\\\").replace('"', "\\\""),
+                     state_name).unwrap();
+        }
+        for (id, node) in &state.nodes {
+            for dependent in &node.dependents {
+                writeln!(dot, "    op{} -> op{};", id.0, dependent.0).unwrap();
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Wrap an operation's server so that it notifies us of its completion
+    fn wrap<Config: AsyncOpServerConfig>(
+        &self,
+        id: OpId,
+        server: AsyncOpServer<Config>
+    ) -> AsyncOpServer<TrackedServerConfig<Config>> {
+        let graph = self.shared.clone();
+        server.map_config(|inner| TrackedServerConfig {
+            inner: inner.into_inner(),
+            graph,
+            id,
+        })
+    }
+}
+
+
+/// Server configuration of operations tracked by a dependency graph, which
+/// notifies the graph once the operation has reached a final status
+pub struct TrackedServerConfig<Config: AsyncOpServerConfig> {
+    /// Configuration of the underlying operation
+    inner: Config,
+
+    /// Dependency graph which tracks the operation
+    graph: Arc<Mutex<GraphState>>,
+
+    /// Identifier of the operation within the graph
+    id: OpId,
+}
+//
+impl<Config: AsyncOpServerConfig> AsyncOpServerConfig
+    for TrackedServerConfig<Config>
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Config::StatusDetails;
+
+    /// Method used to send status updates to the client
+    fn update(&mut self, status: AsyncOpStatus<Self::StatusDetails>) {
+        let outcome = match status {
+            AsyncOpStatus::Pending(_) | AsyncOpStatus::Running(_) => None,
+            AsyncOpStatus::Done(_) => Some(Outcome::Done),
+            AsyncOpStatus::Cancelled(_) => Some(Outcome::Cancelled),
+            AsyncOpStatus::Error(_) => Some(Outcome::Error),
+        };
+        self.inner.update(status);
+        if let Some(outcome) = outcome {
+            let actions = self.graph.lock().unwrap().finish(self.id, outcome);
+            for action in actions {
+                action.run();
+            }
+        }
+    }
+
+    /// Side channels shared with the client
+    fn channels(&self) -> &OpChannels {
+        self.inner.channels()
+    }
+}
+
+
+/// Internal state of the dependency graph
+#[derive(Default)]
+struct GraphState {
+    /// Identifier of the next operation to be added to the graph
+    next_id: u64,
+
+    /// Operations of the graph, ordered by identifier
+    nodes: BTreeMap<OpId, Node>,
+}
+//
+impl GraphState {
+    /// Add a new operation to the graph
+    fn add_node(&mut self, state: NodeState) -> OpId {
+        let id = OpId(self.next_id);
+        self.next_id += 1;
+        self.nodes.insert(id, Node {
+            label: None,
+            prerequisites: Vec::new(),
+            dependents: Vec::new(),
+            state,
+        });
+        id
+    }
+
+    /// Make a waiting operation depend on another operation, and tell if this
+    /// means that the operation must now fail
+    fn add_edge(&mut self, op: OpId, prerequisite: OpId) -> Option<Action> {
+        // Record the dependency on both sides
+        let prerequisite_state = {
+            let node = self.nodes.get_mut(&prerequisite).unwrap();
+            node.dependents.push(op);
+            match node.state {
+                NodeState::Finished(outcome) => Some(outcome),
+                _ => None,
+            }
+        };
+        self.nodes.get_mut(&op).unwrap().prerequisites.push(prerequisite);
+
+        // Account for the state of the prerequisite
+        match prerequisite_state {
+            Some(Outcome::Done) => None,
+            Some(outcome) => self.take_waiting(op).map(|op| {
+                Action::Fail(op, DependencyFailure { dependency: prerequisite },
+                             outcome)
+            }),
+            None => {
+                if let NodeState::Waiting { ref mut remaining, .. } =
+                    self.nodes.get_mut(&op).unwrap().state
+                {
+                    *remaining += 1;
+                }
+                None
+            }
+        }
+    }
+
+    /// Take a waiting operation out of the graph and schedule its launch if
+    /// all of its prerequisites are done
+    fn take_if_ready(&mut self, op: OpId) -> Option<Action> {
+        match self.nodes[&op].state {
+            NodeState::Waiting { remaining: 0, .. } =>
+                self.take_waiting(op).map(Action::Launch),
+            _ => None,
+        }
+    }
+
+    /// Take a waiting operation out of the graph, marking it as running
+    fn take_waiting(&mut self, op: OpId) -> Option<Box<dyn WaitingOp>> {
+        let node = self.nodes.get_mut(&op).unwrap();
+        match mem::replace(&mut node.state, NodeState::Running) {
+            NodeState::Waiting { op, .. } => Some(op),
+            other => {
+                node.state = other;
+                None
+            }
+        }
+    }
+
+    /// Record that an operation has reached a final status, and tell what
+    /// should happen to the operations which depend on it
+    ///
+    /// Failures are propagated through the whole graph right away, using a
+    /// worklist rather than recursion so that long dependency chains cannot
+    /// overflow the stack. Operations which are failed this way are marked as
+    /// finished immediately, so their own completion is ignored later on.
+    ///
+    fn finish(&mut self, id: OpId, outcome: Outcome) -> Vec<Action> {
+        match self.nodes.get(&id) {
+            Some(&Node { state: NodeState::Finished(_), .. }) | None =>
+                return Vec::new(),
+            Some(_) => {},
+        }
+        let mut actions = Vec::new();
+        let mut worklist = vec![(id, outcome)];
+        while let Some((id, outcome)) = worklist.pop() {
+            let dependents = {
+                let node = self.nodes.get_mut(&id).unwrap();
+                node.state = NodeState::Finished(outcome);
+                node.dependents.clone()
+            };
+            for dependent in dependents {
+                if outcome == Outcome::Done {
+                    if let NodeState::Waiting { ref mut remaining, .. } =
+                        self.nodes.get_mut(&dependent).unwrap().state
+                    {
+                        *remaining -= 1;
+                    }
+                    actions.extend(self.take_if_ready(dependent));
+                } else if let Some(op) = self.take_waiting(dependent) {
+                    actions.push(
+                        Action::Fail(op, DependencyFailure { dependency: id },
+                                     outcome)
+                    );
+                    worklist.push((dependent, outcome));
+                }
+            }
+        }
+        actions
+    }
+
+    /// Look for a chain of prerequisites leading from one operation to another
+    fn find_path(&self, from: OpId, to: OpId) -> Option<Vec<OpId>> {
+        let mut parents = HashMap::new();
+        let mut stack = vec![from];
+        parents.insert(from, from);
+        while let Some(id) = stack.pop() {
+            if id == to {
+                let mut path = vec![id];
+                let mut current = id;
+                while current != from {
+                    current = parents[&current];
+                    path.push(current);
+                }
+                path.reverse();
+                return Some(path);
+            }
+            for &prerequisite in &self.nodes[&id].prerequisites {
+                if let Entry::Vacant(entry) = parents.entry(prerequisite) {
+                    entry.insert(id);
+                    stack.push(prerequisite);
+                }
+            }
+        }
+        None
+    }
+}
+
+
+/// Operation of the dependency graph
+struct Node {
+    /// Human-readable label, if any
+    label: Option<String>,
+
+    /// Operations which this operation depends on
+    prerequisites: Vec<OpId>,
+
+    /// Operations which depend on this operation
+    dependents: Vec<OpId>,
+
+    /// Current state of the operation
+    state: NodeState,
+}
+
+
+/// State of an operation in the dependency graph
+enum NodeState {
+    /// The operation is waiting for some prerequisites to be done
+    Waiting {
+        /// Number of prerequisites which are not done yet
+        remaining: usize,
+
+        /// Operation to be launched once all prerequisites are done
+        op: Box<dyn WaitingOp>,
+    },
+
+    /// The operation was launched, or is about to fail
+    Running,
+
+    /// The operation has reached a final status
+    Finished(Outcome),
+}
+
+
+/// Final outcome of an operation
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Outcome {
+    /// The operation was successfully processed
+    Done,
+
+    /// The operation was cancelled
+    Cancelled,
+
+    /// The operation failed
+    Error,
+}
+
+
+/// Something which must happen to a waiting operation, outside of the lock
+enum Action {
+    /// All prerequisites are done, the operation can be launched
+    Launch(Box<dyn WaitingOp>),
+
+    /// A prerequisite failed, and the operation must fail in the same way
+    Fail(Box<dyn WaitingOp>, DependencyFailure, Outcome),
+}
+//
+impl Action {
+    /// Carry out the action
+    fn run(self) {
+        match self {
+            Action::Launch(op) => op.launch(),
+            Action::Fail(op, failure, outcome) => op.fail(failure, outcome),
+        }
+    }
+}
+
+
+/// Type-erased interface to operations waiting for their prerequisites
+trait WaitingOp: Send {
+    /// Hand the operation over to its launch function
+    fn launch(self: Box<Self>);
+
+    /// Fail or cancel the operation due to a failed prerequisite
+    fn fail(self: Box<Self>, failure: DependencyFailure, outcome: Outcome);
+}
+//
+struct WaitingOpImpl<Config: AsyncOpServerConfig, F> {
+    /// Server of the waiting operation
+    server: AsyncOpServer<TrackedServerConfig<Config>>,
+
+    /// Function used to start the operation
+    launch: F,
+}
+//
+impl<Config, F> WaitingOp for WaitingOpImpl<Config, F>
+    where Config: AsyncOpServerConfig + Send,
+          <Config::StatusDetails as AsyncOpStatusDetails>::CancelledDetails:
+              From<DependencyFailure>,
+          <Config::StatusDetails as AsyncOpStatusDetails>::ErrorDetails:
+              From<DependencyFailure>,
+          F: FnOnce(AsyncOpServer<TrackedServerConfig<Config>>) + Send
+{
+    /// Hand the operation over to its launch function
+    fn launch(self: Box<Self>) {
+        let this = *self;
+        (this.launch)(this.server);
+    }
+
+    /// Fail or cancel the operation due to a failed prerequisite
+    fn fail(self: Box<Self>, failure: DependencyFailure, outcome: Outcome) {
+        let mut server = self.server;
+        server.update(match outcome {
+            Outcome::Cancelled => AsyncOpStatus::Cancelled(failure.into()),
+            Outcome::Error =>
+                AsyncOpStatus::Error(AsyncOpError::CustomError(failure.into())),
+            Outcome::Done => unreachable!(),
+        });
+    }
+}
+
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use dependency::*;
+    use multithread::polling::{AsyncOp, AsyncOpServer, AsyncOpClient,
+                               PollingServerConfig};
+    use status::{self, NO_DETAILS};
+    use std::sync::{Arc, Mutex};
+
+    /// Status details reporting dependency failures
+    #[derive(Clone, Debug, PartialEq)]
+    struct DepDetails {}
+    //
+    impl AsyncOpStatusDetails for DepDetails {
+        type PendingDetails = NoDetails;
+        type RunningDetails = NoDetails;
+        type DoneDetails = NoDetails;
+        type CancelledDetails = DependencyFailure;
+        type ErrorDetails = DependencyFailure;
+    }
+    //
+    impl AsyncOpStatusTraits for DepDetails {}
+
+    /// Server of a tracked test operation
+    type TrackedServer = ::server::AsyncOpServer<
+        TrackedServerConfig<PollingServerConfig<DepDetails>>
+    >;
+
+    /// Launched operation servers, in order of launch
+    type Launched = Arc<Mutex<Vec<TrackedServer>>>;
+
+    /// Create a new pending operation
+    fn new_op() -> (AsyncOpServer<DepDetails>, AsyncOpClient<DepDetails>) {
+        AsyncOp::new(AsyncOpStatus::Pending(NO_DETAILS)).split()
+    }
+
+    /// Submit a new operation to a graph, recording its launch
+    fn submit(graph: &DependencyGraph,
+              prerequisites: &[OpId],
+              launched: &Launched) -> (OpId, AsyncOpClient<DepDetails>) {
+        let (server, client) = new_op();
+        let launched = launched.clone();
+        let id = graph.submit(server, prerequisites, move |server| {
+            launched.lock().unwrap().push(server)
+        }).unwrap();
+        (id, client)
+    }
+
+    /// Check that operations are only launched once prerequisites are done
+    #[test]
+    fn wait_for_prerequisites() {
+        // Track two running operations, and make a third one depend on them
+        let graph = DependencyGraph::new();
+        let (a, mut server_a) = graph.track(new_op().0);
+        let (b, mut server_b) = graph.track(new_op().0);
+        let launched = Launched::default();
+        let (_, mut client_c) = submit(&graph, &[a, b], &launched);
+        assert_eq!(launched.lock().unwrap().len(), 0);
+        assert_eq!(*client_c.status(), AsyncOpStatus::Pending(NO_DETAILS));
+
+        // The dependent operation should only start once both are done
+        server_a.update(AsyncOpStatus::Done(NO_DETAILS));
+        assert_eq!(launched.lock().unwrap().len(), 0);
+        server_b.update(AsyncOpStatus::Done(NO_DETAILS));
+        assert_eq!(launched.lock().unwrap().len(), 1);
+
+        // Operations whose prerequisites are done should start immediately
+        let (_, _client_d) = submit(&graph, &[a, b], &launched);
+        assert_eq!(launched.lock().unwrap().len(), 2);
+    }
+
+    /// Check that failures propagate through the dependency graph
+    #[test]
+    fn failure_propagation() {
+        // Build a chain of three operations, plus an unrelated dependent
+        let graph = DependencyGraph::new();
+        let (a, mut server_a) = graph.track(new_op().0);
+        let (b, mut server_b) = graph.track(new_op().0);
+        let launched = Launched::default();
+        let (c, mut client_c) = submit(&graph, &[a, b], &launched);
+        let (_, mut client_d) = submit(&graph, &[c], &launched);
+        let (_, mut client_e) = submit(&graph, &[b], &launched);
+
+        // An error should fail the direct and indirect dependents
+        server_a.update(
+            AsyncOpStatus::Error(AsyncOpError::CustomError(
+                DependencyFailure { dependency: a }
+            ))
+        );
+        assert_eq!(
+            *client_c.status(),
+            AsyncOpStatus::Error(AsyncOpError::CustomError(
+                DependencyFailure { dependency: a }
+            ))
+        );
+        assert_eq!(
+            *client_d.status(),
+            AsyncOpStatus::Error(AsyncOpError::CustomError(
+                DependencyFailure { dependency: c }
+            ))
+        );
+
+        // A cancellation should cancel the dependents
+        server_b.update(AsyncOpStatus::Cancelled(
+            DependencyFailure { dependency: b }
+        ));
+        assert_eq!(
+            *client_e.status(),
+            AsyncOpStatus::Cancelled(DependencyFailure { dependency: b })
+        );
+        assert_eq!(launched.lock().unwrap().len(), 0);
+
+        // A killed server is a failure too, even for late dependents
+        let (f, server_f) = graph.track(new_op().0);
+        ::std::mem::drop(server_f);
+        let (_, mut client_g) = submit(&graph, &[f], &launched);
+        assert_eq!(
+            *client_g.status(),
+            AsyncOpStatus::Error(AsyncOpError::CustomError(
+                DependencyFailure { dependency: f }
+            ))
+        );
+    }
+
+    /// Check that failures propagate through long dependency chains
+    #[test]
+    fn long_chain() {
+        // Build a chain which is far too long for recursive propagation
+        let graph = DependencyGraph::new();
+        let (root, mut root_server) = graph.track(new_op().0);
+        let launched = Launched::default();
+        let mut last = root;
+        let mut clients = Vec::new();
+        for _ in 0..100_000 {
+            let (id, client) = submit(&graph, &[last], &launched);
+            clients.push((last, client));
+            last = id;
+        }
+
+        // Cancelling the root should cancel every operation of the chain
+        root_server.update(AsyncOpStatus::Cancelled(
+            DependencyFailure { dependency: root }
+        ));
+        for (prerequisite, mut client) in clients {
+            assert_eq!(
+                *client.status(),
+                AsyncOpStatus::Cancelled(
+                    DependencyFailure { dependency: prerequisite }
+                )
+            );
+        }
+        assert_eq!(launched.lock().unwrap().len(), 0);
+    }
+
+    /// Check that operations with unknown prerequisites are handed back
+    #[test]
+    fn unknown_prerequisite() {
+        let graph = DependencyGraph::new();
+        let (a, _server_a) = DependencyGraph::new().track(new_op().0);
+        let (server, mut client) = new_op();
+        let error = graph.submit(server, &[a], |_| unreachable!())
+                         .unwrap_err();
+        assert_eq!(error.error, DependencyError::UnknownOp(a));
+
+        // The server should still be usable
+        let mut server = error.server;
+        server.update(AsyncOpStatus::Running(NO_DETAILS));
+        assert_eq!(*client.status(), AsyncOpStatus::Running(NO_DETAILS));
+        assert_eq!(graph.to_dot(), "digraph dependencies {\n}\n");
+    }
+
+    /// Check that dependency cycles are detected and rejected
+    #[test]
+    fn cycle_detection() {
+        // Build a chain of waiting operations: a <- b <- c
+        let graph = DependencyGraph::new();
+        let (root, _root_server) = graph.track(new_op().0);
+        let launched = Launched::default();
+        let (a, _client_a) = submit(&graph, &[root], &launched);
+        let (b, _client_b) = submit(&graph, &[a], &launched);
+        let (c, _client_c) = submit(&graph, &[b], &launched);
+
+        // Closing the loop should be refused
+        assert_eq!(graph.add_dependency(a, c),
+                   Err(DependencyError::Cycle(vec![a, c, b, a])));
+        assert_eq!(graph.add_dependency(a, a),
+                   Err(DependencyError::Cycle(vec![a, a])));
+
+        // Other dependencies should be accepted
+        assert_eq!(graph.add_dependency(c, a), Ok(()));
+
+        // Started operations cannot gain new prerequisites
+        assert_eq!(graph.add_dependency(root, c),
+                   Err(DependencyError::AlreadyStarted(root)));
+    }
+
+    /// Check that the graph can be exported in DOT format
+    #[test]
+    fn dot_export() {
+        let graph = DependencyGraph::new();
+        let (a, mut server_a) = graph.track(new_op().0);
+        let launched = Launched::default();
+        let (b, _client_b) = submit(&graph, &[a], &launched);
+        graph.set_label(b, "say \"hi\"").unwrap();
+        server_a.update(status::AsyncOpStatus::Running(NO_DETAILS));
+        assert_eq!(graph.to_dot(),
+                   "digraph dependencies {\n\
+                   \x20   op0 [label=\"#0 (running)\"];\n\
+                   \x20   op1 [label=\"#1 say \\\"hi\\\" (waiting)\"];\n\
+                   \x20   op0 -> op1;\n\
+                   }\n");
+
+        // Once everything is finished, the graph can be cleared
+        server_a.update(status::AsyncOpStatus::Done(NO_DETAILS));
+        launched.lock().unwrap()[0].update(AsyncOpStatus::Done(NO_DETAILS));
+        graph.clear_finished();
+        assert_eq!(graph.to_dot(), "digraph dependencies {\n}\n");
+    }
+}
@@ -6,12 +6,14 @@
 //! boundary, but in local communication perimeters like coroutines and threads
 //! it can be a good choice for short performance-critical callbacks
 
-use executor::{CallbackExecutor, CallbackChannel, AnyCallbackChannel};
+use executor::{CallbackExecutor, CallbackChannel, AnyCallbackChannel,
+               SendCallbackExecutor};
 use status::{AsyncOpStatus, AsyncOpStatusDetails};
 use std::any::Any;
 
 
 /// CallbackExecutor implementation suitable for inline callback execution
+#[derive(Default)]
 pub struct InlineCallbackExecutor {}
 //
 impl InlineCallbackExecutor {
@@ -39,12 +41,32 @@ impl CallbackExecutor for InlineCallbackExecutor {
         }
     }
 }
+//
+impl SendCallbackExecutor for InlineCallbackExecutor {
+    /// Notification channel which can be sent to other threads
+    type SendChannel = AnySendInlineCallbackChannel;
+
+    /// Setup a notification channel which can be sent to other threads
+    fn setup_send_callback<F, Details>(&mut self,
+                                       callback: F) -> Self::SendChannel
+        where F: Fn(AsyncOpStatus<Details>) + Send + 'static,
+              Details: AsyncOpStatusDetails + 'static
+    {
+        AnySendInlineCallbackChannel {
+            holder: Box::new(
+                SendInlineCallbackChannel {
+                    callback: Box::new(callback)
+                }
+            )
+        }
+    }
+}
 
 
 /// Callback channel which invokes an internal callback whenever a new operation
 /// status is pushed into it
 pub struct InlineCallbackChannel<'a, Details: AsyncOpStatusDetails> {
-    callback: Box<Fn(AsyncOpStatus<Details>) + 'a>,
+    callback: Box<dyn Fn(AsyncOpStatus<Details>) + 'a>,
 }
 //
 impl<'a, Details: AsyncOpStatusDetails> CallbackChannel<'a, Details>
@@ -59,7 +81,7 @@ impl<'a, Details: AsyncOpStatusDetails> CallbackChannel<'a, Details>
 
 /// AnyCallbackChannel implementation corresponding to InlineCallbackChannel
 pub struct AnyInlineCallbackChannel {
-    holder: Box<Any>,
+    holder: Box<dyn Any>,
 }
 //
 impl AnyCallbackChannel for AnyInlineCallbackChannel {
@@ -75,7 +97,7 @@ impl AnyCallbackChannel for AnyInlineCallbackChannel {
     fn notify<Details>(&mut self, new_status: AsyncOpStatus<Details>)
         where Details: AsyncOpStatusDetails + 'static
     {
-        let mut channel = self.holder
+        let channel = self.holder
                               .downcast_mut::<InlineCallbackChannel<Details>>()
                               .unwrap();
         channel.notify(new_status);
@@ -83,6 +105,47 @@ impl AnyCallbackChannel for AnyInlineCallbackChannel {
 }
 
 
+/// Variant of InlineCallbackChannel whose callback can be sent to other threads
+pub struct SendInlineCallbackChannel<Details: AsyncOpStatusDetails> {
+    callback: Box<dyn Fn(AsyncOpStatus<Details>) + Send>,
+}
+//
+impl<Details: AsyncOpStatusDetails> CallbackChannel<'static, Details>
+    for SendInlineCallbackChannel<Details>
+{
+    /// Notify the client that an operation status update has occured
+    fn notify(&mut self, new_status: AsyncOpStatus<Details>) {
+        (self.callback)(new_status);
+    }
+}
+
+
+/// AnyCallbackChannel implementation corresponding to SendInlineCallbackChannel
+pub struct AnySendInlineCallbackChannel {
+    holder: Box<dyn Any + Send>,
+}
+//
+impl AnyCallbackChannel for AnySendInlineCallbackChannel {
+    /// Check if the channel was configured for the right operation status type
+    fn is_compatible<Details>(&self) -> bool
+        where Details: AsyncOpStatusDetails + 'static
+    {
+        self.holder.is::<SendInlineCallbackChannel<Details>>()
+    }
+
+    /// Attempt to notify the client about a status update, will panic if
+    /// incorrect status details are specified.
+    fn notify<Details>(&mut self, new_status: AsyncOpStatus<Details>)
+        where Details: AsyncOpStatusDetails + 'static
+    {
+        let channel = self.holder
+                          .downcast_mut::<SendInlineCallbackChannel<Details>>()
+                          .unwrap();
+        channel.notify(new_status);
+    }
+}
+
+
 /// Unit tests
 #[cfg(test)]
 mod tests {
@@ -90,6 +153,8 @@ mod tests {
     use status::{self, NoDetails, StandardAsyncOpStatus};
     use std::cell::Cell;
     use std::rc::Rc;
+    use std::sync::mpsc;
+    use std::thread;
 
     // Make sure that executor creation works well
     #[test]
@@ -136,6 +201,25 @@ mod tests {
         channel.notify(status::DONE);
         assert_eq!(counter.get(), 1);
     }
+
+    // Make sure that sendable callback channels work from other threads
+    #[test]
+    fn send_update() {
+        // This callback will forward status updates to the test thread
+        let (sender, receiver) = mpsc::channel();
+        let callback =
+            move | s: StandardAsyncOpStatus | sender.send(s).unwrap();
+
+        // Setup a sendable callback channel for it
+        let mut executor = InlineCallbackExecutor::new();
+        let mut channel = executor.setup_send_callback(callback);
+        assert!(channel.is_compatible::<NoDetails>());
+
+        // Check that the callback gets called from another thread
+        thread::spawn(move || channel.notify(status::DONE)).join().unwrap();
+        assert_eq!(receiver.recv(), Ok(status::DONE));
+        assert!(receiver.recv().is_err());
+    }
 }
 
 
@@ -45,6 +45,25 @@ pub trait CallbackExecutor {
 }
 
 
+/// Callback executor whose notification channels can also be sent to other
+/// threads, provided that the callbacks themselves can be
+///
+/// Such channels are needed whenever status updates may be sent from some
+/// thread other than the one which owns the operation server, for example by
+/// the timer thread which enforces operation deadlines.
+///
+pub trait SendCallbackExecutor: CallbackExecutor {
+    /// Notification channel which can be sent to other threads
+    type SendChannel: AnyCallbackChannel + Send;
+
+    /// Setup a notification channel which can be sent to other threads
+    fn setup_send_callback<F, Details>(&mut self,
+                                       callback: F) -> Self::SendChannel
+        where F: Fn(AsyncOpStatus<Details>) + Send + 'static,
+              Details: AsyncOpStatusDetails + 'static;
+}
+
+
 /// Client-side entry point used to have callacks called on status updates. Can
 /// be extended to the server side through simple message-passing techniques.
 pub trait CallbackChannel<'a, Details: AsyncOpStatusDetails> {
@@ -0,0 +1,741 @@
+//! Hierarchical operations, made of child operations
+//!
+//! Many jobs are naturally organized as trees: a build is made of compilation
+//! steps, each of which reads a number of files... This module allows such a
+//! structure to be expressed by turning an operation into a parent, to which
+//! child operations can be attached, recursively.
+//!
+//! A parent keeps track of the status of its children. Whenever it changes,
+//! the parent's client receives a running status whose details are built from
+//! a summary of the children's states and progress. This summary only holds
+//! aggregate counts, so that it is cheap to send no matter how many children
+//! there are, but the parent's server can list its children individually.
+//! Children, in turn, are
+//! asked to stop whenever their parent is cancelled or over, so cancelling an
+//! operation cancels all of its descendants. If a child's server is killed,
+//! its parent fails with an error identifying that child.
+
+use cancellation::CancellationToken;
+use channels::OpChannels;
+use instrument::Instrumentation;
+use server::{AsyncOpServer, AsyncOpServerConfig};
+use status::{self, AsyncOpError, AsyncOpState, AsyncOpStatus,
+             AsyncOpStatusDetails, AsyncOpStatusTraits, NoDetails};
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+
+/// Identifier of a child operation, among the children of its parent
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ChildId(usize);
+//
+impl fmt::Display for ChildId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+
+/// Running status details which can tell the progress of an operation
+///
+/// Children must implement this on their running status details, so that
+/// their parent can report their progress.
+///
+pub trait ReportProgress {
+    /// Fraction of the work which has been done, between 0 and 1, if known
+    fn progress(&self) -> Option<f32> {
+        None
+    }
+}
+//
+impl ReportProgress for NoDetails {}
+
+
+/// Simplified status of a child operation
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChildState {
+    /// The child is pending
+    Pending,
+
+    /// The child is running
+    Running,
+
+    /// The child is done
+    Done,
+
+    /// The child was cancelled
+    Cancelled,
+
+    /// The child has failed, including if its server was killed
+    Failed,
+}
+
+
+/// Summary of the status of one child operation
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChildSummary {
+    /// Identifier of the child
+    pub id: ChildId,
+
+    /// Human-readable description of the child
+    pub label: String,
+
+    /// Simplified status of the child
+    pub state: ChildState,
+
+    /// Progress of the child, if it is running and reports it
+    pub progress: Option<f32>,
+}
+
+
+/// Summary of the status of the children of an operation
+///
+/// Parents report this information through their running status details, which
+/// must therefore be constructible from it.
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChildrenSummary {
+    /// Number of pending children
+    pub pending: usize,
+
+    /// Number of running children
+    pub running: usize,
+
+    /// Number of children which are done
+    pub done: usize,
+
+    /// Number of children which were cancelled
+    pub cancelled: usize,
+
+    /// Number of children which have failed
+    pub failed: usize,
+
+    /// Sum of the progress of the running children which report it
+    pub running_progress: f32,
+}
+//
+impl ChildrenSummary {
+    /// Total number of children
+    pub fn total(&self) -> usize {
+        self.pending + self.running + self.done + self.cancelled + self.failed
+    }
+
+    /// Number of children which are in a certain state
+    pub fn count(&self, state: ChildState) -> usize {
+        match state {
+            ChildState::Pending => self.pending,
+            ChildState::Running => self.running,
+            ChildState::Done => self.done,
+            ChildState::Cancelled => self.cancelled,
+            ChildState::Failed => self.failed,
+        }
+    }
+
+    /// Overall progress of the children, between 0 and 1
+    ///
+    /// Children which reached a final state count as fully done, pending ones
+    /// and running ones which don't report their progress count as not
+    /// started. An operation without children has no work left to do.
+    ///
+    pub fn progress(&self) -> f32 {
+        let total = self.total();
+        if total == 0 { return 1.0; }
+        let finished = self.done + self.cancelled + self.failed;
+        (finished as f32 + self.running_progress) / total as f32
+    }
+
+    /// Account for a child entering a state, with some progress
+    fn add(&mut self, state: ChildState, progress: Option<f32>) {
+        *self.count_mut(state) += 1;
+        if state == ChildState::Running {
+            self.running_progress += progress.unwrap_or(0.0);
+        }
+    }
+
+    /// Account for a child leaving a state, which it had with some progress
+    fn remove(&mut self, state: ChildState, progress: Option<f32>) {
+        *self.count_mut(state) -= 1;
+        if state == ChildState::Running {
+            // Start over from an exact zero when no child is running, so that
+            // rounding errors do not pile up
+            self.running_progress = if self.running == 0 {
+                0.0
+            } else {
+                self.running_progress - progress.unwrap_or(0.0)
+            };
+        }
+    }
+
+    /// Access the number of children which are in a certain state
+    fn count_mut(&mut self, state: ChildState) -> &mut usize {
+        match state {
+            ChildState::Pending => &mut self.pending,
+            ChildState::Running => &mut self.running,
+            ChildState::Done => &mut self.done,
+            ChildState::Cancelled => &mut self.cancelled,
+            ChildState::Failed => &mut self.failed,
+        }
+    }
+}
+//
+impl AsyncOpStatusTraits for ChildrenSummary {}
+//
+impl From<ChildrenSummary> for NoDetails {
+    /// Standard statuses simply discard information about children
+    fn from(_: ChildrenSummary) -> Self {
+        status::NO_DETAILS
+    }
+}
+
+
+/// Error reported by a parent operation when the server of a child is killed
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChildKilled {
+    /// Identifier of the child
+    pub child: ChildId,
+
+    /// Human-readable description of the child
+    pub label: String,
+}
+//
+impl fmt::Display for ChildKilled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Server of child {} ({}) was killed", self.child, self.label)
+    }
+}
+//
+impl Error for ChildKilled {}
+//
+impl AsyncOpStatusTraits for ChildKilled {}
+//
+impl From<ChildKilled> for NoDetails {
+    /// Standard statuses simply discard information about killed children
+    fn from(_: ChildKilled) -> Self {
+        status::NO_DETAILS
+    }
+}
+
+
+/// Turn an operation into a parent, to which children can be attached
+pub fn parent<Config>(
+    server: AsyncOpServer<Config>
+) -> AsyncOpServer<ParentServerConfig<Config>>
+    where Config: AsyncOpServerConfig + Send + 'static,
+          <Config::StatusDetails as AsyncOpStatusDetails>::RunningDetails:
+              From<ChildrenSummary>,
+          <Config::StatusDetails as AsyncOpStatusDetails>::ErrorDetails:
+              From<ChildKilled>
+{
+    let finished = server.is_final();
+    let instrumentation = server.instrumentation();
+    server.map_config(move |config| {
+        let channels =
+            config.channels().with_cancellation(config.cancellation().child());
+        let children_stop = channels.cancellation().child();
+        if finished {
+            children_stop.cancel();
+        }
+        ParentServerConfig {
+            family: Arc::new(Mutex::new(Family {
+                inner: config.into_inner(),
+                finished,
+                instrumentation,
+                cancellation: channels.cancellation().clone(),
+                children_stop,
+                children: Vec::new(),
+                summary: ChildrenSummary::default(),
+            })),
+            channels,
+        }
+    })
+}
+
+
+/// Server configuration wrapper for operations which have children
+pub struct ParentServerConfig<Config: AsyncOpServerConfig> {
+    /// State shared with the children
+    family: Arc<Mutex<Family<Config>>>,
+
+    /// Side channels of the wrapped configuration, which is behind the mutex,
+    /// with a token which is cancelled by the client or when a child is killed
+    channels: OpChannels,
+}
+//
+impl<Config: AsyncOpServerConfig> ParentServerConfig<Config> {
+    /// Summarize the status of the children
+    pub fn summary(&self) -> ChildrenSummary {
+        self.family.lock().unwrap().summary
+    }
+
+    /// Describe the status of each child, in the order in which they were
+    /// added
+    pub fn children(&self) -> Vec<ChildSummary> {
+        self.family.lock().unwrap().children.clone()
+    }
+}
+//
+impl<Config: AsyncOpServerConfig> AsyncOpServerConfig
+    for ParentServerConfig<Config>
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Config::StatusDetails;
+
+    /// Forward status updates to the client, unless a child was killed
+    fn update(&mut self, status: AsyncOpStatus<Self::StatusDetails>) {
+        let mut family = self.family.lock().unwrap();
+        if family.finished { return; }
+        family.finished = status::is_final(&status);
+        family.inner.update(status);
+
+        // Children are not needed anymore once the parent is over
+        if family.finished {
+            let children_stop = family.children_stop.clone();
+            ::std::mem::drop(family);
+            children_stop.cancel();
+        }
+    }
+
+    /// Side channels shared with the client, whose token is also cancelled
+    /// when one of the operation's ancestors is cancelled, or when the
+    /// operation fails due to a killed child
+    fn channels(&self) -> &OpChannels {
+        &self.channels
+    }
+}
+//
+impl<Config> AsyncOpServer<ParentServerConfig<Config>>
+    where Config: AsyncOpServerConfig + Send + 'static,
+          <Config::StatusDetails as AsyncOpStatusDetails>::RunningDetails:
+              From<ChildrenSummary>,
+          <Config::StatusDetails as AsyncOpStatusDetails>::ErrorDetails:
+              From<ChildKilled>
+{
+    /// Attach a child operation, given its server, with a human-readable label
+    pub fn add_child<ChildConfig>(
+        &mut self,
+        label: &str,
+        child: AsyncOpServer<ChildConfig>
+    ) -> AsyncOpServer<ChildServerConfig<ChildConfig>>
+        where ChildConfig: AsyncOpServerConfig,
+              <ChildConfig::StatusDetails as AsyncOpStatusDetails>
+                  ::RunningDetails: ReportProgress
+    {
+        // Register the child, starting from its current state...
+        let state = match child.state() {
+            AsyncOpState::Pending => ChildState::Pending,
+            AsyncOpState::Running => ChildState::Running,
+            AsyncOpState::Done => ChildState::Done,
+            AsyncOpState::Cancelled => ChildState::Cancelled,
+            AsyncOpState::Error => ChildState::Failed,
+        };
+        let family = self.config().family.clone();
+        let (id, children_stop) = {
+            let mut family = family.lock().unwrap();
+            let id = ChildId(family.children.len());
+            family.children.push(ChildSummary {
+                id,
+                label: label.to_owned(),
+                state,
+                progress: None,
+            });
+            family.summary.add(state, None);
+            (id, family.children_stop.clone())
+        };
+
+        // ...make it stop when we do...
+        let cancellation = child.cancellation().child();
+        let child_cancellation = cancellation.clone();
+        children_stop.on_cancel(move || child_cancellation.cancel());
+
+        // ...and make it report to us
+        child.map_config(move |config| {
+            let channels = config.channels().with_cancellation(cancellation);
+            ChildServerConfig {
+                inner: config.into_inner(),
+                parent: family,
+                id,
+                channels,
+            }
+        })
+    }
+
+    /// Summarize the status of the children
+    pub fn summary(&self) -> ChildrenSummary {
+        self.config().summary()
+    }
+
+    /// Describe the status of each child, in the order in which they were
+    /// added
+    pub fn children(&self) -> Vec<ChildSummary> {
+        self.config().children()
+    }
+}
+
+
+/// Server configuration wrapper for operations which have a parent
+pub struct ChildServerConfig<Config: AsyncOpServerConfig> {
+    /// Server configuration which we are wrapping
+    inner: Config,
+
+    /// Parent operation
+    parent: Arc<dyn ParentNode>,
+
+    /// Identifier of this child among the parent's children
+    id: ChildId,
+
+    /// Side channels of the wrapped configuration, with a token which is
+    /// cancelled by the client, or when the parent stops
+    channels: OpChannels,
+}
+//
+impl<Config> AsyncOpServerConfig for ChildServerConfig<Config>
+    where Config: AsyncOpServerConfig,
+          <Config::StatusDetails as AsyncOpStatusDetails>::RunningDetails:
+              ReportProgress
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Config::StatusDetails;
+
+    /// Forward status updates to the client, and report them to the parent
+    fn update(&mut self, status: AsyncOpStatus<Self::StatusDetails>) {
+        let (state, progress) = match status {
+            AsyncOpStatus::Pending(_) => (ChildState::Pending, None),
+            AsyncOpStatus::Running(ref details) =>
+                (ChildState::Running, details.progress()),
+            AsyncOpStatus::Done(_) => (ChildState::Done, None),
+            AsyncOpStatus::Cancelled(_) => (ChildState::Cancelled, None),
+            AsyncOpStatus::Error(_) => (ChildState::Failed, None),
+        };
+        let killed = matches!(status,
+                              AsyncOpStatus::Error(AsyncOpError::ServerKilled));
+        self.inner.update(status);
+        self.parent.child_update(self.id, state, progress, killed);
+    }
+
+    /// Side channels shared with the client, whose token is also cancelled
+    /// when the parent is cancelled or over
+    fn channels(&self) -> &OpChannels {
+        &self.channels
+    }
+}
+
+
+/// Type-erased interface of parent operations, as seen by their children
+trait ParentNode: Send + Sync {
+    /// Record a status update from a child
+    fn child_update(&self,
+                    id: ChildId,
+                    state: ChildState,
+                    progress: Option<f32>,
+                    killed: bool);
+}
+
+
+/// State shared by a parent operation and its children
+struct Family<Config: AsyncOpServerConfig> {
+    /// Server configuration of the parent
+    inner: Config,
+
+    /// Whether the parent's client has been notified of a final status
+    finished: bool,
+
+    /// Instrumentation of the parent, which must hear about the status
+    /// updates that are sent on behalf of the children
+    instrumentation: Instrumentation,
+
+    /// Token which is cancelled when the parent should stop working
+    cancellation: CancellationToken,
+
+    /// Token which is cancelled when the children should stop working
+    children_stop: CancellationToken,
+
+    /// Status of each child
+    children: Vec<ChildSummary>,
+
+    /// Aggregate status of the children
+    summary: ChildrenSummary,
+}
+//
+impl<Config> ParentNode for Mutex<Family<Config>>
+    where Config: AsyncOpServerConfig + Send,
+          <Config::StatusDetails as AsyncOpStatusDetails>::RunningDetails:
+              From<ChildrenSummary>,
+          <Config::StatusDetails as AsyncOpStatusDetails>::ErrorDetails:
+              From<ChildKilled>
+{
+    /// Record a status update from a child, and tell the parent's client
+    fn child_update(&self,
+                    id: ChildId,
+                    state: ChildState,
+                    progress: Option<f32>,
+                    killed: bool) {
+        let mut family = self.lock().unwrap();
+        let (old_state, old_progress) = {
+            let child = &mut family.children[id.0];
+            let old = (child.state, child.progress);
+            child.state = state;
+            child.progress = progress;
+            old
+        };
+        family.summary.remove(old_state, old_progress);
+        family.summary.add(state, progress);
+        if family.finished { return; }
+
+        // A killed child takes its parent down with it...
+        if killed {
+            let error = ChildKilled {
+                child: id,
+                label: family.children[id.0].label.clone(),
+            };
+            family.finished = true;
+            let status =
+                AsyncOpStatus::Error(AsyncOpError::CustomError(error.into()));
+            family.instrumentation.record(&status);
+            family.inner.update(status);
+            let cancellation = family.cancellation.clone();
+            ::std::mem::drop(family);
+            cancellation.cancel();
+            return;
+        }
+
+        // ...otherwise, the parent's client is told about the children
+        let status = AsyncOpStatus::Running(family.summary.into());
+        family.instrumentation.record(&status);
+        family.inner.update(status);
+    }
+}
+
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use client::IAsyncOpClient;
+    use executor::inline::InlineCallbackExecutor;
+    use hierarchy::*;
+    use multithread::{blocking, callback, polling};
+    use status::NO_DETAILS;
+
+    /// Running details of test parents, which keep track of their children
+    #[derive(Clone, Debug, PartialEq)]
+    enum TreeRunning {
+        Own(u32),
+        Children(ChildrenSummary),
+    }
+    //
+    impl AsyncOpStatusTraits for TreeRunning {}
+    //
+    impl From<ChildrenSummary> for TreeRunning {
+        fn from(summary: ChildrenSummary) -> Self {
+            TreeRunning::Children(summary)
+        }
+    }
+    //
+    impl ReportProgress for TreeRunning {
+        fn progress(&self) -> Option<f32> {
+            match *self {
+                TreeRunning::Own(percent) => Some(percent as f32 / 100.0),
+                TreeRunning::Children(ref summary) => Some(summary.progress()),
+            }
+        }
+    }
+
+    /// Status details of test operations
+    #[derive(Clone, Debug, PartialEq)]
+    struct TreeDetails {}
+    //
+    impl AsyncOpStatusDetails for TreeDetails {
+        type PendingDetails = NoDetails;
+        type RunningDetails = TreeRunning;
+        type DoneDetails = NoDetails;
+        type CancelledDetails = NoDetails;
+        type ErrorDetails = TreeError;
+    }
+    //
+    impl AsyncOpStatusTraits for TreeDetails {}
+
+    /// Error details of test operations
+    #[derive(Clone, Debug, PartialEq)]
+    struct TreeError(Option<ChildKilled>);
+    //
+    impl fmt::Display for TreeError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{:?}", self.0)
+        }
+    }
+    //
+    impl Error for TreeError {}
+    //
+    impl AsyncOpStatusTraits for TreeError {}
+    //
+    impl From<ChildKilled> for TreeError {
+        fn from(killed: ChildKilled) -> Self {
+            TreeError(Some(killed))
+        }
+    }
+
+    /// Create a test operation with polling-based monitoring
+    fn new_op() -> polling::AsyncOp<TreeDetails> {
+        polling::AsyncOp::new(AsyncOpStatus::Pending(NO_DETAILS))
+    }
+
+    /// Check that parents report the status and progress of their children
+    #[test]
+    fn aggregation() {
+        let (server, mut client) = new_op().split();
+        let mut parent_server = parent(server);
+        let (child, _child_client) = new_op().split();
+        let mut child_a = parent_server.add_child("a", child);
+        let (child, _child_client) = new_op().split();
+        let mut child_b = parent_server.add_child("b", child);
+        assert_eq!(parent_server.summary().count(ChildState::Pending), 2);
+
+        // Child status updates should be reported to the parent's client
+        child_a.update(AsyncOpStatus::Running(TreeRunning::Own(50)));
+        let summary = match *client.status() {
+            AsyncOpStatus::Running(TreeRunning::Children(s)) => s,
+            ref other => panic!("Unexpected parent status {:?}", other),
+        };
+        assert_eq!(summary.total(), 2);
+        assert_eq!(summary.count(ChildState::Running), 1);
+        assert_eq!(summary.count(ChildState::Pending), 1);
+        assert_eq!(summary.progress(), 0.25);
+
+        // The parent's server should be able to tell the children apart
+        let children = parent_server.children();
+        assert_eq!(children[0].label, "a");
+        assert_eq!(children[0].state, ChildState::Running);
+        assert_eq!(children[0].progress, Some(0.5));
+        assert_eq!(children[1].state, ChildState::Pending);
+
+        // Completion of the children should be reported as well
+        child_a.update(AsyncOpStatus::Done(NO_DETAILS));
+        child_b.update(AsyncOpStatus::Done(NO_DETAILS));
+        assert_eq!(parent_server.summary().count(ChildState::Done), 2);
+        assert_eq!(parent_server.summary().progress(), 1.0);
+        parent_server.update(AsyncOpStatus::Done(NO_DETAILS));
+        assert_eq!(*client.status(), AsyncOpStatus::Done(NO_DETAILS));
+    }
+
+    /// Check that children are accounted for in the state they are in when
+    /// they are added
+    #[test]
+    fn late_children() {
+        let (server, _client) = new_op().split();
+        let mut parent_server = parent(server);
+        let (running, _running_client) =
+            polling::AsyncOp::<TreeDetails>::new(
+                AsyncOpStatus::Running(TreeRunning::Own(50))
+            ).split();
+        let mut running = parent_server.add_child("running", running);
+        let (done, _done_client) =
+            polling::AsyncOp::<TreeDetails>::new(
+                AsyncOpStatus::Done(NO_DETAILS)
+            ).split();
+        let _done = parent_server.add_child("done", done);
+
+        let summary = parent_server.summary();
+        assert_eq!(summary.total(), 2);
+        assert_eq!(summary.count(ChildState::Running), 1);
+        assert_eq!(summary.count(ChildState::Done), 1);
+        assert_eq!(summary.count(ChildState::Pending), 0);
+        let children = parent_server.children();
+        assert_eq!(children[0].state, ChildState::Running);
+        assert_eq!(children[1].state, ChildState::Done);
+
+        // The running child's progress is only known once it reports it
+        running.update(AsyncOpStatus::Running(TreeRunning::Own(50)));
+        assert_eq!(parent_server.summary().progress(), 0.75);
+        running.update(AsyncOpStatus::Done(NO_DETAILS));
+        assert_eq!(parent_server.summary().count(ChildState::Done), 2);
+        assert_eq!(parent_server.summary().progress(), 1.0);
+    }
+
+    /// Check that cancellation propagates to all descendants
+    #[test]
+    fn cancellation() {
+        // Build a three-level hierarchy
+        let (server, mut client) = new_op().split();
+        let mut root = parent(server);
+        let (child, _child_client) = new_op().split();
+        let mut child = parent(root.add_child("child", child));
+        let (grandchild, _grandchild_client) = new_op().split();
+        let grandchild = child.add_child("grandchild", grandchild);
+        let (sibling, mut sibling_client) = new_op().split();
+        let sibling = root.add_child("sibling", sibling);
+        assert!(!grandchild.cancelled());
+
+        // Cancelling a child should only affect its own descendants
+        sibling_client.cancel();
+        assert!(sibling.cancelled());
+        assert!(!child.cancelled() && !grandchild.cancelled());
+
+        // Cancelling the root should affect everyone
+        client.cancel();
+        assert!(child.cancelled() && grandchild.cancelled());
+    }
+
+    /// Check that cancelling a parent through any kind of client reaches all
+    /// of its descendants
+    #[test]
+    fn cancellation_any_client() {
+        /// Build a three-level hierarchy, and cancel it through its client
+        fn check<Config>(server: AsyncOpServer<Config>,
+                         mut client: Box<dyn IAsyncOpClient>)
+            where Config: AsyncOpServerConfig<StatusDetails = TreeDetails>
+                          + Send + 'static
+        {
+            let mut root = parent(server);
+            let (child, _child_client) = new_op().split();
+            let mut child = parent(root.add_child("child", child));
+            let (grandchild, _grandchild_client) = new_op().split();
+            let grandchild = child.add_child("grandchild", grandchild);
+            client.cancel();
+            assert!(root.cancelled());
+            assert!(child.cancelled() && grandchild.cancelled());
+        }
+
+        let (server, client) = new_op().split();
+        check(server, Box::new(client));
+
+        let initial_status = AsyncOpStatus::Pending(NO_DETAILS);
+        let (server, client) =
+            blocking::AsyncOp::new(initial_status.clone()).split();
+        check(server, Box::new(client));
+
+        let mut executor = InlineCallbackExecutor::new();
+        let (server, client) =
+            callback::new_send_async_op(|_: AsyncOpStatus<TreeDetails>| {},
+                                        &mut executor,
+                                        initial_status).split();
+        check(server, Box::new(client));
+    }
+
+    /// Check that killed children make their parent fail, identifying them
+    #[test]
+    fn killed_child() {
+        let (server, mut client) =
+            blocking::AsyncOp::<TreeDetails>::new(AsyncOpStatus::Pending(
+                NO_DETAILS
+            )).split();
+        let mut parent_server = parent(server);
+        let (child, _child_client) = new_op().split();
+        let child_a = parent_server.add_child("a", child);
+        let (child, _child_client) = new_op().split();
+        let child_b = parent_server.add_child("b", child);
+
+        // Killing a child should fail the parent, and stop other children
+        ::std::mem::drop(child_b);
+        let expected = AsyncOpStatus::Error(AsyncOpError::CustomError(
+            TreeError(Some(ChildKilled { child: ChildId(1),
+                                         label: "b".to_owned() }))
+        ));
+        assert_eq!(client.status(), expected);
+        assert!(parent_server.cancelled());
+        assert!(child_a.cancelled());
+
+        // The parent's own updates should be ignored from then on
+        parent_server.update(AsyncOpStatus::Done(NO_DETAILS));
+        assert_eq!(client.status(), expected);
+    }
+}
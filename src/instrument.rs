@@ -0,0 +1,329 @@
+//! Reporting of operation lifecycles to the log and tracing ecosystems
+//!
+//! When the "log" feature is enabled, every status update which a server sends
+//! is logged, along with an identifier which tells operations apart.
+//!
+//! When the "tracing" feature is enabled, each operation gets a span, which
+//! is opened when its server is created and therefore inherits the context of
+//! the code which started the operation. Status updates are recorded as
+//! events within that span, and the span is closed once the operation reaches
+//! its final status or its server is dropped. The span travels with the
+//! server to whichever thread processes the operation, along with the tracing
+//! subscriber which was in use when the operation started, so that status
+//! updates reach it even if it is not the server thread's default subscriber.
+//! Server code can also enter the span so that its own events are attributed
+//! to the operation.
+//!
+//! Server configuration wrappers which send a final status on their own, like
+//! timeouts, report it through a shared handle to the server's instrumentation.
+//! Since the client ignores whatever the server sends afterwards, so does the
+//! instrumentation.
+//!
+//! Without either feature, this instrumentation compiles down to nothing.
+
+use status::{AsyncOpStatus, AsyncOpStatusDetails};
+#[cfg(any(feature = "log", feature = "tracing"))]
+use status;
+#[cfg(any(feature = "log", feature = "tracing"))]
+use std::sync::{Arc, Mutex};
+#[cfg(any(feature = "log", feature = "tracing"))]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+
+/// Instrumentation of an asynchronous operation, shared by its server and the
+/// configuration wrappers which may send status updates on their own
+///
+/// This object can be cheaply cloned, and all clones refer to the same
+/// instrumentation.
+///
+#[derive(Clone)]
+pub struct Instrumentation {
+    /// State shared between all clones of the instrumentation
+    #[cfg(any(feature = "log", feature = "tracing"))]
+    shared: Arc<Mutex<InstrumentationState>>,
+}
+//
+impl Instrumentation {
+    /// Start instrumenting an operation, given its initial status
+    pub fn new<Details: AsyncOpStatusDetails>(
+        initial_status: &AsyncOpStatus<Details>
+    ) -> Self {
+        #[cfg(any(feature = "log", feature = "tracing"))]
+        let id = {
+            static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        };
+        let result = Instrumentation {
+            #[cfg(any(feature = "log", feature = "tracing"))]
+            shared: Arc::new(Mutex::new(InstrumentationState {
+                #[cfg(feature = "log")]
+                id,
+                #[cfg(feature = "tracing")]
+                span: ::tracing::info_span!("async_op", id),
+                #[cfg(feature = "tracing")]
+                dispatch: ::tracing::dispatcher::get_default(|d| d.clone()),
+                over: false,
+            })),
+        };
+        result.record(initial_status);
+        result
+    }
+
+    /// Record a status update of the operation, unless it is already over
+    #[cfg_attr(not(any(feature = "log", feature = "tracing")),
+               allow(unused_variables))]
+    pub fn record<Details: AsyncOpStatusDetails>(
+        &self,
+        status: &AsyncOpStatus<Details>
+    ) {
+        #[cfg(any(feature = "log", feature = "tracing"))]
+        self.shared.lock().unwrap().record(status);
+    }
+
+    /// Span of the operation, which is disabled once the operation is over
+    #[cfg(feature = "tracing")]
+    pub fn span(&self) -> ::tracing::Span {
+        self.shared.lock().unwrap().span.clone()
+    }
+}
+
+
+/// State of an operation's instrumentation
+#[cfg(any(feature = "log", feature = "tracing"))]
+struct InstrumentationState {
+    /// Identifier which tells operations apart in logs
+    #[cfg(feature = "log")]
+    id: u64,
+
+    /// Span of the operation, which is disabled once the operation is over
+    #[cfg(feature = "tracing")]
+    span: ::tracing::Span,
+
+    /// Tracing subscriber which was in use when the operation started, which
+    /// is released once the operation is over
+    #[cfg(feature = "tracing")]
+    dispatch: ::tracing::Dispatch,
+
+    /// Whether a final status has been recorded
+    over: bool,
+}
+//
+#[cfg(any(feature = "log", feature = "tracing"))]
+impl InstrumentationState {
+    /// Record a status update of the operation, unless it is already over
+    fn record<Details: AsyncOpStatusDetails>(
+        &mut self,
+        status: &AsyncOpStatus<Details>
+    ) {
+        if self.over { return; }
+        let state = status::state(status);
+        self.over = state.is_final();
+
+        #[cfg(feature = "log")]
+        {
+            let level = if state.is_final() {
+                ::log::Level::Info
+            } else {
+                ::log::Level::Debug
+            };
+            ::log::log!(level,
+                        "Async op #{} is {}: {:?}", self.id, state, status);
+        }
+
+        #[cfg(feature = "tracing")]
+        {
+            let span = &self.span;
+            ::tracing::dispatcher::with_default(&self.dispatch, || {
+                if state.is_final() {
+                    ::tracing::info!(parent: span,
+                                     state = %state,
+                                     status = ?status,
+                                     "operation is over");
+                } else {
+                    ::tracing::debug!(parent: span,
+                                      state = %state,
+                                      status = ?status,
+                                      "operation status changed");
+                }
+            });
+            if state.is_final() {
+                self.span = ::tracing::Span::none();
+                self.dispatch = ::tracing::Dispatch::none();
+            }
+        }
+    }
+}
+
+
+/// Unit tests
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use multithread::blocking;
+    use status;
+    use std::collections::HashMap;
+    use std::fmt::Debug;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::thread;
+    use std::time::Duration;
+    use timeout;
+    use tracing::{self, Event, Id, Metadata, Subscriber};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Record};
+
+    /// What the recording subscriber has seen so far
+    #[derive(Debug, Default, PartialEq)]
+    struct Recording {
+        /// Names of the spans which were opened
+        spans: Vec<String>,
+
+        /// State recorded by each event, along with the span it belongs to
+        events: Vec<(Option<u64>, String)>,
+
+        /// Spans which were closed
+        closed: Vec<u64>,
+    }
+
+    /// Subscriber which records spans and events
+    struct Recorder {
+        /// Identifier of the next span
+        next_id: AtomicU64,
+
+        /// Number of handles to each span which is still open
+        handles: Mutex<HashMap<u64, usize>>,
+
+        /// What was recorded so far
+        recording: Arc<Mutex<Recording>>,
+    }
+    //
+    impl Subscriber for Recorder {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes) -> Id {
+            let mut recording = self.recording.lock().unwrap();
+            recording.spans.push(span.metadata().name().to_owned());
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            self.handles.lock().unwrap().insert(id, 1);
+            Id::from_u64(id)
+        }
+
+        fn clone_span(&self, span: &Id) -> Id {
+            *self.handles.lock().unwrap()
+                         .get_mut(&span.into_u64())
+                         .unwrap() += 1;
+            span.clone()
+        }
+
+        fn record(&self, _span: &Id, _values: &Record) {}
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, event: &Event) {
+            let mut visitor = StateVisitor(String::new());
+            event.record(&mut visitor);
+            let parent = event.parent().map(|id| id.into_u64());
+            self.recording.lock().unwrap().events.push((parent, visitor.0));
+        }
+
+        fn enter(&self, _span: &Id) {}
+
+        fn exit(&self, _span: &Id) {}
+
+        fn try_close(&self, span: Id) -> bool {
+            let id = span.into_u64();
+            let mut handles = self.handles.lock().unwrap();
+            let count = handles.get_mut(&id).unwrap();
+            *count -= 1;
+            if *count > 0 { return false; }
+            handles.remove(&id);
+            self.recording.lock().unwrap().closed.push(id);
+            true
+        }
+    }
+
+    /// Field visitor which extracts the state of status update events
+    struct StateVisitor(String);
+    //
+    impl Visit for StateVisitor {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            if field.name() == "state" {
+                self.0 = format!("{:?}", value);
+            }
+        }
+    }
+
+    /// Run some code with a recording subscriber, and return what it recorded
+    fn record<F: FnOnce()>(f: F) -> Recording {
+        let recording = Arc::new(Mutex::new(Recording::default()));
+        let recorder = Recorder {
+            next_id: AtomicU64::new(1),
+            handles: Mutex::new(HashMap::new()),
+            recording: recording.clone(),
+        };
+        tracing::subscriber::with_default(recorder, f);
+        Arc::try_unwrap(recording).unwrap().into_inner().unwrap()
+    }
+
+    /// Check that the lifecycle of an operation is recorded, even when its
+    /// server runs on another thread
+    #[test]
+    fn lifecycle() {
+        let recording = record(|| {
+            let (mut server, _client) =
+                blocking::AsyncOp::new(status::PENDING).split();
+            thread::spawn(move || {
+                server.update(status::RUNNING);
+                server.update(status::DONE);
+            }).join().unwrap();
+        });
+        assert_eq!(recording, Recording {
+            spans: vec!["async_op".to_owned()],
+            events: vec![(Some(1), "Pending".to_owned()),
+                         (Some(1), "Running".to_owned()),
+                         (Some(1), "Done".to_owned())],
+            closed: vec![1],
+        });
+    }
+
+    /// Check that the span is closed when the server is dropped
+    #[test]
+    fn server_drop() {
+        let recording = record(|| {
+            let (server, _client) =
+                blocking::AsyncOp::new(status::RUNNING).split();
+            let server = server.map_config(|config| config.into_inner());
+            assert!(!server.span().is_disabled());
+            ::std::mem::drop(server);
+        });
+        assert_eq!(recording.events,
+                   vec![(Some(1), "Running".to_owned()),
+                        (Some(1), "Error".to_owned())]);
+        assert_eq!(recording.closed, vec![1]);
+    }
+
+    /// Check that timeouts are recorded, and that the server's later status
+    /// updates are ignored like the client ignores them
+    #[test]
+    fn timeout() {
+        let recording = record(|| {
+            let (server, mut client) =
+                blocking::AsyncOp::new(status::PENDING).split();
+            let mut server =
+                timeout::with_timeout(server, Duration::from_millis(10));
+            server.update(status::RUNNING);
+            while !status::is_final(&client.wait()) {}
+            assert_eq!(client.status(), status::ERROR_TIMED_OUT);
+            assert!(server.span().is_disabled());
+            server.update(status::DONE);
+        });
+        assert_eq!(recording, Recording {
+            spans: vec!["async_op".to_owned()],
+            events: vec![(Some(1), "Pending".to_owned()),
+                         (Some(1), "Running".to_owned()),
+                         (Some(1), "Error".to_owned())],
+            closed: vec![1],
+        });
+    }
+}
@@ -23,10 +23,33 @@
 //!
 //! This crate is an attempt to make this dream come true.
 
+#[cfg(feature = "log")]
+extern crate log;
+#[cfg(feature = "tracing")]
+extern crate tracing;
 extern crate triple_buffer;
 
+pub mod admission;
+pub mod cancellation;
+pub mod channels;
 pub mod client;
+pub mod coalesce;
+pub mod combinators;
+pub mod command_queue;
+pub mod control;
+pub mod dependency;
 pub mod executor;
+pub mod hierarchy;
+mod instrument;
+pub mod middleware;
 pub mod multithread;
+pub mod pause;
+pub mod registry;
+pub mod retry;
 pub mod server;
 pub mod status;
+pub mod stream;
+pub mod timeout;
+mod timer;
+pub mod user_event;
+pub mod watchdog;
@@ -0,0 +1,477 @@
+//! Composable server-side middleware
+//!
+//! Server configurations are the place where status updates are propagated to
+//! the client, which makes them a natural place to observe or alter them on
+//! their way. This module provides configuration wrappers, or layers, which
+//! take an inner configuration and intercept the status updates and
+//! cancellation queries that go through it:
+//!
+//! - Logging reports status updates and cancellation to a user-provided sink.
+//! - Throttling discards Running updates which come too close to one another.
+//! - Coalescing delays them instead, as described in the coalesce module.
+//! - History recording keeps a timestamped copy of every status update.
+//! - Teeing sends every status update to a second operation as well.
+//!
+//! Layers are stacked on top of a server using a builder, starting from the
+//! innermost layer, which sees status updates last. Custom layers can be added
+//! to the stack as well, as long as they wrap their inner configuration.
+
+use channels::OpChannels;
+use coalesce::{self, CoalescingServerConfig};
+use server::{AsyncOpServer, AsyncOpServerConfig};
+use status::{AsyncOpCloneableDetails, AsyncOpStatus};
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+
+/// Start stacking middleware on top of an operation's server
+pub fn builder<Config: AsyncOpServerConfig>(
+    server: AsyncOpServer<Config>
+) -> MiddlewareBuilder<Config> {
+    MiddlewareBuilder { server }
+}
+
+
+/// Builder which stacks middleware layers on top of a server, one at a time
+pub struct MiddlewareBuilder<Config: AsyncOpServerConfig> {
+    /// Server with the layers stacked so far
+    server: AsyncOpServer<Config>,
+}
+//
+impl<Config: AsyncOpServerConfig> MiddlewareBuilder<Config> {
+    /// Stack a custom layer, given a function which wraps the configuration
+    pub fn layer<NewConfig, F>(self, wrap: F) -> MiddlewareBuilder<NewConfig>
+        where NewConfig: AsyncOpServerConfig<
+                  StatusDetails=Config::StatusDetails
+              >,
+              F: FnOnce(Config) -> NewConfig
+    {
+        MiddlewareBuilder {
+            server: self.server.map_config(|config| wrap(config.into_inner()))
+        }
+    }
+
+    /// Report status updates and cancellation to a sink, under some name
+    pub fn logged<Sink>(
+        self,
+        name: &str,
+        sink: Sink
+    ) -> MiddlewareBuilder<LoggingServerConfig<Config, Sink>>
+        where Sink: Fn(&str)
+    {
+        let name = name.to_owned();
+        self.layer(move |inner| LoggingServerConfig {
+            inner,
+            name,
+            sink,
+            cancel_logged: Cell::new(false),
+        })
+    }
+
+    /// Discard Running updates which come less than `interval` after the
+    /// last Running update which was let through
+    pub fn throttled(
+        self,
+        interval: Duration
+    ) -> MiddlewareBuilder<ThrottlingServerConfig<Config>> {
+        self.layer(move |inner| ThrottlingServerConfig {
+            inner,
+            interval,
+            last_running: None,
+        })
+    }
+
+    /// Delay Running updates which come less than `min_interval` after the
+    /// last Running update which was let through, keeping the latest one
+    pub fn coalesced(
+        self,
+        min_interval: Duration
+    ) -> MiddlewareBuilder<CoalescingServerConfig<Config>>
+        where Config: Send + 'static
+    {
+        MiddlewareBuilder {
+            server: coalesce::with_min_interval(self.server, min_interval)
+        }
+    }
+
+    /// Record a copy of every status update in some history
+    pub fn recorded(
+        self,
+        history: &History<Config::StatusDetails>
+    ) -> MiddlewareBuilder<HistoryServerConfig<Config>>
+        where Config::StatusDetails: AsyncOpCloneableDetails
+    {
+        let history = history.clone();
+        self.layer(move |inner| HistoryServerConfig { inner, history })
+    }
+
+    /// Send a copy of every status update to another operation's server
+    pub fn tee<Other>(
+        self,
+        other: AsyncOpServer<Other>
+    ) -> MiddlewareBuilder<TeeServerConfig<Config, Other>>
+        where Config::StatusDetails: AsyncOpCloneableDetails,
+              Other: AsyncOpServerConfig<StatusDetails=Config::StatusDetails>
+    {
+        self.layer(move |inner| TeeServerConfig { inner, other })
+    }
+
+    /// Get the server with all the stacked layers
+    pub fn build(self) -> AsyncOpServer<Config> {
+        self.server
+    }
+}
+
+
+/// Server configuration wrapper which reports status updates and cancellation
+/// to a sink, such as a log
+pub struct LoggingServerConfig<Config: AsyncOpServerConfig, Sink: Fn(&str)> {
+    /// Configuration of the underlying operation
+    inner: Config,
+
+    /// Name of the operation in log messages
+    name: String,
+
+    /// Destination of log messages
+    sink: Sink,
+
+    /// Whether cancellation was already reported
+    cancel_logged: Cell<bool>,
+}
+//
+impl<Config, Sink> AsyncOpServerConfig for LoggingServerConfig<Config, Sink>
+    where Config: AsyncOpServerConfig,
+          Sink: Fn(&str)
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Config::StatusDetails;
+
+    /// Method used to send status updates to the client
+    fn update(&mut self, status: AsyncOpStatus<Self::StatusDetails>) {
+        (self.sink)(&format!("{}: {:?}", self.name, status));
+        self.inner.update(status);
+    }
+
+    /// Side channels shared with the client
+    fn channels(&self) -> &OpChannels {
+        self.inner.channels()
+    }
+
+    /// Method used to query whether the client has cancelled the operation,
+    /// which reports the first time where the server notices it
+    fn cancelled(&self) -> bool {
+        let cancelled = self.inner.cancelled();
+        if cancelled && !self.cancel_logged.replace(true) {
+            (self.sink)(&format!("{}: cancellation requested", self.name));
+        }
+        cancelled
+    }
+}
+
+
+/// Server configuration wrapper which discards frequent Running updates
+///
+/// Pending and final statuses are always let through, so the client is never
+/// left behind regarding the outcome of the operation, only regarding its
+/// progress. They also reset the interval, so that the next Running update is
+/// let through as well.
+///
+pub struct ThrottlingServerConfig<Config: AsyncOpServerConfig> {
+    /// Configuration of the underlying operation
+    inner: Config,
+
+    /// Minimal interval between two Running updates
+    interval: Duration,
+
+    /// Time at which the last Running update was let through, if there was
+    /// one since the last non-Running update
+    last_running: Option<Instant>,
+}
+//
+impl<Config: AsyncOpServerConfig> AsyncOpServerConfig
+    for ThrottlingServerConfig<Config>
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Config::StatusDetails;
+
+    /// Method used to send status updates to the client
+    fn update(&mut self, status: AsyncOpStatus<Self::StatusDetails>) {
+        if let AsyncOpStatus::Running(_) = status {
+            let now = Instant::now();
+            if let Some(last_running) = self.last_running {
+                if now.duration_since(last_running) < self.interval {
+                    return;
+                }
+            }
+            self.last_running = Some(now);
+        } else {
+            self.last_running = None;
+        }
+        self.inner.update(status);
+    }
+
+    /// Side channels shared with the client
+    fn channels(&self) -> &OpChannels {
+        self.inner.channels()
+    }
+
+    /// Method used to query whether the client has cancelled the operation
+    fn cancelled(&self) -> bool {
+        self.inner.cancelled()
+    }
+}
+
+
+/// Timestamped record of the status updates of an operation
+///
+/// This object can be cheaply cloned, and all clones share the same records,
+/// so that one of them can be kept while another is handed to the server.
+///
+pub struct History<Details: AsyncOpCloneableDetails> {
+    /// Records of the status updates, from oldest to newest
+    entries: Arc<Mutex<VecDeque<HistoryEntry<Details>>>>,
+
+    /// Maximal number of records to keep, if any
+    limit: Option<usize>,
+}
+//
+impl<Details: AsyncOpCloneableDetails> History<Details> {
+    /// Create an empty history, which keeps every record
+    pub fn new() -> Self {
+        History { entries: Arc::new(Mutex::new(VecDeque::new())), limit: None }
+    }
+
+    /// Create an empty history, which only keeps the newest `limit` records
+    pub fn with_limit(limit: usize) -> Self {
+        History { limit: Some(limit), ..Self::new() }
+    }
+
+    /// Get a copy of the records, from oldest to newest
+    pub fn entries(&self) -> Vec<HistoryEntry<Details>> {
+        self.entries.lock()
+                    .unwrap()
+                    .iter()
+                    .map(|entry| HistoryEntry {
+                        time: entry.time,
+                        status: entry.status.clone(),
+                    })
+                    .collect()
+    }
+
+    /// Get a copy of the recorded statuses, from oldest to newest
+    pub fn statuses(&self) -> Vec<AsyncOpStatus<Details>> {
+        self.entries().into_iter().map(|entry| entry.status).collect()
+    }
+
+    /// Record a status update
+    fn record(&self, status: AsyncOpStatus<Details>) {
+        let mut entries = self.entries.lock().unwrap();
+        if Some(entries.len()) == self.limit {
+            entries.pop_front();
+        }
+        if self.limit != Some(0) {
+            entries.push_back(HistoryEntry { time: Instant::now(), status });
+        }
+    }
+}
+//
+impl<Details: AsyncOpCloneableDetails> Clone for History<Details> {
+    fn clone(&self) -> Self {
+        History { entries: self.entries.clone(), limit: self.limit }
+    }
+}
+//
+impl<Details: AsyncOpCloneableDetails> Default for History<Details> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+/// Record of a status update
+#[derive(Debug)]
+pub struct HistoryEntry<Details: AsyncOpCloneableDetails> {
+    /// Time at which the status update occured
+    pub time: Instant,
+
+    /// New status of the operation
+    pub status: AsyncOpStatus<Details>,
+}
+
+
+/// Server configuration wrapper which records status updates in a history
+pub struct HistoryServerConfig<Config>
+    where Config: AsyncOpServerConfig,
+          Config::StatusDetails: AsyncOpCloneableDetails
+{
+    /// Configuration of the underlying operation
+    inner: Config,
+
+    /// History in which status updates are recorded
+    history: History<Config::StatusDetails>,
+}
+//
+impl<Config> AsyncOpServerConfig for HistoryServerConfig<Config>
+    where Config: AsyncOpServerConfig,
+          Config::StatusDetails: AsyncOpCloneableDetails
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Config::StatusDetails;
+
+    /// Method used to send status updates to the client
+    fn update(&mut self, status: AsyncOpStatus<Self::StatusDetails>) {
+        self.history.record(status.clone());
+        self.inner.update(status);
+    }
+
+    /// Side channels shared with the client
+    fn channels(&self) -> &OpChannels {
+        self.inner.channels()
+    }
+
+    /// Method used to query whether the client has cancelled the operation
+    fn cancelled(&self) -> bool {
+        self.inner.cancelled()
+    }
+}
+
+
+/// Server configuration wrapper which also sends status updates to the server
+/// of another operation
+///
+/// Only the client of the wrapped configuration has control over the
+/// operation: cancellation, pausing, control messages and partial results of
+/// the other operation are ignored. If the operation's server is dropped
+/// early, the other operation's server is dropped as well, so both clients are
+/// notified.
+///
+pub struct TeeServerConfig<Config, Other>
+    where Config: AsyncOpServerConfig,
+          Config::StatusDetails: AsyncOpCloneableDetails,
+          Other: AsyncOpServerConfig<StatusDetails=Config::StatusDetails>
+{
+    /// Configuration of the underlying operation
+    inner: Config,
+
+    /// Server of the other operation
+    other: AsyncOpServer<Other>,
+}
+//
+impl<Config, Other> AsyncOpServerConfig for TeeServerConfig<Config, Other>
+    where Config: AsyncOpServerConfig,
+          Config::StatusDetails: AsyncOpCloneableDetails,
+          Other: AsyncOpServerConfig<StatusDetails=Config::StatusDetails>
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Config::StatusDetails;
+
+    /// Method used to send status updates to the client
+    fn update(&mut self, status: AsyncOpStatus<Self::StatusDetails>) {
+        if !self.other.is_final() {
+            self.other.update(status.clone());
+        }
+        self.inner.update(status);
+    }
+
+    /// Side channels shared with the client
+    fn channels(&self) -> &OpChannels {
+        self.inner.channels()
+    }
+
+    /// Method used to query whether the client has cancelled the operation
+    fn cancelled(&self) -> bool {
+        self.inner.cancelled()
+    }
+}
+
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use client::IAsyncOpClient;
+    use middleware::*;
+    use multithread::{blocking, polling};
+    use status;
+    use std::thread;
+
+    /// Check that logging reports status updates and cancellation once
+    #[test]
+    fn logging() {
+        let (server, mut client) =
+            blocking::AsyncOp::new(status::PENDING).split();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let sink_log = log.clone();
+        let mut server = builder(server).logged("op", move |message| {
+            sink_log.lock().unwrap().push(message.to_owned());
+        }).build();
+        server.update(status::RUNNING);
+        client.cancel();
+        assert!(server.cancelled());
+        assert!(server.cancelled());
+        assert_eq!(*log.lock().unwrap(),
+                   vec![format!("op: {:?}", status::RUNNING),
+                        "op: cancellation requested".to_owned()]);
+    }
+
+    /// Check that throttling discards Running updates, but not final ones
+    #[test]
+    fn throttling() {
+        let history = History::new();
+        let (server, _client) = polling::AsyncOp::new(status::PENDING).split();
+        let mut server = builder(server).recorded(&history)
+                                        .throttled(Duration::from_millis(50))
+                                        .build();
+        server.update(status::RUNNING);
+        server.update(status::RUNNING);
+        thread::sleep(Duration::from_millis(60));
+        server.update(status::RUNNING);
+
+        // Going back to Pending should not delay the next Running update
+        server.update(status::PENDING);
+        server.update(status::RUNNING);
+        server.update(status::DONE);
+        assert_eq!(history.statuses(), vec![status::RUNNING,
+                                            status::RUNNING,
+                                            status::PENDING,
+                                            status::RUNNING,
+                                            status::DONE]);
+    }
+
+    /// Check that histories only keep the newest records if asked to
+    #[test]
+    fn history_limit() {
+        let history = History::with_limit(2);
+        let (server, _client) = polling::AsyncOp::new(status::PENDING).split();
+        let mut server = builder(server).recorded(&history).build();
+        server.update(status::RUNNING);
+        server.update(status::RUNNING);
+        server.update(status::DONE);
+        assert_eq!(history.statuses(), vec![status::RUNNING, status::DONE]);
+        let entries = history.entries();
+        assert!(entries[0].time <= entries[1].time);
+    }
+
+    /// Check that teeing sends status updates to both operations
+    #[test]
+    fn tee() {
+        let (server, mut client) =
+            polling::AsyncOp::new(status::PENDING).split();
+        let (other_server, mut other_client) =
+            blocking::AsyncOp::new(status::PENDING).split();
+        let mut server = builder(server).tee(other_server).build();
+        server.update(status::RUNNING);
+        assert_eq!(*client.status(), status::RUNNING);
+        assert_eq!(other_client.wait(), status::RUNNING);
+
+        // Only the main client controls the operation
+        other_client.cancel();
+        assert!(!server.cancelled());
+
+        // Killing the server should notify both clients
+        ::std::mem::drop(server);
+        assert_eq!(*client.status(), status::ERROR_SERVER_KILLED);
+        assert_eq!(other_client.wait(), status::ERROR_SERVER_KILLED);
+    }
+}
@@ -4,12 +4,28 @@
 //! until their status changes. This synchronization mechanism is easy to use
 //! and reason about, but should be used with care as the unpredictable
 //! application delays that it introduces can be harmful to performance.
-
-use client::IAsyncOpClient;
-use server::{self, AsyncOpServerConfig};
-use status::{self, AsyncOpStatus, AsyncOpStatusDetails};
-use std::sync::{Arc, Mutex, Condvar};
-use std::sync::atomic::{AtomicBool, Ordering};
+//!
+//! Status queries return a copy of the operation status, which requires its
+//! details to be cloneable. Clients can avoid this copy by borrowing the status
+//! in place with status_ref() and wait_ref(), or, if they are only interested
+//! in the final result, by moving it out with take_result().
+//!
+//! Additional clients can be created with subscribe(). Instead of a single read
+//! bit, the shared state counts status updates, and each client remembers how
+//! many of them it has read, so that clients have independent read states.
+
+use cancellation::CancellationToken;
+use channels::{ClientChannels, OpChannels};
+use client::{IAsyncOpClient, IAsyncOpControlClient, IAsyncOpStatusClient,
+             IAsyncOpStreamClient, IAsyncOpWaitClient};
+use control::AsyncOpControlDetails;
+use server::{self, AsyncOpServerConfig, DynAsyncOpServer};
+use status::{self, AsyncOpCloneableDetails, AsyncOpError, AsyncOpStatus,
+             AsyncOpStatusDetails};
+use std::mem;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, MutexGuard, Condvar};
+use stream::AsyncOpStreamDetails;
 
 /// Asynchronous operation object
 pub struct AsyncOp<Details: AsyncOpStatusDetails> {
@@ -23,30 +39,50 @@ pub struct AsyncOp<Details: AsyncOpStatusDetails> {
 impl<Details: AsyncOpStatusDetails> AsyncOp<Details> {
     /// Create a new asynchronous operation object with some initial status
     pub fn new(initial_status: AsyncOpStatus<Details>) -> Self {
-        // Keep a copy of the initial operation status
-        let initial_status_copy = initial_status.clone();
+        Self::with_cancel_flag(initial_status, CancellationToken::new())
+    }
 
+    /// Create a new asynchronous operation object with some initial status,
+    /// which will be cancelled if the provided token is cancelled
+    pub fn with_token(initial_status: AsyncOpStatus<Details>,
+                      token: &CancellationToken) -> Self {
+        Self::with_cancel_flag(initial_status, token.child())
+    }
+
+    /// Create a new asynchronous operation object with some initial status and
+    /// a certain cancellation flag
+    fn with_cancel_flag(initial_status: AsyncOpStatus<Details>,
+                        cancelled: CancellationToken) -> Self {
         // Start by building the shared state...
         let shared_state = Arc::new(
             SharedState {
                 status_lock: Mutex::new(
-                    StatusWithReadBit {
+                    StatusWithGeneration {
                         status: initial_status,
-                        read: false,
+                        generation: 1,
                     }
                 ),
                 update_cv: Condvar::new(),
-                cancelled: AtomicBool::new(false),
+                channels: OpChannels::with_token(cancelled),
             }
         );
 
         // ...then build the client and server
-        AsyncOp {
-            server: AsyncOpServer::new(
+        let server = {
+            let status_lock = shared_state.status_lock.lock().unwrap();
+            AsyncOpServer::new(
                 BlockingServerConfig { shared: shared_state.clone() },
-                &initial_status_copy
-            ),
-            client: AsyncOpClient { shared: shared_state },
+                &status_lock.status
+            )
+        };
+        let channels = ClientChannels::new(&shared_state.channels);
+        AsyncOp {
+            server,
+            client: AsyncOpClient {
+                shared: shared_state,
+                channels,
+                read_generation: 0,
+            },
         }
     }
 
@@ -59,10 +95,21 @@ impl<Details: AsyncOpStatusDetails> AsyncOp<Details> {
 
 
 /// Server interface, used to send operation status updates to the client
-pub type AsyncOpServer<Details: AsyncOpStatusDetails> =
+pub type AsyncOpServer<Details> =
     server::AsyncOpServer<BlockingServerConfig<Details>>;
 
 
+/// Servers can be converted into servers of unknown monitoring mode
+impl<Details: AsyncOpStatusDetails + 'static> From<AsyncOpServer<Details>>
+    for DynAsyncOpServer<Details>
+{
+    /// Erase the monitoring mode of the server
+    fn from(server: AsyncOpServer<Details>) -> Self {
+        server.into_dyn()
+    }
+}
+
+
 /// Server configuration for blocking operation monitoring
 pub struct BlockingServerConfig<Details: AsyncOpStatusDetails> {
     /// Reference-counted shared state
@@ -77,19 +124,25 @@ impl<Details: AsyncOpStatusDetails> AsyncOpServerConfig
 
     /// Method used to send a status update to the client
     fn update(&mut self, status: AsyncOpStatus<Details>) {
+        // Once the operation is over, the result stream is over as well
+        if status::is_final(&status) {
+            self.shared.channels.close_stream();
+        }
+
         // Update the value of the asynchronous operation status
-        *self.shared.status_lock
-                    .lock()
-                    .unwrap() = StatusWithReadBit { status: status,
-                                                    read: false };
+        {
+            let mut status_lock = self.shared.status_lock.lock().unwrap();
+            status_lock.status = status;
+            status_lock.generation += 1;
+        }
 
-        // Notify the reader that an update has occured
+        // Notify the readers that an update has occured
         self.shared.update_cv.notify_all();
     }
 
-    /// Method used to query whether the client has cancelled the operation
-    fn cancelled(&self) -> bool {
-        self.shared.cancelled.load(Ordering::Acquire)
+    /// Side channels shared with the client
+    fn channels(&self) -> &OpChannels {
+        &self.shared.channels
     }
 }
 
@@ -98,44 +151,198 @@ impl<Details: AsyncOpStatusDetails> AsyncOpServerConfig
 pub struct AsyncOpClient<Details: AsyncOpStatusDetails> {
     /// Reference-counted shared state
     shared: Arc<SharedState<Details>>,
+
+    /// Side channels of the operation, through which the client is also
+    /// accounted for
+    channels: ClientChannels,
+
+    /// Generation of the last operation status which the client has read
+    read_generation: usize,
 }
 //
 impl<Details: AsyncOpStatusDetails> AsyncOpClient<Details> {
-    /// Access the current operation status and mark it as read
-    pub fn status(&mut self) -> AsyncOpStatus<Details> {
-        // Access the current operation status
+    /// Wait for the operation to reach a final status, then move its result
+    /// out if it is done, or its final status otherwise
+    ///
+    /// Unlike status queries, this does not copy anything, so it also works
+    /// for results which cannot be cloned. But since the final status is moved
+    /// out, this may only be done once. From then on, this client and every
+    /// other one see an `AsyncOpError::ResultTaken` error in its place, which
+    /// later calls to this method return as well.
+    ///
+    pub fn take_result(
+        &mut self
+    ) -> Result<Details::DoneDetails, AsyncOpStatus<Details>> {
+        // Wait for the final operation status
         let mut status_lock = self.shared.status_lock.lock().unwrap();
+        while !status::is_final(&status_lock.status) {
+            let wait_result = self.shared.update_cv.wait(status_lock);
+            status_lock = wait_result.unwrap();
+        }
 
-        // Mark it as read
-        status_lock.read = true;
-
-        // Return a copy of it
-        status_lock.status.clone()
+        // Move it out, leaving an error behind
+        self.read_generation = status_lock.generation;
+        let taken = AsyncOpStatus::Error(AsyncOpError::ResultTaken);
+        match mem::replace(&mut status_lock.status, taken) {
+            AsyncOpStatus::Done(result) => Ok(result),
+            other => Err(other),
+        }
     }
 
-    /// Wait for either a status update or a final operation status
-    pub fn wait(&mut self) -> AsyncOpStatus<Details> {
-        // Access the current operation status
-        let mut status_lock = self.shared.status_lock.lock().unwrap();
+    /// Borrow the current operation status in place and mark it as read
+    ///
+    /// This avoids copying the status, but the server cannot update it until
+    /// the returned guard is dropped, so it should not be held for long.
+    ///
+    pub fn status_ref(&mut self) -> StatusGuard<'_, Details> {
+        // Access the current operation status and mark it as read
+        let status_lock = self.shared.status_lock.lock().unwrap();
+        self.read_generation = status_lock.generation;
+        StatusGuard { status_lock }
+    }
 
+    /// Wait for either a status update or a final operation status, then
+    /// borrow it in place like status_ref() does
+    pub fn wait_ref(&mut self) -> StatusGuard<'_, Details> {
         // Only wait if the current status was read and can still change
-        while status_lock.read && !status::is_final(&status_lock.status) {
+        let mut status_lock = self.shared.status_lock.lock().unwrap();
+        while status_lock.generation == self.read_generation
+              && !status::is_final(&status_lock.status) {
             let wait_result = self.shared.update_cv.wait(status_lock);
             status_lock = wait_result.unwrap();
         }
 
         // Mark the current operation status as read
-        status_lock.read = true;
+        self.read_generation = status_lock.generation;
+        StatusGuard { status_lock }
+    }
 
-        // Return a copy of the final operation status
-        status_lock.status.clone()
+    /// Create another client for the same operation, with its own read state,
+    /// for which the current operation status is unread
+    ///
+    /// All clients share control over the operation: any of them can cancel
+    /// or pause it, or send it control messages. Partial results are shared
+    /// too, so each one of them is consumed by only one client.
+    ///
+    pub fn subscribe(&self) -> Self {
+        AsyncOpClient {
+            shared: self.shared.clone(),
+            channels: self.channels.clone(),
+            read_generation: 0,
+        }
+    }
+}
+//
+impl<Details: AsyncOpCloneableDetails> AsyncOpClient<Details> {
+    /// Access the current operation status and mark it as read
+    pub fn status(&mut self) -> AsyncOpStatus<Details> {
+        self.status_ref().clone()
+    }
+
+    /// Wait for either a status update or a final operation status
+    pub fn wait(&mut self) -> AsyncOpStatus<Details> {
+        self.wait_ref().clone()
     }
 }
 //
 impl<Details: AsyncOpStatusDetails> IAsyncOpClient for AsyncOpClient<Details> {
     /// Request the cancellation of the active asynchronous operation
     fn cancel(&mut self) {
-        self.shared.cancelled.store(true, Ordering::Release);
+        self.channels.cancellation().cancel();
+    }
+
+    /// Request the active asynchronous operation to pause
+    fn pause(&mut self) {
+        self.channels.pause_flag().pause();
+    }
+
+    /// Request a paused asynchronous operation to resume
+    fn resume(&mut self) {
+        self.channels.pause_flag().resume();
+    }
+}
+//
+impl<Details> IAsyncOpControlClient for AsyncOpClient<Details>
+    where Details: AsyncOpControlDetails
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Details;
+
+    /// Send a control message to the server of the asynchronous operation
+    fn send_control(&mut self, message: Details::ControlMessage) {
+        self.channels.control_channel().send(message);
+    }
+}
+//
+impl<Details> IAsyncOpStreamClient for AsyncOpClient<Details>
+    where Details: AsyncOpStreamDetails
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Details;
+
+    /// Consume the oldest partial result, if any, without blocking
+    fn try_next_item(&mut self) -> Option<Details::StreamItem> {
+        self.channels.stream_channel().try_next()
+    }
+
+    /// Consume the oldest partial result, blocking until one is available
+    fn next_item(&mut self) -> Option<Details::StreamItem> {
+        self.channels.stream_channel().wait_next()
+    }
+
+    /// Check whether all partial results have been consumed
+    fn stream_finished(&mut self) -> bool {
+        self.channels.stream_channel().is_finished()
+    }
+}
+//
+impl<Details: AsyncOpCloneableDetails> IAsyncOpStatusClient
+    for AsyncOpClient<Details>
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Details;
+
+    /// Query the current asynchronous operation status, marking it as read
+    fn current_status(&mut self) -> AsyncOpStatus<Details> {
+        self.status()
+    }
+}
+
+//
+impl<Details: AsyncOpCloneableDetails> IAsyncOpWaitClient
+    for AsyncOpClient<Details>
+{
+    /// Wait for either a status update or a final operation status
+    fn wait(&mut self) -> AsyncOpStatus<Details> {
+        AsyncOpClient::wait(self)
+    }
+
+    /// Return the operation status if it was updated since it was last read
+    /// or if it is final, or None instead of blocking
+    fn try_wait(&mut self) -> Option<AsyncOpStatus<Details>> {
+        let status_lock = self.shared.status_lock.lock().unwrap();
+        if status_lock.generation == self.read_generation
+           && !status::is_final(&status_lock.status) {
+            return None;
+        }
+        self.read_generation = status_lock.generation;
+        Some(status_lock.status.clone())
+    }
+}
+
+
+/// Borrowed view of the operation status, which blocks status updates
+pub struct StatusGuard<'a, Details: AsyncOpStatusDetails + 'a> {
+    /// Lock on the shared operation status
+    status_lock: MutexGuard<'a, StatusWithGeneration<Details>>,
+}
+//
+impl<'a, Details: AsyncOpStatusDetails> Deref for StatusGuard<'a, Details> {
+    type Target = AsyncOpStatus<Details>;
+
+    /// Access the borrowed operation status
+    fn deref(&self) -> &AsyncOpStatus<Details> {
+        &self.status_lock.status
     }
 }
 
@@ -143,21 +350,23 @@ impl<Details: AsyncOpStatusDetails> IAsyncOpClient for AsyncOpClient<Details> {
 /// State shared between the client and the server
 struct SharedState<Details: AsyncOpStatusDetails> {
     /// Current asynchronous operation status (mutex-protected)
-    status_lock: Mutex<StatusWithReadBit<Details>>,
+    status_lock: Mutex<StatusWithGeneration<Details>>,
 
     /// Condition variable used to notify clients about status updates
     update_cv: Condvar,
 
-    /// Atomic boolean used by the client to request cancellation
-    cancelled: AtomicBool,
+    /// Side channels shared by the client and the server
+    channels: OpChannels,
 }
 //
-struct StatusWithReadBit<Details: AsyncOpStatusDetails> {
-    /// Current asynchronous operation status
+struct StatusWithGeneration<Details: AsyncOpStatusDetails> {
+    /// Current asynchronous operation status, which is replaced with an
+    /// error once the final status has been taken
     status: AsyncOpStatus<Details>,
 
-    /// Whether this version of the status was read by the client
-    read: bool,
+    /// Number of status updates published so far, plus one, which clients
+    /// compare with the last generation that they read
+    generation: usize,
 }
 
 
@@ -165,25 +374,34 @@ struct StatusWithReadBit<Details: AsyncOpStatusDetails> {
 #[cfg(test)]
 mod tests {
     use multithread::blocking::*;
-    use status;
+    use status::{self, AsyncOpError, AsyncOpStatusTraits};
     use std::sync::{Arc, Condvar};
     use std::thread;
     use std::time::Duration;
 
+    /// Check whether the server has published a status which a client did not
+    /// read yet
+    fn unread<Details: AsyncOpStatusDetails>(
+        client: &AsyncOpClient<Details>
+    ) -> bool {
+        let status_lock = client.shared.status_lock.lock().unwrap();
+        status_lock.generation != client.read_generation
+    }
+
     /// Check the initial state of asynchronous operations
     #[test]
     fn initial_state() {
         // Access the initial value of the operation's shared state
         let async_op = AsyncOp::new(status::PENDING);
+        assert!(unread(&async_op.client));
         let shared_state = async_op.client.shared;
 
-        // Is the initial operation status correct and unread?
+        // Is the initial operation status correct?
         let status_lock = shared_state.status_lock.lock().unwrap();
         assert_eq!(status_lock.status, status::PENDING);
-        assert_eq!(status_lock.read, false);
 
         // Is it mistakenly cancelled?
-        let cancelled = shared_state.cancelled.load(Ordering::Relaxed);
+        let cancelled = shared_state.channels.cancellation().is_cancelled();
         assert!(!cancelled);
     }
 
@@ -197,9 +415,9 @@ mod tests {
         assert_eq!(client.status(), status::RUNNING);
 
         // Check that it marks the operation status as read
+        assert!(!unread(&client));
         let status_lock = client.shared.status_lock.lock().unwrap();
         assert_eq!(status_lock.status, status::RUNNING);
-        assert_eq!(status_lock.read, true);
     }
 
     /// Check that writing to the operation status works
@@ -207,13 +425,14 @@ mod tests {
     fn write() {
         // Send a status update
         let async_op = AsyncOp::new(status::PENDING);
-        let (mut server, client) = async_op.split();
+        let (mut server, mut client) = async_op.split();
+        client.status();
         server.update(status::RUNNING);
 
         // Check that it marks the operation status as unread
+        assert!(unread(&client));
         let status_lock = client.shared.status_lock.lock().unwrap();
         assert_eq!(status_lock.status, status::RUNNING);
-        assert_eq!(status_lock.read, false);
     }
 
     /// Check that waiting for status changes works
@@ -298,6 +517,118 @@ mod tests {
         client.cancel();
         assert!(server.cancelled());
     }
+
+    /// Check that subscribers have independent read states
+    #[test]
+    fn subscribers() {
+        let (mut server, mut client) = AsyncOp::new(status::PENDING).split();
+        assert_eq!(client.status(), status::PENDING);
+        let mut subscriber = client.subscribe();
+        assert!(unread(&subscriber));
+        assert!(!unread(&client));
+
+        // Reading the status through a subscriber leaves the others unread
+        server.update(status::RUNNING);
+        assert_eq!(subscriber.wait(), status::RUNNING);
+        assert!(!unread(&subscriber));
+        assert!(unread(&client));
+
+        // Every waiting subscriber should be woken up
+        let waiter = thread::spawn(move || subscriber.wait());
+        assert_eq!(client.wait(), status::RUNNING);
+        server.update(status::DONE);
+        assert_eq!(waiter.join().unwrap(), status::DONE);
+        assert_eq!(client.wait(), status::DONE);
+
+        // Any subscriber can cancel the operation
+        client.subscribe().cancel();
+        assert!(server.cancelled());
+    }
+
+    /// Results which can be moved, but not cloned
+    #[derive(Debug, PartialEq)]
+    struct Buffer(Vec<u8>);
+    //
+    impl AsyncOpStatusTraits for Buffer {}
+
+    /// Status details of operations which produce a buffer
+    #[derive(Debug)]
+    struct BufferDetails {}
+    //
+    impl AsyncOpStatusDetails for BufferDetails {
+        type PendingDetails = status::NoDetails;
+        type RunningDetails = status::NoDetails;
+        type DoneDetails = Buffer;
+        type CancelledDetails = status::NoDetails;
+        type ErrorDetails = status::NoDetails;
+    }
+    //
+    impl AsyncOpStatusTraits for BufferDetails {}
+
+    /// Check that results can be moved out without cloning them
+    #[test]
+    fn take_result() {
+        // Results should be moved out once the operation is done
+        let async_op = AsyncOp::<BufferDetails>::new(
+            AsyncOpStatus::Pending(status::NO_DETAILS)
+        );
+        let (mut server, mut client) = async_op.split();
+        let worker = thread::spawn(move || client.take_result());
+        server.update(AsyncOpStatus::Done(Buffer(vec![4, 2])));
+        assert_eq!(worker.join().unwrap().ok(), Some(Buffer(vec![4, 2])));
+
+        // Other final statuses should be moved out instead
+        let async_op = AsyncOp::<BufferDetails>::new(
+            AsyncOpStatus::Running(status::NO_DETAILS)
+        );
+        let (server, mut client) = async_op.split();
+        ::std::mem::drop(server);
+        match client.take_result() {
+            Err(AsyncOpStatus::Error(AsyncOpError::ServerKilled)) => {},
+            _ => panic!("Killed operations should not have a result"),
+        }
+
+        // Once the final status is taken, every client should be told so
+        let mut subscriber = client.subscribe();
+        for _ in 0..2 {
+            match client.take_result() {
+                Err(AsyncOpStatus::Error(AsyncOpError::ResultTaken)) => {},
+                _ => panic!("The final status should only be taken once"),
+            }
+        }
+        match *subscriber.wait_ref() {
+            AsyncOpStatus::Error(AsyncOpError::ResultTaken) => {},
+            _ => panic!("Subscribers should see that the status was taken"),
+        }
+
+        // Status queries should keep working as well
+        let (mut server, mut client) = AsyncOp::new(status::RUNNING).split();
+        server.update(status::DONE);
+        assert_eq!(client.take_result(), Ok(status::NO_DETAILS));
+        assert_eq!(client.status(), status::ERROR_RESULT_TAKEN);
+        assert_eq!(client.wait(), status::ERROR_RESULT_TAKEN);
+    }
+
+    /// Check that statuses can be borrowed in place
+    #[test]
+    fn borrowed_status() {
+        let async_op = AsyncOp::<BufferDetails>::new(
+            AsyncOpStatus::Pending(status::NO_DETAILS)
+        );
+        let (mut server, mut client) = async_op.split();
+        assert!(!status::is_final(&*client.status_ref()));
+        assert!(!unread(&client));
+
+        // Waiting should give access to the next status, without copying it
+        let worker = thread::spawn(move || {
+            server.update(AsyncOpStatus::Done(Buffer(vec![1, 2, 3])));
+        });
+        match *client.wait_ref() {
+            AsyncOpStatus::Done(ref buffer) => assert_eq!(buffer.0.len(), 3),
+            _ => panic!("The operation should be done"),
+        }
+        worker.join().unwrap();
+    }
 }
 
 
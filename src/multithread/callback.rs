@@ -4,14 +4,41 @@
 //! asynchronous callbacks. It is more flexible than polling and blocking (which
 //! could technically be implemented on top of it), and can achieve higher
 //! performance, but at the cost of somewhat higher code complexity.
+//!
+//! Statuses are moved into the callback, so their details need not be
+//! cloneable, which makes this mode suitable for results that are expensive or
+//! impossible to copy.
+//!
+//! The callback of an operation created with new_async_op() need not be Send,
+//! but then its server may only be sent to other threads if the executor's
+//! notification channels can be. Operations created with new_send_async_op()
+//! require a Send callback instead, and their server can always be sent to
+//! other threads, be given a deadline, or have its type erased.
+//!
+//! Additional callbacks can be registered with subscribe(), possibly on other
+//! executors. Status updates are then fanned out to every callback, which does
+//! require the status details to be cloneable. Subscribers are only notified
+//! of the status updates which occur after they subscribed. Since they are
+//! invoked by the server, their callbacks must be Send. Callbacks are invoked
+//! without holding any lock, so they may subscribe more callbacks.
+//!
+//! Since subscribing requires knowing the status type, clients are generic
+//! over the status details, like those of the other monitoring modes. Code
+//! which used to name the non-generic `AsyncOpClient` type must now spell out
+//! the details of the operation, as in `AsyncOpClient<Details>`.
 
-use client::IAsyncOpClient;
-use executor::{CallbackExecutor, AnyCallbackChannel};
-use server::{self, AsyncOpServerConfig};
-use status::{AsyncOpStatus, AsyncOpStatusDetails};
+use cancellation::CancellationToken;
+use channels::{ClientChannels, OpChannels};
+use client::{IAsyncOpClient, IAsyncOpControlClient, IAsyncOpStreamClient};
+use control::AsyncOpControlDetails;
+use executor::{CallbackExecutor, AnyCallbackChannel, SendCallbackExecutor};
+use server::{self, AsyncOpServerConfig, DynAsyncOpServer};
+use status::{self, AsyncOpCloneableDetails, AsyncOpStatus,
+             AsyncOpStatusDetails};
 use std::marker::PhantomData;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use stream::AsyncOpStreamDetails;
 
 
 /// Asynchronous operation object
@@ -22,7 +49,7 @@ pub struct AsyncOp<Details: AsyncOpStatusDetails + 'static,
     server: AsyncOpServer<Details, Channel>,
 
     /// Client interface used to monitor the operation status
-    client: AsyncOpClient,
+    client: AsyncOpClient<Details>,
 }
 //
 impl<Details: AsyncOpStatusDetails,
@@ -33,7 +60,9 @@ AsyncOp<Details, Channel> {
 
     /// Split the asynchronous operation object into client and server
     /// objects which can be respectively sent to client and server threads
-    pub fn split(self) -> (AsyncOpServer<Details, Channel>, AsyncOpClient) {
+    pub fn split(self)
+        -> (AsyncOpServer<Details, Channel>, AsyncOpClient<Details>)
+    {
         (self.server, self.client)
     }
 }
@@ -47,43 +76,120 @@ pub fn new_async_op<Details: AsyncOpStatusDetails + 'static,
     executor: &mut Executor,
     initial_status: AsyncOpStatus<Details>
 ) -> AsyncOp<Details, Executor::Channel> {
-    // Setup a callback channel on the active executor...
-    let callback_channel = executor.setup_callback(callback);
+    new_async_op_impl(executor.setup_callback(callback),
+                      initial_status,
+                      CancellationToken::new())
+}
+
+
+/// EXTERNAL constructor of asynchronous operations which will be cancelled if
+/// the provided token is cancelled
+pub fn new_async_op_with_token<Details: AsyncOpStatusDetails + 'static,
+                               F: Fn(AsyncOpStatus<Details>) + 'static,
+                               Executor: CallbackExecutor>(
+    callback: F,
+    executor: &mut Executor,
+    initial_status: AsyncOpStatus<Details>,
+    token: &CancellationToken
+) -> AsyncOp<Details, Executor::Channel> {
+    new_async_op_impl(executor.setup_callback(callback),
+                      initial_status,
+                      token.child())
+}
+
+
+/// EXTERNAL constructor of asynchronous operations whose server can be sent to
+/// other threads, given a callback which can be
+pub fn new_send_async_op<Details: AsyncOpStatusDetails + 'static,
+                         F: Fn(AsyncOpStatus<Details>) + Send + 'static,
+                         Executor: SendCallbackExecutor>(
+    callback: F,
+    executor: &mut Executor,
+    initial_status: AsyncOpStatus<Details>
+) -> AsyncOp<Details, Executor::SendChannel> {
+    new_async_op_impl(executor.setup_send_callback(callback),
+                      initial_status,
+                      CancellationToken::new())
+}
+
+
+/// EXTERNAL constructor of asynchronous operations whose server can be sent to
+/// other threads, and which will be cancelled if the provided token is
+pub fn new_send_async_op_with_token<
+    Details: AsyncOpStatusDetails + 'static,
+    F: Fn(AsyncOpStatus<Details>) + Send + 'static,
+    Executor: SendCallbackExecutor
+>(
+    callback: F,
+    executor: &mut Executor,
+    initial_status: AsyncOpStatus<Details>,
+    token: &CancellationToken
+) -> AsyncOp<Details, Executor::SendChannel> {
+    new_async_op_impl(executor.setup_send_callback(callback),
+                      initial_status,
+                      token.child())
+}
 
-    // ...and a shared cancellation flag...
-    let cancel_flag = Arc::new(AtomicBool::new(false));
+
+/// Shared implementation of the asynchronous operation constructors
+fn new_async_op_impl<Details: AsyncOpStatusDetails + 'static,
+                     Channel: AnyCallbackChannel>(
+    callback_channel: Channel,
+    initial_status: AsyncOpStatus<Details>,
+    cancel_flag: CancellationToken
+) -> AsyncOp<Details, Channel> {
+    // Use the callback channel which was set up on the active executor...
+    let channels = OpChannels::with_token(cancel_flag);
+    let subscribers = Subscribers::default();
 
     // ...then build the asynchronous operation client and serer
     AsyncOp {
         server: AsyncOpServer::new(
             CallbackServerConfig {
                 channel: callback_channel,
-                cancelled: cancel_flag.clone(),
+                channels: channels.clone(),
+                subscribers: subscribers.clone(),
                 details: PhantomData,
             },
             &initial_status
         ),
         client: AsyncOpClient {
-            cancelled: cancel_flag,
+            channels: ClientChannels::new(&channels),
+            subscribers,
         },
     }
 }
 
 
 /// Server interface, used to send operation status updates to the client
-pub type AsyncOpServer<Details: AsyncOpStatusDetails + 'static,
-                       Channel: AnyCallbackChannel> =
+pub type AsyncOpServer<Details, Channel> =
     server::AsyncOpServer<CallbackServerConfig<Details, Channel>>;
 
 
+/// Servers can be converted into servers of unknown monitoring mode
+impl<Details, Channel> From<AsyncOpServer<Details, Channel>>
+    for DynAsyncOpServer<Details>
+    where Details: AsyncOpStatusDetails + 'static,
+          Channel: AnyCallbackChannel + Send + 'static
+{
+    /// Erase the monitoring mode of the server
+    fn from(server: AsyncOpServer<Details, Channel>) -> Self {
+        server.into_dyn()
+    }
+}
+
+
 /// Server configuration for callback-based operation monitoring
 pub struct CallbackServerConfig<Details: AsyncOpStatusDetails + 'static,
                                 CallbackChannel: AnyCallbackChannel> {
     /// The following callback channel will receive our status updates
     channel: CallbackChannel,
 
-    /// In addition, the client & server also share a cancellation flag
-    cancelled: Arc<AtomicBool>,
+    /// In addition, the client & server also share some side channels...
+    channels: OpChannels,
+
+    /// ...and the callbacks of additional subscribers
+    subscribers: Subscribers<Details>,
 
     /// We need to remember our status details because AnyCallbackChannel won't
     /// be able to do it for us
@@ -99,29 +205,127 @@ AsyncOpServerConfig for CallbackServerConfig<Details, CallbackChannel>
 
     /// Method used to send a status update to the client
     fn update(&mut self, status: AsyncOpStatus<Details>) {
+        // Subscribers are called without holding the lock, so that they can
+        // subscribe more callbacks, which will be added after them
+        let is_final = status::is_final(&status);
+        let mut subscribers = mem::take(&mut *self.subscribers.lock().unwrap());
+        for subscriber in subscribers.iter_mut() {
+            subscriber(&status);
+        }
+        {
+            let mut locked = self.subscribers.lock().unwrap();
+            let added = mem::replace(&mut *locked, subscribers);
+            locked.extend(added);
+        }
         self.channel.notify(status);
+        if is_final {
+            self.channels.close_stream();
+        }
     }
 
-    /// Method used to query whether the client has cancelled the operation
-    fn cancelled(&self) -> bool {
-        self.cancelled.load(Ordering::Acquire)
+    /// Side channels shared with the client
+    fn channels(&self) -> &OpChannels {
+        &self.channels
     }
 }
 
 
-/// Client interface, only used to cancel the asynchronous operation
-pub struct AsyncOpClient {
-    /// In callback-based synchronization, all the client can do is cancel
-    cancelled: Arc<AtomicBool>,
+/// Client interface, only used to control the asynchronous operation
+pub struct AsyncOpClient<Details: AsyncOpStatusDetails> {
+    /// In callback-based synchronization, all the client can do is use the
+    /// side channels (to cancel or pause the operation, send it control
+    /// messages, or consume its partial results)...
+    channels: ClientChannels,
+
+    /// ...or subscribe to its status updates
+    subscribers: Subscribers<Details>,
+}
+//
+impl<Details> AsyncOpClient<Details>
+    where Details: AsyncOpCloneableDetails + 'static
+{
+    /// Schedule an additional callback to be executed on every subsequent
+    /// status update, and return a client for the same operation
+    ///
+    /// All clients share control over the operation: any of them can cancel
+    /// or pause it, or send it control messages. Partial results are shared
+    /// too, so each one of them is consumed by only one client.
+    ///
+    pub fn subscribe<F, Executor>(&self,
+                                  callback: F,
+                                  executor: &mut Executor) -> Self
+        where F: Fn(AsyncOpStatus<Details>) + Send + 'static,
+              Executor: SendCallbackExecutor,
+              Executor::SendChannel: 'static
+    {
+        let mut channel = executor.setup_send_callback(callback);
+        self.subscribers.lock().unwrap().push(Box::new(
+            move |status: &AsyncOpStatus<Details>| {
+                channel.notify(status.clone())
+            }
+        ));
+        AsyncOpClient {
+            channels: self.channels.clone(),
+            subscribers: self.subscribers.clone(),
+        }
+    }
 }
 //
-impl IAsyncOpClient for AsyncOpClient {
+impl<Details: AsyncOpStatusDetails> IAsyncOpClient for AsyncOpClient<Details> {
     /// Request the cancellation of the active asynchronous operation
     fn cancel(&mut self) {
-        self.cancelled.store(true, Ordering::Release);
+        self.channels.cancellation().cancel();
+    }
+
+    /// Request the active asynchronous operation to pause
+    fn pause(&mut self) {
+        self.channels.pause_flag().pause();
+    }
+
+    /// Request a paused asynchronous operation to resume
+    fn resume(&mut self) {
+        self.channels.pause_flag().resume();
     }
 }
+//
+impl<Details> IAsyncOpControlClient for AsyncOpClient<Details>
+    where Details: AsyncOpControlDetails
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Details;
+
+    /// Send a control message to the server of the asynchronous operation
+    fn send_control(&mut self, message: Details::ControlMessage) {
+        self.channels.control_channel().send(message);
+    }
+}
+//
+impl<Details> IAsyncOpStreamClient for AsyncOpClient<Details>
+    where Details: AsyncOpStreamDetails
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Details;
+
+    /// Consume the oldest partial result, if any, without blocking
+    fn try_next_item(&mut self) -> Option<Details::StreamItem> {
+        self.channels.stream_channel().try_next()
+    }
+
+    /// Consume the oldest partial result, blocking until one is available
+    fn next_item(&mut self) -> Option<Details::StreamItem> {
+        self.channels.stream_channel().wait_next()
+    }
+
+    /// Check whether all partial results have been consumed
+    fn stream_finished(&mut self) -> bool {
+        self.channels.stream_channel().is_finished()
+    }
+}
+
 
+/// Callbacks of the subscribers which were added after the operation's creation
+type Subscribers<Details> =
+    Arc<Mutex<Vec<Box<dyn FnMut(&AsyncOpStatus<Details>) + Send>>>>;
 
 
 /// Unit tests
@@ -132,6 +336,8 @@ mod tests {
     use status::{self, StandardAsyncOpStatus};
     use std::cell::Cell;
     use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
     /// Check the initial operation state
     #[test]
@@ -186,6 +392,65 @@ mod tests {
         assert!(server.cancelled());
         assert!(!called.get());
     }
+
+    /// Check that status updates are fanned out to every subscriber
+    #[test]
+    fn subscribers() {
+        // These callbacks will increment a counter if called
+        let counter = Arc::new(AtomicUsize::new(0));
+        let make_callback = || {
+            let c_counter = counter.clone();
+            move | s: StandardAsyncOpStatus | {
+                assert_eq!(s, status::DONE);
+                c_counter.fetch_add(1, Ordering::Relaxed);
+            }
+        };
+
+        // Check that every callback gets called on status updates
+        let mut executor = InlineCallbackExecutor::new();
+        let async_op =
+            new_async_op(make_callback(), &mut executor, status::PENDING);
+        let (mut server, client) = async_op.split();
+        let mut subscriber = client.subscribe(make_callback(), &mut executor);
+        client.subscribe(make_callback(), &mut executor);
+        server.update(status::DONE);
+        assert_eq!(counter.load(Ordering::Relaxed), 3);
+
+        // Any subscriber can cancel the operation
+        subscriber.cancel();
+        assert!(server.cancelled());
+    }
+
+    /// Check that callbacks can subscribe more callbacks
+    #[test]
+    fn reentrant_subscribe() {
+        let mut executor = InlineCallbackExecutor::new();
+        let async_op = new_async_op(|_: StandardAsyncOpStatus| {},
+                                    &mut executor,
+                                    status::PENDING);
+        let (mut server, client) = async_op.split();
+
+        // This callback subscribes a counting callback on every status update
+        let client = Arc::new(Mutex::new(client));
+        let counter = Arc::new(AtomicUsize::new(0));
+        let c_client = client.clone();
+        let c_counter = counter.clone();
+        let callback = move |_: StandardAsyncOpStatus| {
+            let c_counter = c_counter.clone();
+            let subscribed = move |_: StandardAsyncOpStatus| {
+                c_counter.fetch_add(1, Ordering::Relaxed);
+            };
+            let mut executor = InlineCallbackExecutor::new();
+            c_client.lock().unwrap().subscribe(subscribed, &mut executor);
+        };
+        client.lock().unwrap().subscribe(callback, &mut executor);
+
+        // New callbacks should only see the updates after their subscription
+        server.update(status::RUNNING);
+        assert_eq!(counter.load(Ordering::Relaxed), 0);
+        server.update(status::DONE);
+        assert_eq!(counter.load(Ordering::Relaxed), 1);
+    }
 }
 
 
@@ -0,0 +1,224 @@
+//! Fan-out of triple buffer writes to multiple readers
+//!
+//! Triple buffers only support a single reader, which is what makes them fast.
+//! To let several clients monitor the same operation, the server keeps one
+//! triple buffer per subscribed client, and writes every status update to all
+//! of them.
+//!
+//! Most operations only ever have one client, so until someone subscribes,
+//! the server writes to the first client's triple buffer directly, without
+//! taking any lock or cloning anything. Subscribing switches the publisher to
+//! a list of buffers which is protected by a mutex. That mutex is uncontended
+//! unless a client is subscribing, so reads remain wait-free.
+//!
+//! New subscribers start from the value that their parent subscriber can see.
+//! Subscribing waits for any direct write to complete, and all later writes
+//! are performed while holding the lock, so this is always the latest value.
+//! Buffers whose reader is gone are cleaned up on the next write.
+
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::thread;
+use triple_buffer::{TripleBuffer, TripleBufferInput, TripleBufferOutput};
+
+
+/// Create a publisher and its first subscriber, starting with some value
+pub fn new<T: Clone + Send>(initial: T) -> (Publisher<T>, Subscriber<T>) {
+    let (input, output) = TripleBuffer::new(initial).split();
+    let alive = Arc::new(());
+    let first = SubscriberInput { input, alive: Arc::downgrade(&alive) };
+    let shared = Arc::new(Shared {
+        mode: AtomicU8::new(SINGLE),
+        inputs: Mutex::new(Vec::new()),
+    });
+    (
+        Publisher { first: Some(first), shared: shared.clone() },
+        Subscriber { output, shared, _alive: alive },
+    )
+}
+
+
+/// Writing end, which sends values to every subscriber
+pub struct Publisher<T: Clone + Send> {
+    /// Writing end of the first subscriber's triple buffer, until the list of
+    /// subscribers is used
+    first: Option<SubscriberInput<T>>,
+
+    /// State shared with the subscribers
+    shared: Arc<Shared<T>>,
+}
+//
+impl<T: Clone + Send> Publisher<T> {
+    /// Send a new value to every subscriber
+    pub fn write(&mut self, value: T) {
+        // As long as nobody subscribed, write to the first buffer directly
+        if let Some(ref mut first) = self.first {
+            let claimed = self.shared.mode.compare_exchange(SINGLE,
+                                                            WRITING,
+                                                            Ordering::Acquire,
+                                                            Ordering::Relaxed);
+            if claimed.is_ok() {
+                first.input.write(value);
+                self.shared.mode.store(SINGLE, Ordering::Release);
+                return;
+            }
+        }
+
+        // Otherwise, write to every buffer in the list
+        let mut inputs = self.shared.inputs.lock().unwrap();
+        if let Some(first) = self.first.take() {
+            inputs.insert(0, first);
+        }
+        inputs.retain(|subscriber| subscriber.alive.strong_count() > 0);
+        if let Some((last, others)) = inputs.split_last_mut() {
+            for subscriber in others {
+                subscriber.input.write(value.clone());
+            }
+            last.input.write(value);
+        }
+    }
+
+    /// Create a new subscriber, starting from some value which the caller
+    /// knows to be the latest one
+    pub fn subscribe(&mut self, initial: T) -> Subscriber<T> {
+        self.shared.fan_out();
+        let mut inputs = self.shared.inputs.lock().unwrap();
+        Subscriber::register(&mut inputs, &self.shared, initial)
+    }
+}
+
+
+/// Reading end, which can spawn more reading ends
+pub struct Subscriber<T: Clone + Send> {
+    /// Reading end of this subscriber's triple buffer
+    output: TripleBufferOutput<T>,
+
+    /// State shared with the publisher
+    shared: Arc<Shared<T>>,
+
+    /// Tells the publisher that this subscriber is still around
+    _alive: Arc<()>,
+}
+//
+impl<T: Clone + Send> Subscriber<T> {
+    /// Access the latest value
+    pub fn read(&mut self) -> &T {
+        self.output.read()
+    }
+
+    /// Create a new subscriber, which starts from the latest value
+    pub fn subscribe(&mut self) -> Self {
+        self.shared.fan_out();
+        let mut inputs = self.shared.inputs.lock().unwrap();
+        let initial = self.output.read().clone();
+        Self::register(&mut inputs, &self.shared, initial)
+    }
+
+    /// Create a new subscriber and add it to a locked list of subscribers
+    fn register(locked_inputs: &mut Vec<SubscriberInput<T>>,
+                shared: &Arc<Shared<T>>,
+                initial: T) -> Self {
+        let (input, output) = TripleBuffer::new(initial).split();
+        let alive = Arc::new(());
+        locked_inputs.push(
+            SubscriberInput { input, alive: Arc::downgrade(&alive) }
+        );
+        Subscriber { output, shared: shared.clone(), _alive: alive }
+    }
+}
+
+
+/// The publisher writes to the first subscriber's buffer directly
+const SINGLE: u8 = 0;
+
+/// The publisher is writing to the first subscriber's buffer directly
+const WRITING: u8 = 1;
+
+/// The publisher writes to the list of subscribers' buffers
+const FANNED_OUT: u8 = 2;
+
+
+/// State shared by the publisher and its subscribers
+struct Shared<T: Clone + Send> {
+    /// Whether the publisher uses the list of buffers yet (see above)
+    mode: AtomicU8,
+
+    /// Writing ends of the subscribers' triple buffers, once they are used
+    inputs: Mutex<Vec<SubscriberInput<T>>>,
+}
+//
+impl<T: Clone + Send> Shared<T> {
+    /// Make the publisher use the list of buffers, after waiting for any
+    /// direct write to the first subscriber's buffer to complete
+    fn fan_out(&self) {
+        loop {
+            match self.mode.compare_exchange(SINGLE,
+                                             FANNED_OUT,
+                                             Ordering::Acquire,
+                                             Ordering::Acquire) {
+                Ok(_) | Err(FANNED_OUT) => return,
+                Err(_) => thread::yield_now(),
+            }
+        }
+    }
+}
+
+
+/// Writing end of a subscriber's triple buffer
+struct SubscriberInput<T: Clone + Send> {
+    /// Triple buffer input
+    input: TripleBufferInput<T>,
+
+    /// Liveness of the matching subscriber
+    alive: Weak<()>,
+}
+
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use multithread::fanout::*;
+
+    /// Check that a lone subscriber is written to without the lock
+    #[test]
+    fn single() {
+        let (mut publisher, mut first) = new(0);
+        publisher.write(1);
+        assert_eq!(*first.read(), 1);
+        assert!(publisher.first.is_some());
+        assert_eq!(publisher.shared.mode.load(Ordering::Relaxed), SINGLE);
+    }
+
+    /// Check that every subscriber sees every write
+    #[test]
+    fn fan_out() {
+        let (mut publisher, mut first) = new(0);
+        publisher.write(1);
+        let mut second = first.subscribe();
+        assert_eq!(*second.read(), 1);
+        publisher.write(2);
+        assert_eq!(*first.read(), 2);
+        assert_eq!(*second.read(), 2);
+        assert!(publisher.first.is_none());
+
+        // Late subscribers should start from the latest value
+        let mut third = second.subscribe();
+        assert_eq!(*third.read(), 2);
+        let mut fourth = publisher.subscribe(2);
+        publisher.write(3);
+        assert_eq!(*third.read(), 3);
+        assert_eq!(*fourth.read(), 3);
+    }
+
+    /// Check that buffers are cleaned up once their subscriber is gone
+    #[test]
+    fn cleanup() {
+        let (mut publisher, mut first) = new(0);
+        let second = first.subscribe();
+        ::std::mem::drop(second);
+        assert_eq!(publisher.shared.inputs.lock().unwrap().len(), 1);
+        publisher.write(1);
+        assert_eq!(publisher.shared.inputs.lock().unwrap().len(), 1);
+        assert_eq!(*first.read(), 1);
+    }
+}
@@ -0,0 +1,643 @@
+//! Hybrid asynchronous operation monitoring
+//!
+//! This module provides a way to monitor a single asynchronous operation using
+//! polling, blocking and callbacks at the same time, as is needed when for
+//! example a user interface polls for progress while another part of the
+//! application wants to be called back on completion.
+//!
+//! The status is stored in a mutex-protected shared state, as in the blocking
+//! module, and clients opt into each monitoring method lazily:
+//!
+//! - Blocking clients use this shared state directly. The server only signals
+//!   status updates when a client is actually waiting for one.
+//! - The first call to poll() sets up a triple buffer, through which the server
+//!   will keep that client up to date. Until then, no buffer is written to.
+//! - Callbacks are scheduled using on_update(). Until then, the server has no
+//!   callback to call.
+//!
+//! Since the status may be sent to several consumers, every part of the status
+//! details must be cloneable.
+//!
+//! When no client is waiting for a status update and no callback is scheduled,
+//! as is the case when clients only poll, the server does not even take the
+//! mutex: it writes to the triple buffers and stashes the status on the side.
+//! The next client which locks the shared state takes the server off this
+//! fast path, and moves the stashed status into the shared state. Callbacks
+//! are invoked without holding the mutex, so they may schedule more callbacks.
+
+use cancellation::CancellationToken;
+use channels::{ClientChannels, OpChannels};
+use client::{IAsyncOpClient, IAsyncOpControlClient, IAsyncOpStatusClient,
+             IAsyncOpStreamClient, IAsyncOpWaitClient};
+use control::AsyncOpControlDetails;
+use executor::{AnyCallbackChannel, SendCallbackExecutor};
+use multithread::fanout::{self, Publisher, Subscriber};
+use server::{self, AsyncOpServerConfig, DynAsyncOpServer};
+use status::{self, AsyncOpCloneableDetails, AsyncOpStatus};
+use std::cell::UnsafeCell;
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::thread;
+use stream::AsyncOpStreamDetails;
+
+
+/// Asynchronous operation object
+pub struct AsyncOp<Details: AsyncOpCloneableDetails> {
+    /// Server interface used to submit status updates
+    server: AsyncOpServer<Details>,
+
+    /// Client interface used to monitor the operation status
+    client: AsyncOpClient<Details>,
+}
+//
+impl<Details: AsyncOpCloneableDetails> AsyncOp<Details> {
+    /// Create a new asynchronous operation object with some initial status
+    pub fn new(initial_status: AsyncOpStatus<Details>) -> Self {
+        Self::with_cancel_flag(initial_status, CancellationToken::new())
+    }
+
+    /// Create a new asynchronous operation object with some initial status,
+    /// which will be cancelled if the provided token is cancelled
+    pub fn with_token(initial_status: AsyncOpStatus<Details>,
+                      token: &CancellationToken) -> Self {
+        Self::with_cancel_flag(initial_status, token.child())
+    }
+
+    /// Create a new asynchronous operation object with some initial status and
+    /// a certain cancellation flag
+    fn with_cancel_flag(initial_status: AsyncOpStatus<Details>,
+                        cancelled: CancellationToken) -> Self {
+        // Start by building the shared state...
+        let shared = Arc::new(SharedState {
+            state: Mutex::new(HybridState {
+                status: initial_status,
+                generation: 1,
+                waiting: 0,
+                callbacks: Vec::new(),
+            }),
+            update_cv: Condvar::new(),
+            channels: OpChannels::with_token(cancelled),
+            mode: AtomicU8::new(LOCKED),
+            fast: UnsafeCell::new(FastPath {
+                pollers: None,
+                status: None,
+                updates: 0,
+            }),
+        });
+
+        // ...then build the client and server
+        let server = {
+            let state = shared.state.lock().unwrap();
+            AsyncOpServer::new(HybridServerConfig { shared: shared.clone() },
+                               &state.status)
+        };
+        let channels = ClientChannels::new(&shared.channels);
+        AsyncOp {
+            server,
+            client: AsyncOpClient {
+                shared,
+                channels,
+                read_generation: 0,
+                poller: None,
+            },
+        }
+    }
+
+    /// Split the asynchronous operation object into client and server
+    /// objects which can be respectively sent to client and server threads
+    pub fn split(self) -> (AsyncOpServer<Details>, AsyncOpClient<Details>) {
+        (self.server, self.client)
+    }
+}
+
+
+/// Server interface, used to send operation status updates to the client
+pub type AsyncOpServer<Details> =
+    server::AsyncOpServer<HybridServerConfig<Details>>;
+
+
+/// Servers can be converted into servers of unknown monitoring mode
+impl<Details: AsyncOpCloneableDetails + 'static> From<AsyncOpServer<Details>>
+    for DynAsyncOpServer<Details>
+{
+    /// Erase the monitoring mode of the server
+    fn from(server: AsyncOpServer<Details>) -> Self {
+        server.into_dyn()
+    }
+}
+
+
+/// Server configuration for hybrid operation monitoring
+pub struct HybridServerConfig<Details: AsyncOpCloneableDetails> {
+    /// Reference-counted shared state
+    shared: Arc<SharedState<Details>>,
+}
+//
+impl<Details: AsyncOpCloneableDetails> AsyncOpServerConfig
+    for HybridServerConfig<Details>
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Details;
+
+    /// Method used to send a status update to the client
+    fn update(&mut self, status: AsyncOpStatus<Details>) {
+        // Once the operation is over, the result stream is over as well
+        let shared = &*self.shared;
+        let is_final = status::is_final(&status);
+        if is_final {
+            shared.channels.close_stream();
+        }
+
+        // If nobody is waiting and no callback is scheduled, bypass the mutex
+        let claimed = shared.mode.compare_exchange(FAST,
+                                                   FAST_WRITING,
+                                                   Ordering::Acquire,
+                                                   Ordering::Relaxed);
+        if claimed.is_ok() {
+            // This is safe because nobody else accesses the fast path state
+            // while the server is writing on the fast path
+            let fast = unsafe { &mut *shared.fast.get() };
+            if let Some(ref mut pollers) = fast.pollers {
+                pollers.write(status.clone());
+            }
+            fast.status = Some(status);
+            fast.updates += 1;
+            shared.mode.store(FAST, Ordering::Release);
+            return;
+        }
+
+        // Otherwise, send the new status to the clients which opted into
+        // polling. This is safe because we hold the lock, and are not on the
+        // fast path, which only the server itself can get on.
+        let mut state = shared.state.lock().unwrap();
+        let fast = unsafe { &mut *shared.fast.get() };
+        if let Some(ref mut pollers) = fast.pollers {
+            pollers.write(status.clone());
+        }
+
+        // Keep the scheduled callbacks for later, along with a copy of the
+        // status if there are any...
+        let mut callbacks = mem::take(&mut state.callbacks);
+        let notified = if callbacks.is_empty() {
+            None
+        } else {
+            Some(status.clone())
+        };
+
+        // ...send the new status to the blocking clients, waking them up if
+        // they are waiting...
+        state.status = status;
+        state.generation += 1;
+        if state.waiting > 0 {
+            shared.update_cv.notify_all();
+        } else if callbacks.is_empty() {
+            shared.mode.store(FAST, Ordering::Release);
+        }
+        ::std::mem::drop(state);
+
+        // ...then call the callbacks without holding the lock, and schedule
+        // them again along with those which they scheduled, if any
+        if let Some(status) = notified {
+            for callback in &mut callbacks {
+                callback(&status);
+            }
+            if !is_final {
+                let mut state = shared.state.lock().unwrap();
+                let added = mem::replace(&mut state.callbacks, callbacks);
+                state.callbacks.extend(added);
+            }
+        }
+    }
+
+    /// Side channels shared with the client
+    fn channels(&self) -> &OpChannels {
+        &self.shared.channels
+    }
+}
+
+
+/// Client interface, used to synchronize with the operation status
+pub struct AsyncOpClient<Details: AsyncOpCloneableDetails> {
+    /// Reference-counted shared state
+    shared: Arc<SharedState<Details>>,
+
+    /// Side channels of the operation, through which the client is also
+    /// accounted for
+    channels: ClientChannels,
+
+    /// Generation of the last operation status which the client has read
+    read_generation: usize,
+
+    /// Triple buffer used for polling, once the client has opted into it
+    poller: Option<Subscriber<AsyncOpStatus<Details>>>,
+}
+//
+impl<Details: AsyncOpCloneableDetails> AsyncOpClient<Details> {
+    /// Access the current operation status without blocking the server, like
+    /// polling clients do
+    ///
+    /// The first call sets up a triple buffer, which the server will keep up
+    /// to date from then on. This does not affect the read state of blocking
+    /// queries.
+    ///
+    pub fn poll(&mut self) -> &AsyncOpStatus<Details> {
+        if self.poller.is_none() {
+            let state = self.shared.lock();
+            let latest = state.status.clone();
+
+            // This is safe because we hold the lock, and took the server off
+            // the fast path
+            let fast = unsafe { &mut *self.shared.fast.get() };
+            self.poller = Some(match fast.pollers {
+                Some(ref mut pollers) => pollers.subscribe(latest),
+                None => {
+                    let (pollers, poller) = fanout::new(latest);
+                    fast.pollers = Some(pollers);
+                    poller
+                }
+            });
+        }
+        self.poller.as_mut().unwrap().read()
+    }
+
+    /// Access the current operation status and mark it as read
+    pub fn status(&mut self) -> AsyncOpStatus<Details> {
+        let state = self.shared.lock();
+        self.read_generation = state.generation;
+        state.status.clone()
+    }
+
+    /// Wait for either a status update or a final operation status
+    pub fn wait(&mut self) -> AsyncOpStatus<Details> {
+        // Only wait if the current status was read and can still change
+        let mut state = self.shared.lock();
+        while state.generation == self.read_generation
+              && !status::is_final(&state.status) {
+            state.waiting += 1;
+            state = self.shared.update_cv.wait(state).unwrap();
+            state.waiting -= 1;
+        }
+
+        // Mark the current operation status as read
+        self.read_generation = state.generation;
+        state.status.clone()
+    }
+
+    /// Schedule a callback to be executed on every subsequent status update
+    ///
+    /// If the operation has already reached a final status, the callback is
+    /// instead executed once with that status, so that completion callbacks
+    /// are never missed.
+    ///
+    pub fn on_update<F, Executor>(&self, callback: F, executor: &mut Executor)
+        where F: Fn(AsyncOpStatus<Details>) + Send + 'static,
+              Executor: SendCallbackExecutor,
+              Executor::SendChannel: 'static,
+              Details: 'static
+    {
+        let mut channel = executor.setup_send_callback(callback);
+        let mut state = self.shared.lock();
+        if status::is_final(&state.status) {
+            channel.notify(state.status.clone());
+        } else {
+            state.callbacks.push(Box::new(
+                move |status: &AsyncOpStatus<Details>| {
+                    channel.notify(status.clone())
+                }
+            ));
+        }
+    }
+
+    /// Create another client for the same operation, with its own read state,
+    /// for which the current operation status is unread
+    ///
+    /// All clients share control over the operation: any of them can cancel
+    /// or pause it, or send it control messages. Partial results are shared
+    /// too, so each one of them is consumed by only one client.
+    ///
+    pub fn subscribe(&self) -> Self {
+        AsyncOpClient {
+            shared: self.shared.clone(),
+            channels: self.channels.clone(),
+            read_generation: 0,
+            poller: None,
+        }
+    }
+}
+//
+impl<Details: AsyncOpCloneableDetails> IAsyncOpClient
+    for AsyncOpClient<Details>
+{
+    /// Request the cancellation of the active asynchronous operation
+    fn cancel(&mut self) {
+        self.channels.cancellation().cancel();
+    }
+
+    /// Request the active asynchronous operation to pause
+    fn pause(&mut self) {
+        self.channels.pause_flag().pause();
+    }
+
+    /// Request a paused asynchronous operation to resume
+    fn resume(&mut self) {
+        self.channels.pause_flag().resume();
+    }
+}
+//
+impl<Details: AsyncOpCloneableDetails> IAsyncOpStatusClient
+    for AsyncOpClient<Details>
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Details;
+
+    /// Query the current asynchronous operation status, marking it as read
+    fn current_status(&mut self) -> AsyncOpStatus<Details> {
+        self.status()
+    }
+}
+
+//
+impl<Details: AsyncOpCloneableDetails> IAsyncOpWaitClient
+    for AsyncOpClient<Details>
+{
+    /// Wait for either a status update or a final operation status
+    fn wait(&mut self) -> AsyncOpStatus<Details> {
+        AsyncOpClient::wait(self)
+    }
+
+    /// Return the operation status if it was updated since it was last read
+    /// or if it is final, or None instead of blocking
+    fn try_wait(&mut self) -> Option<AsyncOpStatus<Details>> {
+        let state = self.shared.lock();
+        if state.generation == self.read_generation
+           && !status::is_final(&state.status) {
+            return None;
+        }
+        self.read_generation = state.generation;
+        Some(state.status.clone())
+    }
+}
+//
+impl<Details> IAsyncOpControlClient for AsyncOpClient<Details>
+    where Details: AsyncOpCloneableDetails + AsyncOpControlDetails
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Details;
+
+    /// Send a control message to the server of the asynchronous operation
+    fn send_control(&mut self, message: Details::ControlMessage) {
+        self.channels.control_channel().send(message);
+    }
+}
+//
+impl<Details> IAsyncOpStreamClient for AsyncOpClient<Details>
+    where Details: AsyncOpCloneableDetails + AsyncOpStreamDetails
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Details;
+
+    /// Consume the oldest partial result, if any, without blocking
+    fn try_next_item(&mut self) -> Option<Details::StreamItem> {
+        self.channels.stream_channel().try_next()
+    }
+
+    /// Consume the oldest partial result, blocking until one is available
+    fn next_item(&mut self) -> Option<Details::StreamItem> {
+        self.channels.stream_channel().wait_next()
+    }
+
+    /// Check whether all partial results have been consumed
+    fn stream_finished(&mut self) -> bool {
+        self.channels.stream_channel().is_finished()
+    }
+}
+
+
+/// State shared between the client and the server
+struct SharedState<Details: AsyncOpCloneableDetails> {
+    /// Operation status and subscribed consumers (mutex-protected)
+    state: Mutex<HybridState<Details>>,
+
+    /// Condition variable used to notify blocking clients about updates
+    update_cv: Condvar,
+
+    /// Side channels shared by the client and the server
+    channels: OpChannels,
+
+    /// Whether the server is on the fast path, and writing there
+    mode: AtomicU8,
+
+    /// State which the server accesses on the fast path. While it is off the
+    /// fast path, this state is protected by the mutex instead.
+    fast: UnsafeCell<FastPath<Details>>,
+}
+//
+impl<Details: AsyncOpCloneableDetails> SharedState<Details> {
+    /// Lock the shared state on behalf of a client, taking the server off the
+    /// fast path so that the state is up to date
+    fn lock(&self) -> MutexGuard<'_, HybridState<Details>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            match self.mode.compare_exchange(FAST,
+                                             LOCKED,
+                                             Ordering::Acquire,
+                                             Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(FAST_WRITING) => thread::yield_now(),
+                Err(_) => return state,
+            }
+        }
+
+        // Move the status which was stashed on the fast path, if any, into the
+        // shared state. This is safe because we hold the lock, and the server
+        // is off the fast path.
+        let fast = unsafe { &mut *self.fast.get() };
+        if let Some(status) = fast.status.take() {
+            state.status = status;
+            state.generation += fast.updates;
+            fast.updates = 0;
+        }
+        state
+    }
+}
+//
+// The fast path state is only accessed by the server on the fast path, or by
+// whoever holds the mutex while it is off the fast path, so the shared state
+// can be shared as long as the mutex-protected state could.
+unsafe impl<Details> Sync for SharedState<Details>
+    where Details: AsyncOpCloneableDetails,
+          AsyncOpStatus<Details>: Send
+{}
+
+
+/// The server updates the shared state while holding the mutex
+const LOCKED: u8 = 0;
+
+/// The server writes status updates without holding the mutex
+const FAST: u8 = 1;
+
+/// The server is writing a status update without holding the mutex
+const FAST_WRITING: u8 = 2;
+
+
+/// State which the server accesses on the fast path
+struct FastPath<Details: AsyncOpCloneableDetails> {
+    /// Triple buffers of the clients which opted into polling, if any
+    pollers: Option<Publisher<AsyncOpStatus<Details>>>,
+
+    /// Latest status sent on the fast path, if it was not moved into the
+    /// shared state yet
+    status: Option<AsyncOpStatus<Details>>,
+
+    /// Number of status updates sent on the fast path since then
+    updates: usize,
+}
+
+
+/// Operation status and subscribed consumers
+struct HybridState<Details: AsyncOpCloneableDetails> {
+    /// Current asynchronous operation status
+    status: AsyncOpStatus<Details>,
+
+    /// Number of status updates published so far, plus one
+    generation: usize,
+
+    /// Number of blocking clients which are waiting for a status update
+    waiting: usize,
+
+    /// Callbacks which were scheduled by clients
+    callbacks: Vec<StatusCallback<Details>>,
+}
+
+
+/// Callback which was scheduled by a client, and is fed status updates
+type StatusCallback<Details> = Box<dyn FnMut(&AsyncOpStatus<Details>) + Send>;
+
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use executor::inline::InlineCallbackExecutor;
+    use multithread::hybrid::*;
+    use std::sync::atomic::AtomicUsize;
+
+    /// Check whether clients opted into polling
+    fn has_pollers<Details: AsyncOpCloneableDetails>(
+        client: &AsyncOpClient<Details>
+    ) -> bool {
+        let _state = client.shared.lock();
+        // This is safe because we hold the lock, and the server is off the
+        // fast path
+        unsafe { (*client.shared.fast.get()).pollers.is_some() }
+    }
+
+    /// Check that monitoring methods are only set up when used
+    #[test]
+    fn lazy_opt_in() {
+        let (mut server, mut client) = AsyncOp::new(status::PENDING).split();
+        server.update(status::RUNNING);
+        assert!(!has_pollers(&client));
+        assert!(client.shared.lock().callbacks.is_empty());
+        assert_eq!(*client.poll(), status::RUNNING);
+        assert!(has_pollers(&client));
+    }
+
+    /// Check that status updates bypass the mutex when possible, without
+    /// being hidden from blocking clients
+    #[test]
+    fn fast_path() {
+        let (mut server, mut client) = AsyncOp::new(status::PENDING).split();
+        assert_eq!(client.status(), status::PENDING);
+        assert_eq!(*client.poll(), status::PENDING);
+        server.update(status::RUNNING);
+        assert_eq!(client.shared.mode.load(Ordering::Relaxed), FAST);
+
+        // Pollers should see updates sent on the fast path...
+        server.update(status::DONE);
+        assert_eq!(*client.poll(), status::DONE);
+
+        // ...and so should blocking clients, once the server is off it
+        assert_eq!(client.wait(), status::DONE);
+        assert_eq!(client.shared.mode.load(Ordering::Relaxed), LOCKED);
+        assert_eq!(client.try_wait(), Some(status::DONE));
+    }
+
+    /// Check that callbacks can schedule more callbacks
+    #[test]
+    fn reentrant_callback() {
+        let (mut server, client) = AsyncOp::new(status::PENDING).split();
+        let client = Arc::new(Mutex::new(client));
+        let counter = Arc::new(AtomicUsize::new(0));
+
+        // This callback schedules a counting callback on every status update
+        let c_client = client.clone();
+        let c_counter = counter.clone();
+        let callback = move |_| {
+            let c_counter = c_counter.clone();
+            let scheduled = move |_| {
+                c_counter.fetch_add(1, Ordering::Relaxed);
+            };
+            let mut executor = InlineCallbackExecutor::new();
+            c_client.lock().unwrap().on_update(scheduled, &mut executor);
+        };
+        let mut executor = InlineCallbackExecutor::new();
+        client.lock().unwrap().on_update(callback, &mut executor);
+
+        // New callbacks should only see the updates after they were scheduled,
+        // unless they were scheduled after the final one
+        server.update(status::RUNNING);
+        assert_eq!(counter.load(Ordering::Relaxed), 0);
+        server.update(status::DONE);
+        assert_eq!(counter.load(Ordering::Relaxed), 2);
+    }
+
+    /// Check that every monitoring method sees status updates
+    #[test]
+    fn all_methods() {
+        let (mut server, mut poller) = AsyncOp::new(status::PENDING).split();
+        let mut waiter = poller.subscribe();
+        assert_eq!(*poller.poll(), status::PENDING);
+        assert_eq!(waiter.wait(), status::PENDING);
+
+        // This callback will count the status updates
+        let counter = Arc::new(AtomicUsize::new(0));
+        let c_counter = counter.clone();
+        let mut executor = InlineCallbackExecutor::new();
+        poller.on_update(move |_| {
+                             c_counter.fetch_add(1, Ordering::Relaxed);
+                         },
+                         &mut executor);
+
+        // Send status updates while the blocking client is waiting
+        let worker = thread::spawn(move || waiter.wait());
+        server.update(status::DONE);
+        assert_eq!(worker.join().unwrap(), status::DONE);
+        assert_eq!(*poller.poll(), status::DONE);
+        assert_eq!(counter.load(Ordering::Relaxed), 1);
+    }
+
+    /// Check that callbacks scheduled after completion are called anyway
+    #[test]
+    fn late_callback() {
+        let (mut server, client) = AsyncOp::new(status::RUNNING).split();
+        server.update(status::DONE);
+        let counter = Arc::new(AtomicUsize::new(0));
+        let c_counter = counter.clone();
+        let mut executor = InlineCallbackExecutor::new();
+        client.on_update(move |s| {
+                             assert_eq!(s, status::DONE);
+                             c_counter.fetch_add(1, Ordering::Relaxed);
+                         },
+                         &mut executor);
+        assert_eq!(counter.load(Ordering::Relaxed), 1);
+    }
+
+    /// Check that cancellation works as expected
+    #[test]
+    fn cancelation() {
+        let (server, mut client) = AsyncOp::new(status::PENDING).split();
+        client.cancel();
+        assert!(server.cancelled());
+    }
+}
@@ -11,12 +11,27 @@
 //!   updates. One possible use case is refreshing UI controls.
 //! - Blocking allows a client to wait for status updates. Although easy to use
 //!   and reason about, this synchronization method should be used sparingly as
-//!   it can have a strong averse effect on application performance
+//!   it can have a strong averse effect on application performance. It comes
+//!   in two flavors: a simple mutex-based one, and a lock-free one based on
+//!   thread parking, which is better suited to frequent status updates.
 //! - Callbacks allow a client to schedule code to be executed whenever the
 //!   operation status is updated. This is the most general and powerful
 //!   synchronization mechanism, but also the most complex one.
+//!
+//! A hybrid mode is also available for operations which must be monitored in
+//! several of these ways at once, each of them being set up on first use.
+//!
+//! In every mode, several clients can monitor the same operation, by calling
+//! the subscribe() method of an existing client. Each client tracks status
+//! updates independently, but they all share control over the operation: any
+//! of them may cancel it, and cancellation cannot be undone by the others.
+//! Clients which only want to stop monitoring the operation should be dropped
+//! instead, which does not affect the server or the other clients.
 
 
 pub mod blocking;
 pub mod callback;
+mod fanout;
+pub mod hybrid;
+pub mod parking;
 pub mod polling;
@@ -0,0 +1,474 @@
+//! Lock-free blocking-based asynchronous operation monitoring
+//!
+//! This module provides the same interface as the blocking module, with a
+//! different implementation which is better suited to servers that publish
+//! frequent status updates. Instead of serializing all accesses to the status
+//! through a mutex, the status is shared through a triple buffer, as in the
+//! polling module, and clients which wait for updates are put to sleep using
+//! thread parking.
+//!
+//! Status updates are thus cheap on the server side: they amount to a triple
+//! buffer write, a generation counter increment, and, if the client is asleep,
+//! an unpark operation. The generation counter also serves as the read
+//! bit: a status is unread if the counter has moved since the client last read
+//! the status. In exchange, every part of the status details must be cloneable.
+//!
+//! Additional clients can be created with subscribe(). Each of them has its own
+//! triple buffer and its own read generation, and waiting clients are kept in
+//! a lock-free list so that a status update wakes all of them up. Once there
+//! is more than one client, the list of triple buffers is protected by a lock,
+//! which the server takes on every update, but which is only contended while
+//! a client is subscribing. Operations with a single client do not pay for it.
+
+use cancellation::CancellationToken;
+use channels::{ClientChannels, OpChannels};
+use client::{IAsyncOpClient, IAsyncOpControlClient, IAsyncOpStatusClient,
+             IAsyncOpStreamClient, IAsyncOpWaitClient};
+use control::AsyncOpControlDetails;
+use multithread::fanout::{self, Publisher, Subscriber};
+use server::{self, AsyncOpServerConfig, DynAsyncOpServer};
+use status::{self, AsyncOpCloneableDetails, AsyncOpStatus};
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::thread::{self, Thread};
+use stream::AsyncOpStreamDetails;
+
+
+/// Asynchronous operation object
+pub struct AsyncOp<Details: AsyncOpCloneableDetails> {
+    /// Server interface used to submit status updates
+    server: AsyncOpServer<Details>,
+
+    /// Client interface used to monitor the operation status
+    client: AsyncOpClient<Details>,
+}
+//
+impl<Details: AsyncOpCloneableDetails> AsyncOp<Details> {
+    /// Create a new asynchronous operation object with some initial status
+    pub fn new(initial_status: AsyncOpStatus<Details>) -> Self {
+        Self::with_cancel_flag(initial_status, CancellationToken::new())
+    }
+
+    /// Create a new asynchronous operation object with some initial status,
+    /// which will be cancelled if the provided token is cancelled
+    pub fn with_token(initial_status: AsyncOpStatus<Details>,
+                      token: &CancellationToken) -> Self {
+        Self::with_cancel_flag(initial_status, token.child())
+    }
+
+    /// Create a new asynchronous operation object with some initial status and
+    /// a certain cancellation flag
+    fn with_cancel_flag(initial_status: AsyncOpStatus<Details>,
+                        cancelled: CancellationToken) -> Self {
+        // Setup triple buffer-based status communication...
+        let (buf_input, mut buf_output) = fanout::new(initial_status);
+
+        // ...along with the rest of the shared state. The initial status is
+        // unread, since the client's generation lags behind.
+        let shared = Arc::new(SharedState {
+            generation: AtomicUsize::new(1),
+            waiters: AtomicPtr::new(ptr::null_mut()),
+            channels: OpChannels::with_token(cancelled),
+        });
+
+        // ...then build the client and server
+        let server = AsyncOpServer::new(
+            ParkingServerConfig { buf_input, shared: shared.clone() },
+            buf_output.read()
+        );
+        let channels = ClientChannels::new(&shared.channels);
+        AsyncOp {
+            server,
+            client: AsyncOpClient {
+                buf_output,
+                shared,
+                channels,
+                read_generation: 0,
+            },
+        }
+    }
+
+    /// Split the asynchronous operation object into client and server
+    /// objects which can be respectively sent to client and server threads
+    pub fn split(self) -> (AsyncOpServer<Details>, AsyncOpClient<Details>) {
+        (self.server, self.client)
+    }
+}
+
+
+/// Server interface, used to send operation status updates to the client
+pub type AsyncOpServer<Details> =
+    server::AsyncOpServer<ParkingServerConfig<Details>>;
+
+
+/// Servers can be converted into servers of unknown monitoring mode
+impl<Details: AsyncOpCloneableDetails + 'static> From<AsyncOpServer<Details>>
+    for DynAsyncOpServer<Details>
+{
+    /// Erase the monitoring mode of the server
+    fn from(server: AsyncOpServer<Details>) -> Self {
+        server.into_dyn()
+    }
+}
+
+
+/// Server configuration for lock-free blocking operation monitoring
+pub struct ParkingServerConfig<Details: AsyncOpCloneableDetails> {
+    /// New operation statuses will be sent through these triple buffers
+    buf_input: Publisher<AsyncOpStatus<Details>>,
+
+    /// Reference-counted shared state
+    shared: Arc<SharedState>,
+}
+//
+impl<Details: AsyncOpCloneableDetails> AsyncOpServerConfig
+    for ParkingServerConfig<Details>
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Details;
+
+    /// Method used to send a status update to the client
+    fn update(&mut self, status: AsyncOpStatus<Details>) {
+        // Once the operation is over, the result stream is over as well
+        if status::is_final(&status) {
+            self.shared.channels.close_stream();
+        }
+
+        // Publish the new operation status and mark it as unread
+        self.buf_input.write(status);
+        self.shared.generation.fetch_add(1, Ordering::SeqCst);
+
+        // Wake up the clients which are waiting for this update
+        for waiter in self.shared.take_waiters() {
+            waiter.unpark();
+        }
+    }
+
+    /// Side channels shared with the client
+    fn channels(&self) -> &OpChannels {
+        &self.shared.channels
+    }
+}
+
+
+/// Client interface, used to synchronize with the operation status
+pub struct AsyncOpClient<Details: AsyncOpCloneableDetails> {
+    /// Current operation status will be read through this triple buffer
+    buf_output: Subscriber<AsyncOpStatus<Details>>,
+
+    /// Reference-counted shared state
+    shared: Arc<SharedState>,
+
+    /// Side channels of the operation, through which the client is also
+    /// accounted for
+    channels: ClientChannels,
+
+    /// Generation of the last operation status which the client has read
+    read_generation: usize,
+}
+//
+impl<Details: AsyncOpCloneableDetails> AsyncOpClient<Details> {
+    /// Access the current operation status and mark it as read
+    pub fn status(&mut self) -> AsyncOpStatus<Details> {
+        self.status_ref().clone()
+    }
+
+    /// Wait for either a status update or a final operation status
+    pub fn wait(&mut self) -> AsyncOpStatus<Details> {
+        self.wait_ref().clone()
+    }
+
+    /// Borrow the current operation status in place and mark it as read
+    pub fn status_ref(&mut self) -> &AsyncOpStatus<Details> {
+        // The generation must be read first, so that if an update comes in
+        // between, the client sees the new status but does not mark it as read
+        self.read_generation = self.shared.generation.load(Ordering::SeqCst);
+        self.buf_output.read()
+    }
+
+    /// Wait for either a status update or a final operation status, then
+    /// borrow it in place like status_ref() does
+    pub fn wait_ref(&mut self) -> &AsyncOpStatus<Details> {
+        // Only wait if the current status was read and can still change
+        while !self.unread() && !status::is_final(self.buf_output.read()) {
+            self.park();
+        }
+
+        // Mark the current operation status as read
+        self.status_ref()
+    }
+
+    /// Create another client for the same operation, with its own read
+    /// generation, for which the current operation status is unread
+    ///
+    /// All clients share control over the operation: any of them can cancel
+    /// or pause it, or send it control messages. Partial results are shared
+    /// too, so each one of them is consumed by only one client.
+    ///
+    pub fn subscribe(&mut self) -> Self {
+        AsyncOpClient {
+            buf_output: self.buf_output.subscribe(),
+            shared: self.shared.clone(),
+            channels: self.channels.clone(),
+            read_generation: 0,
+        }
+    }
+
+    /// Check whether the server has published an unread status
+    fn unread(&self) -> bool {
+        self.shared.generation.load(Ordering::SeqCst) != self.read_generation
+    }
+
+    /// Sleep until the server publishes a status update, or spuriously
+    fn park(&mut self) {
+        // Tell the server that we are about to sleep
+        self.shared.push_waiter(thread::current());
+
+        // If an update came in while we were registering, don't sleep. This
+        // check must come after registration, so that the server either sees
+        // our registration or we see its update.
+        //
+        // Our registration cannot be withdrawn afterwards, as other clients
+        // may be registered after it. If we wake up spuriously, the server
+        // will thus unpark us once more on its next update, which is harmless.
+        //
+        if !self.unread() {
+            thread::park();
+        }
+    }
+}
+//
+impl<Details: AsyncOpCloneableDetails> IAsyncOpClient
+    for AsyncOpClient<Details>
+{
+    /// Request the cancellation of the active asynchronous operation
+    fn cancel(&mut self) {
+        self.channels.cancellation().cancel();
+    }
+
+    /// Request the active asynchronous operation to pause
+    fn pause(&mut self) {
+        self.channels.pause_flag().pause();
+    }
+
+    /// Request a paused asynchronous operation to resume
+    fn resume(&mut self) {
+        self.channels.pause_flag().resume();
+    }
+}
+//
+impl<Details: AsyncOpCloneableDetails> IAsyncOpStatusClient
+    for AsyncOpClient<Details>
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Details;
+
+    /// Query the current asynchronous operation status, marking it as read
+    fn current_status(&mut self) -> AsyncOpStatus<Details> {
+        self.status()
+    }
+}
+
+//
+impl<Details: AsyncOpCloneableDetails> IAsyncOpWaitClient
+    for AsyncOpClient<Details>
+{
+    /// Wait for either a status update or a final operation status
+    fn wait(&mut self) -> AsyncOpStatus<Details> {
+        AsyncOpClient::wait(self)
+    }
+
+    /// Return the operation status if it was updated since it was last read
+    /// or if it is final, or None instead of blocking
+    fn try_wait(&mut self) -> Option<AsyncOpStatus<Details>> {
+        if !self.unread() && !status::is_final(self.buf_output.read()) {
+            return None;
+        }
+        Some(self.status())
+    }
+}
+//
+impl<Details> IAsyncOpControlClient for AsyncOpClient<Details>
+    where Details: AsyncOpCloneableDetails + AsyncOpControlDetails
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Details;
+
+    /// Send a control message to the server of the asynchronous operation
+    fn send_control(&mut self, message: Details::ControlMessage) {
+        self.channels.control_channel().send(message);
+    }
+}
+//
+impl<Details> IAsyncOpStreamClient for AsyncOpClient<Details>
+    where Details: AsyncOpCloneableDetails + AsyncOpStreamDetails
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Details;
+
+    /// Consume the oldest partial result, if any, without blocking
+    fn try_next_item(&mut self) -> Option<Details::StreamItem> {
+        self.channels.stream_channel().try_next()
+    }
+
+    /// Consume the oldest partial result, blocking until one is available
+    fn next_item(&mut self) -> Option<Details::StreamItem> {
+        self.channels.stream_channel().wait_next()
+    }
+
+    /// Check whether all partial results have been consumed
+    fn stream_finished(&mut self) -> bool {
+        self.channels.stream_channel().is_finished()
+    }
+}
+
+
+/// State shared between the client and the server, besides the status
+struct SharedState {
+    /// Number of status updates published so far, plus one
+    generation: AtomicUsize,
+
+    /// Client threads which are waiting for a status update, as a linked
+    /// list. This is either null or a pointer created by Box::into_raw.
+    waiters: AtomicPtr<Waiter>,
+
+    /// Side channels shared by the client and the server
+    channels: OpChannels,
+}
+//
+impl SharedState {
+    /// Add a client thread to the list of waiting threads
+    fn push_waiter(&self, thread: Thread) {
+        let waiter = Box::into_raw(Box::new(Waiter {
+            thread,
+            next: self.waiters.load(Ordering::SeqCst),
+        }));
+        loop {
+            // This is safe because we own the waiter until it is published
+            let next = unsafe { (*waiter).next };
+            match self.waiters.compare_exchange_weak(next,
+                                                     waiter,
+                                                     Ordering::SeqCst,
+                                                     Ordering::SeqCst) {
+                Ok(_) => return,
+                Err(actual) => unsafe { (*waiter).next = actual },
+            }
+        }
+    }
+
+    /// Take away the list of waiting client threads
+    fn take_waiters(&self) -> Vec<Thread> {
+        let mut waiter = self.waiters.swap(ptr::null_mut(), Ordering::SeqCst);
+        let mut waiters = Vec::new();
+        while !waiter.is_null() {
+            // This is safe because waiters are created by Box::into_raw, and
+            // we took the whole list away from the shared state, so we own it
+            let owned = unsafe { Box::from_raw(waiter) };
+            waiter = owned.next;
+            waiters.push(owned.thread);
+        }
+        waiters
+    }
+}
+//
+impl Drop for SharedState {
+    /// Dispose of the waiting client threads, if any
+    fn drop(&mut self) {
+        self.take_waiters();
+    }
+}
+
+
+/// Client thread which is waiting for a status update
+struct Waiter {
+    /// Handle used to wake up the thread
+    thread: Thread,
+
+    /// Next waiting thread, if any
+    next: *mut Waiter,
+}
+
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use multithread::parking::*;
+    use std::time::Duration;
+
+    /// Check that reading the operation status marks it as read
+    #[test]
+    fn read_bit() {
+        // The initial status should be unread
+        let (mut server, mut client) = AsyncOp::new(status::PENDING).split();
+        assert!(client.unread());
+        assert_eq!(client.status(), status::PENDING);
+        assert!(!client.unread());
+
+        // Updates should mark the status as unread again
+        server.update(status::RUNNING);
+        assert!(client.unread());
+        assert_eq!(client.wait(), status::RUNNING);
+        assert!(!client.unread());
+    }
+
+    /// Check that waiting clients are woken up by status updates
+    #[test]
+    fn wait() {
+        let (mut server, mut client) = AsyncOp::new(status::PENDING).split();
+        assert_eq!(client.wait(), status::PENDING);
+        let worker = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            server.update(status::DONE);
+        });
+        assert_eq!(client.wait(), status::DONE);
+
+        // Waiting on a final status should not block
+        assert_eq!(client.wait(), status::DONE);
+        worker.join().unwrap();
+    }
+
+    /// Check that no update is missed by a client which is frequently waiting
+    #[test]
+    fn frequent_updates() {
+        let (mut server, mut client) = AsyncOp::new(status::PENDING).split();
+        let worker = thread::spawn(move || {
+            for _ in 0..10000 {
+                server.update(status::RUNNING);
+            }
+            server.update(status::DONE);
+        });
+        while !status::is_final(&client.wait()) {}
+        worker.join().unwrap();
+    }
+
+    /// Check that cancellation works as expected
+    #[test]
+    fn cancelation() {
+        let (server, mut client) = AsyncOp::new(status::PENDING).split();
+        client.cancel();
+        assert!(server.cancelled());
+    }
+
+    /// Check that subscribers have their own read state, and are all woken up
+    /// by status updates
+    #[test]
+    fn subscribers() {
+        let (mut server, mut client) = AsyncOp::new(status::PENDING).split();
+        assert_eq!(client.status(), status::PENDING);
+        let mut subscriber = client.subscribe();
+        assert!(subscriber.unread());
+        assert!(!client.unread());
+        assert_eq!(subscriber.status(), status::PENDING);
+
+        // Every waiting subscriber should be woken up
+        let waiters = vec![client, subscriber].into_iter().map(|mut client| {
+            thread::spawn(move || client.wait())
+        }).collect::<Vec<_>>();
+        thread::sleep(Duration::from_millis(10));
+        server.update(status::DONE);
+        for waiter in waiters {
+            assert_eq!(waiter.join().unwrap(), status::DONE);
+        }
+    }
+}
@@ -5,17 +5,28 @@
 //! not need to synchronize with asynchronous operation status updates, but only
 //! to periodically check the status, as is the case for example when updating
 //! progress bars and status graphs in user interfaces.
+//!
+//! Since the status is shared through a triple buffer, which keeps several
+//! copies of it, every part of the status details must be cloneable.
+//!
+//! Additional clients can be created with subscribe(). Each of them gets its
+//! own triple buffer, which the server keeps up to date along with the others.
+//! Until then, status updates are plain triple buffer writes, but from then
+//! on, the server takes a mostly uncontended lock to reach every buffer.
 
-use client::IAsyncOpClient;
-use server::{self, AsyncOpServerConfig};
-use status::{AsyncOpStatus, AsyncOpStatusDetails};
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
-use triple_buffer::{TripleBuffer, TripleBufferInput, TripleBufferOutput};
+use cancellation::CancellationToken;
+use channels::{ClientChannels, OpChannels};
+use client::{IAsyncOpClient, IAsyncOpControlClient, IAsyncOpStatusClient,
+             IAsyncOpStreamClient};
+use control::AsyncOpControlDetails;
+use multithread::fanout::{self, Publisher, Subscriber};
+use server::{self, AsyncOpServerConfig, DynAsyncOpServer};
+use status::{self, AsyncOpCloneableDetails, AsyncOpStatus};
+use stream::AsyncOpStreamDetails;
 
 
 /// Asynchronous operation object
-pub struct AsyncOp<Details: AsyncOpStatusDetails> {
+pub struct AsyncOp<Details: AsyncOpCloneableDetails> {
     /// Server interface used to submit status updates
     server: AsyncOpServer<Details>,
 
@@ -23,31 +34,42 @@ pub struct AsyncOp<Details: AsyncOpStatusDetails> {
     client: AsyncOpClient<Details>,
 }
 //
-impl<Details: AsyncOpStatusDetails> AsyncOp<Details> {
+impl<Details: AsyncOpCloneableDetails> AsyncOp<Details> {
     /// Create a new asynchronous operation object with some initial status
     pub fn new(initial_status: AsyncOpStatus<Details>) -> Self {
+        Self::with_cancel_flag(initial_status, CancellationToken::new())
+    }
+
+    /// Create a new asynchronous operation object with some initial status,
+    /// which will be cancelled if the provided token is cancelled
+    pub fn with_token(initial_status: AsyncOpStatus<Details>,
+                      token: &CancellationToken) -> Self {
+        Self::with_cancel_flag(initial_status, token.child())
+    }
+
+    /// Create a new asynchronous operation object with some initial status and
+    /// a certain cancellation flag
+    fn with_cancel_flag(initial_status: AsyncOpStatus<Details>,
+                        cancel_flag: CancellationToken) -> Self {
         // Keep a copy of the initial operation status
         let initial_status_copy = initial_status.clone();
 
         // Setup triple buffer-based client/server communication...
-        let buffer = TripleBuffer::new(initial_status);
-        let (buf_input, buf_output) = buffer.split();
-
-        // ...and a shared cancellation flag...
-        let cancel_flag = Arc::new(AtomicBool::new(false));
+        let (buf_input, buf_output) = fanout::new(initial_status);
+        let channels = OpChannels::with_token(cancel_flag);
 
         // ...then build the client and server
         AsyncOp {
             server: AsyncOpServer::new(
                 PollingServerConfig {
-                    buf_input: buf_input,
-                    cancelled: cancel_flag.clone(),
+                    buf_input,
+                    channels: channels.clone(),
                 },
                 &initial_status_copy
             ),
             client: AsyncOpClient {
-                buf_output: buf_output,
-                cancelled: cancel_flag,
+                buf_output,
+                channels: ClientChannels::new(&channels),
             },
         }
     }
@@ -61,20 +83,31 @@ impl<Details: AsyncOpStatusDetails> AsyncOp<Details> {
 
 
 /// Server interface, used to send operation status updates to the client
-pub type AsyncOpServer<Details: AsyncOpStatusDetails> =
+pub type AsyncOpServer<Details> =
     server::AsyncOpServer<PollingServerConfig<Details>>;
 
 
+/// Servers can be converted into servers of unknown monitoring mode
+impl<Details: AsyncOpCloneableDetails + 'static> From<AsyncOpServer<Details>>
+    for DynAsyncOpServer<Details>
+{
+    /// Erase the monitoring mode of the server
+    fn from(server: AsyncOpServer<Details>) -> Self {
+        server.into_dyn()
+    }
+}
+
+
 /// Server configuration for polling-based operation monitoring
-pub struct PollingServerConfig<Details: AsyncOpStatusDetails> {
-    /// New operation statuses will be sent through this triple buffer
-    buf_input: TripleBufferInput<AsyncOpStatus<Details>>,
+pub struct PollingServerConfig<Details: AsyncOpCloneableDetails> {
+    /// New operation statuses will be sent through these triple buffers
+    buf_input: Publisher<AsyncOpStatus<Details>>,
 
-    /// In addition, the client & server also share a cancellation flag
-    cancelled: Arc<AtomicBool>,
+    /// In addition, the client & server also share some side channels
+    channels: OpChannels,
 }
 //
-impl<Details: AsyncOpStatusDetails> AsyncOpServerConfig
+impl<Details: AsyncOpCloneableDetails> AsyncOpServerConfig
     for PollingServerConfig<Details>
 {
     /// Implementation details of the asynchronous operation status
@@ -82,36 +115,112 @@ impl<Details: AsyncOpStatusDetails> AsyncOpServerConfig
 
     /// Method used to send a status update to the client
     fn update(&mut self, status: AsyncOpStatus<Details>) {
+        let is_final = status::is_final(&status);
         self.buf_input.write(status);
+        if is_final {
+            self.channels.close_stream();
+        }
     }
 
-    /// Method used to query whether the client has cancelled the operation
-    fn cancelled(&self) -> bool {
-        self.cancelled.load(Ordering::Acquire)
+    /// Side channels shared with the client
+    fn channels(&self) -> &OpChannels {
+        &self.channels
     }
 }
 
 
 /// Client interface, used to synchronize with the operation status
-pub struct AsyncOpClient<Details: AsyncOpStatusDetails> {
+pub struct AsyncOpClient<Details: AsyncOpCloneableDetails> {
     /// Current operation status will be read through this triple buffer
-    buf_output: TripleBufferOutput<AsyncOpStatus<Details>>,
+    buf_output: Subscriber<AsyncOpStatus<Details>>,
 
-    /// In addition, the client & server also share a cancellation flag
-    cancelled: Arc<AtomicBool>,
+    /// In addition, the client & server also share some side channels
+    channels: ClientChannels,
 }
 //
-impl<Details: AsyncOpStatusDetails> AsyncOpClient<Details> {
+impl<Details: AsyncOpCloneableDetails> AsyncOpClient<Details> {
     /// Access the current asynchronous operation status
     pub fn status(&mut self) -> &AsyncOpStatus<Details> {
         self.buf_output.read()
     }
+
+    /// Create another client for the same operation, which starts from the
+    /// latest operation status and is kept up to date independently
+    ///
+    /// All clients share control over the operation: any of them can cancel
+    /// or pause it, or send it control messages. Partial results are shared
+    /// too, so each one of them is consumed by only one client.
+    ///
+    pub fn subscribe(&mut self) -> Self {
+        AsyncOpClient {
+            buf_output: self.buf_output.subscribe(),
+            channels: self.channels.clone(),
+        }
+    }
 }
 //
-impl<Details: AsyncOpStatusDetails> IAsyncOpClient for AsyncOpClient<Details> {
+impl<Details> IAsyncOpClient for AsyncOpClient<Details>
+    where Details: AsyncOpCloneableDetails
+{
     /// Request the cancellation of the active asynchronous operation
     fn cancel(&mut self) {
-        self.cancelled.store(true, Ordering::Release);
+        self.channels.cancellation().cancel();
+    }
+
+    /// Request the active asynchronous operation to pause
+    fn pause(&mut self) {
+        self.channels.pause_flag().pause();
+    }
+
+    /// Request a paused asynchronous operation to resume
+    fn resume(&mut self) {
+        self.channels.pause_flag().resume();
+    }
+}
+//
+impl<Details> IAsyncOpControlClient for AsyncOpClient<Details>
+    where Details: AsyncOpCloneableDetails + AsyncOpControlDetails
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Details;
+
+    /// Send a control message to the server of the asynchronous operation
+    fn send_control(&mut self, message: Details::ControlMessage) {
+        self.channels.control_channel().send(message);
+    }
+}
+//
+impl<Details> IAsyncOpStreamClient for AsyncOpClient<Details>
+    where Details: AsyncOpCloneableDetails + AsyncOpStreamDetails
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Details;
+
+    /// Consume the oldest partial result, if any, without blocking
+    fn try_next_item(&mut self) -> Option<Details::StreamItem> {
+        self.channels.stream_channel().try_next()
+    }
+
+    /// Consume the oldest partial result, blocking until one is available
+    fn next_item(&mut self) -> Option<Details::StreamItem> {
+        self.channels.stream_channel().wait_next()
+    }
+
+    /// Check whether all partial results have been consumed
+    fn stream_finished(&mut self) -> bool {
+        self.channels.stream_channel().is_finished()
+    }
+}
+//
+impl<Details: AsyncOpCloneableDetails> IAsyncOpStatusClient
+    for AsyncOpClient<Details>
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Details;
+
+    /// Query the current asynchronous operation status
+    fn current_status(&mut self) -> AsyncOpStatus<Details> {
+        self.status().clone()
     }
 }
 
@@ -158,6 +267,23 @@ mod tests {
         client.cancel();
         assert!(server.cancelled());
     }
+
+    /// Check that every subscriber sees status updates, and can cancel
+    #[test]
+    fn subscribers() {
+        let (mut server, mut client) = AsyncOp::new(status::PENDING).split();
+        let mut subscriber = client.subscribe();
+        server.update(status::RUNNING);
+        assert_eq!(*client.status(), status::RUNNING);
+        assert_eq!(*subscriber.status(), status::RUNNING);
+
+        // Dropping a subscriber should not affect the others
+        ::std::mem::drop(client);
+        server.update(status::DONE);
+        assert_eq!(*subscriber.status(), status::DONE);
+        subscriber.cancel();
+        assert!(server.cancelled());
+    }
 }
 
 
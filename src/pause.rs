@@ -0,0 +1,201 @@
+//! Pausing and resuming asynchronous operations
+//!
+//! Besides cancelling an operation, a client may want to temporarily suspend
+//! it, for example in order to free up a machine for interactive work while a
+//! long-running batch job is in progress. Like cancellation, pausing is a mere
+//! request which servers should honor whenever they reasonably can.
+//!
+//! Clients request a pause or a resumption through the pause() and resume()
+//! methods of IAsyncOpClient. Servers can either check whether a pause has
+//! been requested, or call checkpoint() at points where they may be suspended,
+//! which blocks them until the operation is resumed or cancelled. While
+//! blocked there, the operation reports a running status built from a
+//! PauseState, so that the client can tell that the pause took effect.
+
+use cancellation::{CancelWakeups, CancellationToken};
+use status::{self, AsyncOpStatusTraits, NoDetails};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+
+/// Paused-ness of an operation, reported by servers at checkpoints
+///
+/// Operations which are paused at a checkpoint report this information through
+/// their running status details, which must therefore be constructible from
+/// it. Once the operation is resumed, the same is done with `paused` unset.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PauseState {
+    /// Whether the operation is currently paused
+    pub paused: bool,
+}
+//
+impl AsyncOpStatusTraits for PauseState {}
+//
+impl From<PauseState> for NoDetails {
+    /// Standard statuses simply discard pause information
+    fn from(_: PauseState) -> Self {
+        status::NO_DETAILS
+    }
+}
+
+
+/// Flag used by clients to request that an operation be paused
+#[derive(Clone, Debug, Default)]
+pub struct PauseFlag {
+    /// Shared state of the flag
+    shared: Arc<PauseShared>,
+}
+//
+impl PauseFlag {
+    /// Create a new flag, with no pause requested
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request the operation to pause
+    pub fn pause(&self) {
+        self.set(true);
+    }
+
+    /// Request the operation to resume
+    pub fn resume(&self) {
+        self.set(false);
+    }
+
+    /// Check whether a pause is currently requested
+    pub fn is_paused(&self) -> bool {
+        self.shared.paused.load(Ordering::Acquire)
+    }
+
+    /// Block while a pause is requested, unless the token is cancelled, then
+    /// tell whether we had to wait
+    pub fn wait_resumed(&self, cancellation: &CancellationToken) -> bool {
+        // Avoid any synchronization overhead in the common case
+        if !self.is_paused() { return false; }
+
+        // Make sure that we will be woken up if the token is cancelled
+        let shared = self.shared.clone();
+        self.shared.wakeups.register(cancellation, move || {
+            let _lock = shared.lock.lock().unwrap();
+            shared.resume_cv.notify_all();
+        });
+
+        // Wait for the operation to be resumed or cancelled
+        let mut lock = self.shared.lock.lock().unwrap();
+        while self.is_paused() && !cancellation.is_cancelled() {
+            lock = self.shared.resume_cv.wait(lock).unwrap();
+        }
+        true
+    }
+
+    /// Set the value of the flag, waking up paused servers if needed
+    fn set(&self, paused: bool) {
+        let _lock = self.shared.lock.lock().unwrap();
+        self.shared.paused.store(paused, Ordering::Release);
+        self.shared.resume_cv.notify_all();
+    }
+}
+
+
+/// Shared state of a pause flag
+#[derive(Debug, Default)]
+struct PauseShared {
+    /// Whether a pause is requested
+    paused: AtomicBool,
+
+    /// Tokens whose cancellation wakes up paused servers
+    wakeups: CancelWakeups,
+
+    /// Mutex used to synchronize with paused servers
+    lock: Mutex<()>,
+
+    /// Condition variable used to wake up paused servers
+    resume_cv: Condvar,
+}
+
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use client::IAsyncOpClient;
+    use combinators;
+    use multithread::{blocking, polling};
+    use pause::*;
+    use status::{AsyncOpStatus, AsyncOpStatusDetails};
+    use std::thread;
+    use std::time::Duration;
+
+    /// Running details which tell whether an operation is paused
+    #[derive(Clone, Debug, PartialEq)]
+    struct PausableDetails {}
+    //
+    impl AsyncOpStatusDetails for PausableDetails {
+        type PendingDetails = NoDetails;
+        type RunningDetails = Option<PauseState>;
+        type DoneDetails = NoDetails;
+        type CancelledDetails = NoDetails;
+        type ErrorDetails = NoDetails;
+    }
+    //
+    impl AsyncOpStatusTraits for PausableDetails {}
+    //
+    impl AsyncOpStatusTraits for Option<PauseState> {}
+
+    /// Check that pause requests reach the server in every mode
+    #[test]
+    fn requests() {
+        let (server, mut client) = polling::AsyncOp::new(status::RUNNING)
+                                                    .split();
+        assert!(!server.pause_requested());
+        client.pause();
+        assert!(server.pause_requested());
+        client.resume();
+        assert!(!server.pause_requested());
+
+        // Combinators should forward pause requests to their inputs
+        let (server_a, client_a) = blocking::AsyncOp::new(status::RUNNING)
+                                                     .split();
+        let (server_b, client_b) = blocking::AsyncOp::new(status::RUNNING)
+                                                     .split();
+        let mut joined = combinators::join(client_a, client_b);
+        joined.pause();
+        assert!(server_a.pause_requested() && server_b.pause_requested());
+    }
+
+    /// Check that checkpoints block paused servers until they are resumed
+    #[test]
+    fn checkpoints() {
+        let (mut server, mut client) = blocking::AsyncOp::<PausableDetails>
+                                                ::new(AsyncOpStatus::Running(
+                                                    None
+                                                )).split();
+
+        // Checkpoints should not block operations which are not paused
+        assert!(server.checkpoint());
+        client.status();
+
+        // Paused operations should block at checkpoints and report it
+        client.pause();
+        let worker = thread::spawn(move || {
+            let result = server.checkpoint();
+            (server, result)
+        });
+        let paused = AsyncOpStatus::Running(Some(PauseState { paused: true }));
+        assert_eq!(client.wait(), paused);
+
+        // Resuming the operation should unblock the server
+        client.resume();
+        let (mut server, result) = worker.join().unwrap();
+        assert!(result);
+        assert_eq!(client.status(),
+                   AsyncOpStatus::Running(Some(PauseState { paused: false })));
+
+        // Cancelling the operation should unblock the server too
+        client.pause();
+        let worker = thread::spawn(move || server.checkpoint());
+        thread::sleep(Duration::from_millis(10));
+        client.cancel();
+        assert!(!worker.join().unwrap());
+    }
+}
@@ -0,0 +1,346 @@
+//! Process-wide registry of in-flight operations
+//!
+//! When a process has many operations in flight, it is useful to be able to
+//! list them, for example to find out which ones are stuck. This module
+//! provides an opt-in registry for this purpose: servers which are registered
+//! get a unique identifier, along with an optional name and tags, and are
+//! tracked until their operation reaches a final status or they are dropped.
+//!
+//! The registry can then be inspected at any time using snapshot(), which
+//! lists live operations along with their current state, age and time spent
+//! in that state, or dump(), which formats the same information as text that
+//! is suitable for a debug endpoint or a diagnostic signal handler.
+//!
+//! Operations start out in the state of the last status which their server
+//! sent before it was registered. Afterwards, the registry is only updated
+//! when their state changes, so that progress updates within the Running
+//! state do not contend for the registry's lock.
+
+use channels::OpChannels;
+use server::{AsyncOpServer, AsyncOpServerConfig};
+use status::{self, AsyncOpState, AsyncOpStatus};
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+
+/// Unique identifier of a registered operation
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct OpId(u64);
+//
+impl fmt::Display for OpId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+
+/// Optional information attached to an operation when it is registered
+#[derive(Clone, Debug, Default)]
+pub struct Registration {
+    /// Human-readable name of the operation
+    name: Option<String>,
+
+    /// Free-form tags, which can be used to group operations
+    tags: Vec<String>,
+}
+//
+impl Registration {
+    /// Start with an anonymous operation, with no tags
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Give the operation a name
+    pub fn named(mut self, name: &str) -> Self {
+        self.name = Some(name.to_owned());
+        self
+    }
+
+    /// Add a tag to the operation
+    pub fn tagged(mut self, tag: &str) -> Self {
+        self.tags.push(tag.to_owned());
+        self
+    }
+}
+
+
+/// Track an operation in the process-wide registry, given its server
+///
+/// Operations which have already reached a final status get an identifier,
+/// but are not tracked.
+///
+pub fn register<Config: AsyncOpServerConfig>(
+    server: AsyncOpServer<Config>,
+    registration: Registration
+) -> AsyncOpServer<RegisteredServerConfig<Config>> {
+    let state = server.state();
+    let tracked = !state.is_final();
+    let id =
+        Registry::get().lock().unwrap().insert(registration, state, tracked);
+    server.map_config(move |config| {
+        RegisteredServerConfig {
+            inner: config.into_inner(),
+            id,
+            state,
+            tracked,
+        }
+    })
+}
+
+
+/// Snapshot of a live operation, as recorded by the registry
+#[derive(Clone, Debug)]
+pub struct OpSnapshot {
+    /// Unique identifier of the operation
+    pub id: OpId,
+
+    /// Name of the operation, if any
+    pub name: Option<String>,
+
+    /// Tags of the operation
+    pub tags: Vec<String>,
+
+    /// Current toplevel state of the operation
+    pub state: AsyncOpState,
+
+    /// Time elapsed since the operation was registered
+    pub age: Duration,
+
+    /// Time elapsed since the operation entered its current state
+    pub time_in_state: Duration,
+}
+//
+impl fmt::Display for OpSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.id, self.state)?;
+        if let Some(ref name) = self.name {
+            write!(f, " {:?}", name)?;
+        }
+        if !self.tags.is_empty() {
+            write!(f, " [{}]", self.tags.join(", "))?;
+        }
+        write!(f, " age={:?} in_state={:?}", self.age, self.time_in_state)
+    }
+}
+
+
+/// List the live operations of the registry, in order of registration
+pub fn snapshot() -> Vec<OpSnapshot> {
+    let now = Instant::now();
+    let registry = Registry::get().lock().unwrap();
+    registry.ops.iter().map(|(&id, op)| {
+        OpSnapshot {
+            id,
+            name: op.registration.name.clone(),
+            tags: op.registration.tags.clone(),
+            state: op.state,
+            age: now.duration_since(op.created),
+            time_in_state: now.duration_since(op.state_since),
+        }
+    }).collect()
+}
+
+
+/// Describe the live operations of the registry as text, one per line
+pub fn dump() -> String {
+    let ops = snapshot();
+    let mut result = format!("{} live operation(s)\n", ops.len());
+    for op in ops {
+        writeln!(result, "{}", op).unwrap();
+    }
+    result
+}
+
+
+/// Server configuration wrapper which keeps the registry up to date
+pub struct RegisteredServerConfig<Config: AsyncOpServerConfig> {
+    /// Server configuration which we are wrapping
+    inner: Config,
+
+    /// Identifier of the operation in the registry
+    id: OpId,
+
+    /// State of the operation, as last recorded in the registry
+    state: AsyncOpState,
+
+    /// Whether the operation is still tracked by the registry
+    tracked: bool,
+}
+//
+impl<Config: AsyncOpServerConfig> RegisteredServerConfig<Config> {
+    /// Identifier of the operation in the registry
+    pub fn id(&self) -> OpId {
+        self.id
+    }
+}
+//
+impl<Config: AsyncOpServerConfig> AsyncOpServerConfig
+    for RegisteredServerConfig<Config>
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Config::StatusDetails;
+
+    /// Record state changes in the registry, then forward status updates to
+    /// the client
+    fn update(&mut self, status: AsyncOpStatus<Self::StatusDetails>) {
+        let state = status::state(&status);
+        if self.tracked && state != self.state {
+            self.state = state;
+            let mut registry = Registry::get().lock().unwrap();
+            if state.is_final() {
+                registry.ops.remove(&self.id);
+                self.tracked = false;
+            } else if let Some(op) = registry.ops.get_mut(&self.id) {
+                op.state = state;
+                op.state_since = Instant::now();
+            }
+        }
+        self.inner.update(status);
+    }
+
+    /// Side channels shared with the client
+    fn channels(&self) -> &OpChannels {
+        self.inner.channels()
+    }
+}
+//
+impl<Config: AsyncOpServerConfig> Drop for RegisteredServerConfig<Config> {
+    /// Make sure that the registry does not keep track of dead operations
+    fn drop(&mut self) {
+        if self.tracked {
+            Registry::get().lock().unwrap().ops.remove(&self.id);
+        }
+    }
+}
+
+
+/// Process-wide operation registry
+struct Registry {
+    /// Identifier of the next registered operation
+    next_id: u64,
+
+    /// Live operations
+    ops: BTreeMap<OpId, RegisteredOp>,
+}
+//
+impl Registry {
+    /// Access the process-wide registry
+    fn get() -> &'static Mutex<Registry> {
+        static REGISTRY: OnceLock<Mutex<Registry>> = OnceLock::new();
+        REGISTRY.get_or_init(|| {
+            Mutex::new(Registry {
+                next_id: 0,
+                ops: BTreeMap::new(),
+            })
+        })
+    }
+
+    /// Allocate an identifier for a new operation, and track it in its
+    /// initial state if requested
+    fn insert(&mut self,
+              registration: Registration,
+              state: AsyncOpState,
+              tracked: bool) -> OpId {
+        let id = OpId(self.next_id);
+        self.next_id += 1;
+        if tracked {
+            let now = Instant::now();
+            self.ops.insert(id, RegisteredOp {
+                registration,
+                state,
+                created: now,
+                state_since: now,
+            });
+        }
+        id
+    }
+}
+
+
+/// Registry entry of a live operation
+struct RegisteredOp {
+    /// Information which was provided at registration time
+    registration: Registration,
+
+    /// Current toplevel state of the operation
+    state: AsyncOpState,
+
+    /// Time at which the operation was registered
+    created: Instant,
+
+    /// Time at which the operation entered its current state
+    state_since: Instant,
+}
+
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use multithread::polling;
+    use registry::*;
+    use std::thread;
+
+    /// Find an operation in a registry snapshot
+    fn find(id: OpId) -> Option<OpSnapshot> {
+        snapshot().into_iter().find(|op| op.id == id)
+    }
+
+    /// Check that operations are tracked until they reach a final status
+    #[test]
+    fn lifecycle() {
+        let (server, _client) = polling::AsyncOp::new(status::PENDING).split();
+        let registration = Registration::new().named("upload").tagged("net");
+        let mut server = register(server, registration);
+        let id = server.config().id();
+        let op = find(id).unwrap();
+        assert_eq!(op.name, Some("upload".to_owned()));
+        assert_eq!(op.tags, vec!["net".to_owned()]);
+        assert_eq!(op.state, AsyncOpState::Pending);
+
+        // Status updates should be reflected in the registry
+        thread::sleep(Duration::from_millis(10));
+        server.update(status::RUNNING);
+        let op = find(id).unwrap();
+        assert_eq!(op.state, AsyncOpState::Running);
+        assert!(op.age >= Duration::from_millis(10));
+        assert!(op.time_in_state < op.age);
+
+        // Final statuses should take the operation out of the registry
+        server.update(status::DONE);
+        assert!(find(id).is_none());
+    }
+
+    /// Check that dropped servers and finished operations are not tracked
+    #[test]
+    fn untracked() {
+        let (server, _client) = polling::AsyncOp::new(status::RUNNING).split();
+        let server = register(server, Registration::new());
+        let id = server.config().id();
+        assert_eq!(find(id).unwrap().state, AsyncOpState::Running);
+        ::std::mem::drop(server);
+        assert!(find(id).is_none());
+
+        let (mut server, _client) =
+            polling::AsyncOp::new(status::RUNNING).split();
+        server.update(status::DONE);
+        let server = register(server, Registration::new());
+        assert!(find(server.config().id()).is_none());
+    }
+
+    /// Check that registry dumps describe every live operation
+    #[test]
+    fn dump() {
+        let (server, _client) = polling::AsyncOp::new(status::PENDING).split();
+        let registration =
+            Registration::new().named("compile").tagged("cpu").tagged("slow");
+        let server = register(server, registration);
+        let id = server.config().id();
+        let dump = super::dump();
+        let line = dump.lines().find(|line| {
+            line.starts_with(&format!("{} ", id))
+        }).unwrap();
+        assert!(line.contains("Pending \"compile\" [cpu, slow] age="));
+    }
+}
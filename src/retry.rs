@@ -0,0 +1,443 @@
+//! Automatic retries of failed operations
+//!
+//! Some operations fail for transient reasons, such as a flaky network link or
+//! a busy device, and are likely to succeed if they are simply tried again.
+//! This module automates this process: the operation is submitted through a
+//! user-provided launch function, which is called again whenever an attempt
+//! fails with an error that the user deems worth retrying.
+//!
+//! From the client's point of view, all attempts make up a single operation.
+//! While a retry is scheduled, the operation is running, with details telling
+//! which attempt is coming next. Retries are delayed with exponential backoff
+//! and random jitter, so that many operations failing at the same time don't
+//! all hammer the backend again at the same time, and the number of attempts
+//! is bounded. An operation whose client requested cancellation is never
+//! retried: the error of its last attempt is reported instead.
+
+use channels::OpChannels;
+use server::{AsyncOpServer, AsyncOpServerConfig};
+use status::{self, AsyncOpError, AsyncOpStatus, AsyncOpStatusDetails,
+             AsyncOpStatusTraits, NoDetails};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use timer;
+
+
+/// Upcoming attempt of an operation which is being retried
+///
+/// This is reported through the running status details of retried operations,
+/// which must therefore be constructible from it.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RetryAttempt {
+    /// Number of the upcoming attempt, starting at 1 for the first attempt
+    pub attempt: u32,
+
+    /// Maximal number of attempts
+    pub max_attempts: u32,
+
+    /// Delay after which the attempt will be launched
+    pub delay: Duration,
+}
+//
+impl AsyncOpStatusTraits for RetryAttempt {}
+//
+impl From<RetryAttempt> for NoDetails {
+    /// Standard statuses simply discard retry information
+    fn from(_: RetryAttempt) -> Self {
+        status::NO_DETAILS
+    }
+}
+
+
+/// Policy deciding how many times, and how often, operations are retried
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Maximal number of attempts, including the first one
+    pub max_attempts: u32,
+
+    /// Delay before the first retry
+    pub initial_delay: Duration,
+
+    /// Factor by which the delay is multiplied after each retry
+    pub multiplier: f64,
+
+    /// Upper bound on the delay between two attempts
+    pub max_delay: Duration,
+
+    /// Fraction of the delay, between 0 and 1, which is randomly shaved off
+    pub jitter: f64,
+}
+//
+impl RetryPolicy {
+    /// Retry up to a certain number of attempts, with a default backoff of
+    /// 10ms doubling up to 10s, and 50% jitter
+    pub fn new(max_attempts: u32) -> Self {
+        assert!(max_attempts > 0, "Operations must be attempted at least once");
+        RetryPolicy {
+            max_attempts,
+            initial_delay: Duration::from_millis(10),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(10),
+            jitter: 0.5,
+        }
+    }
+
+    /// Delay between a failed attempt and the next one, given the number of
+    /// failed attempts so far, before jitter is applied
+    pub fn backoff(&self, failed_attempts: u32) -> Duration {
+        let exponent = failed_attempts.saturating_sub(1) as i32;
+        let delay = self.initial_delay.as_secs_f64()
+                        * self.multiplier.powi(exponent);
+        let max_delay = self.max_delay.as_secs_f64();
+        Duration::from_secs_f64(delay.min(max_delay))
+    }
+
+    /// Apply jitter to a delay, given a random number between 0 and 1
+    fn jittered(&self, delay: Duration, random: f64) -> Duration {
+        delay.mul_f64(1.0 - self.jitter.clamp(0.0, 1.0) * random)
+    }
+}
+
+
+/// Run an operation with retries, given the server of that operation
+///
+/// The launch function is called with the attempt number and a server for each
+/// attempt, including the first one, which is launched right away. It should
+/// hand the server over to whatever will perform the work, without blocking,
+/// as it will be called from the timer thread for retries. Attempts which fail
+/// with an error matching `should_retry` are retried, as long as the policy
+/// and the client allow for it.
+///
+pub fn retry<Config, P, F>(server: AsyncOpServer<Config>,
+                           policy: RetryPolicy,
+                           should_retry: P,
+                           launch: F)
+    where Config: AsyncOpServerConfig + Send + 'static,
+          <Config::StatusDetails as AsyncOpStatusDetails>::RunningDetails:
+              From<RetryAttempt>,
+          P: Fn(&AsyncOpError<Config::StatusDetails>) -> bool
+             + Send + Sync + 'static,
+          F: FnMut(u32, AsyncOpServer<AttemptServerConfig<Config>>)
+             + Send + 'static
+{
+    // Operations which are already over have nothing to retry
+    if server.is_final() { return; }
+
+    // Seed the jitter's random number generator, which must not be zero
+    let seed = RandomState::new().build_hasher().finish() | 1;
+
+    // Set up the retry machinery and launch the first attempt
+    let shared = Arc::new(RetryShared {
+        policy,
+        channels: server.config().channels()
+                        .with_cancellation(server.cancellation().child()),
+        should_retry: Box::new(should_retry),
+        launch: Mutex::new(Box::new(launch)),
+        state: Mutex::new(RetryState {
+            outer: Some(server),
+            last_error: None,
+            random: seed,
+        }),
+    });
+    launch_attempt(shared, 1);
+}
+
+
+/// Launch an attempt of an operation, unless the client has cancelled it
+fn launch_attempt<Config>(shared: Arc<RetryShared<Config>>, attempt: u32)
+    where Config: AsyncOpServerConfig + Send + 'static,
+          <Config::StatusDetails as AsyncOpStatusDetails>::RunningDetails:
+              From<RetryAttempt>
+{
+    // Retries are scheduled in advance, so check for cancellation first
+    if attempt > 1 {
+        let mut state = shared.state.lock().unwrap();
+        if state.outer.as_ref().map_or(true, |outer| outer.cancelled()) {
+            let error = state.last_error.take().unwrap();
+            if let Some(mut outer) = state.outer.take() {
+                outer.update(AsyncOpStatus::Error(error));
+            }
+            ::std::mem::drop(state);
+            shared.channels.cancellation().cancel();
+            return;
+        }
+    }
+
+    // Build the server of the attempt and hand it over
+    let config = AttemptServerConfig { shared: shared.clone(), attempt };
+    let initial_status = AsyncOpStatus::Running(
+        RetryAttempt {
+            attempt,
+            max_attempts: shared.policy.max_attempts,
+            delay: Duration::new(0, 0),
+        }.into()
+    );
+    let server = AsyncOpServer::new(config, &initial_status);
+    let mut launch = shared.launch.lock().unwrap();
+    (*launch)(attempt, server);
+}
+
+
+/// Server configuration of an attempt of a retried operation
+pub struct AttemptServerConfig<Config>
+    where Config: AsyncOpServerConfig + Send + 'static,
+          <Config::StatusDetails as AsyncOpStatusDetails>::RunningDetails:
+              From<RetryAttempt>
+{
+    /// Retry machinery of the operation
+    shared: Arc<RetryShared<Config>>,
+
+    /// Number of this attempt
+    attempt: u32,
+}
+//
+impl<Config> AsyncOpServerConfig for AttemptServerConfig<Config>
+    where Config: AsyncOpServerConfig + Send + 'static,
+          <Config::StatusDetails as AsyncOpStatusDetails>::RunningDetails:
+              From<RetryAttempt>
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Config::StatusDetails;
+
+    /// Forward status updates to the client, unless a retry is in order
+    fn update(&mut self, status: AsyncOpStatus<Self::StatusDetails>) {
+        let mut state = self.shared.state.lock().unwrap();
+        let policy = self.shared.policy;
+        let retry = match status {
+            // The operation cannot go back to pending once it has been retried
+            AsyncOpStatus::Pending(_) if self.attempt > 1 => return,
+
+            // Failed attempts may be retried if the policy and client agree
+            AsyncOpStatus::Error(ref error) => {
+                self.attempt < policy.max_attempts
+                    && state.outer.as_ref()
+                                  .is_some_and(|outer| !outer.cancelled())
+                    && (self.shared.should_retry)(error)
+            },
+            _ => false,
+        };
+
+        // If so, tell the client and schedule the next attempt...
+        if retry {
+            let delay = policy.jittered(policy.backoff(self.attempt),
+                                        state.next_random());
+            let next_attempt = self.attempt + 1;
+            if let Some(ref mut outer) = state.outer {
+                outer.update(AsyncOpStatus::Running(
+                    RetryAttempt {
+                        attempt: next_attempt,
+                        max_attempts: policy.max_attempts,
+                        delay,
+                    }.into()
+                ));
+            }
+            if let AsyncOpStatus::Error(error) = status {
+                state.last_error = Some(error);
+            }
+            let shared = self.shared.clone();
+            timer::schedule(Instant::now() + delay, move || {
+                launch_attempt(shared, next_attempt)
+            });
+            return;
+        }
+
+        // ...otherwise, forward the status update to the client
+        let is_final = status::is_final(&status);
+        if let Some(ref mut outer) = state.outer {
+            outer.update(status);
+        }
+        if is_final {
+            state.outer = None;
+            ::std::mem::drop(state);
+            self.shared.channels.cancellation().cancel();
+        }
+    }
+
+    /// Attempts share the side channels of the operation, and are cancelled
+    /// when the client cancels the operation, or when the operation is over
+    fn channels(&self) -> &OpChannels {
+        &self.shared.channels
+    }
+}
+
+
+/// Launch function of retried operations
+type LaunchFn<Config> =
+    dyn FnMut(u32, AsyncOpServer<AttemptServerConfig<Config>>) + Send;
+
+/// Retry predicate of retried operations
+type RetryPredicate<Config> =
+    dyn Fn(&AsyncOpError<<Config as AsyncOpServerConfig>::StatusDetails>)
+        -> bool + Send + Sync;
+
+
+/// Retry machinery of an operation, shared by all of its attempts
+struct RetryShared<Config>
+    where Config: AsyncOpServerConfig + Send + 'static,
+          <Config::StatusDetails as AsyncOpStatusDetails>::RunningDetails:
+              From<RetryAttempt>
+{
+    /// Retry policy
+    policy: RetryPolicy,
+
+    /// Side channels of the operation, shared by all attempts, with a token
+    /// which is cancelled when attempts should stop
+    channels: OpChannels,
+
+    /// Predicate telling which errors are worth retrying
+    should_retry: Box<RetryPredicate<Config>>,
+
+    /// Function used to launch an attempt
+    launch: Mutex<Box<LaunchFn<Config>>>,
+
+    /// Mutable state of the operation
+    state: Mutex<RetryState<Config>>,
+}
+
+
+/// Mutable state of a retried operation
+struct RetryState<Config: AsyncOpServerConfig> {
+    /// Server of the operation, as seen by the client, until it is over
+    outer: Option<AsyncOpServer<Config>>,
+
+    /// Error of the last failed attempt, if a retry is scheduled
+    last_error: Option<AsyncOpError<Config::StatusDetails>>,
+
+    /// State of the jitter's random number generator
+    random: u64,
+}
+//
+impl<Config: AsyncOpServerConfig> RetryState<Config> {
+    /// Generate a random number between 0 and 1 (xorshift64 algorithm)
+    fn next_random(&mut self) -> f64 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 7;
+        self.random ^= self.random << 17;
+        (self.random >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use client::IAsyncOpClient;
+    use multithread::{blocking, polling};
+    use retry::*;
+    use status::NO_DETAILS;
+    use std::sync::mpsc;
+    use std::thread;
+
+    /// Retry policy with small delays, for testing purposes
+    fn fast_policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            initial_delay: Duration::from_millis(1),
+            ..RetryPolicy::new(max_attempts)
+        }
+    }
+
+    /// Predicate which retries custom errors
+    fn custom_errors(error: &AsyncOpError<NoDetails>) -> bool {
+        matches!(*error, AsyncOpError::CustomError(_))
+    }
+
+    /// Check the exponential backoff and jitter computations
+    #[test]
+    fn backoff() {
+        let policy = RetryPolicy {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            ..RetryPolicy::new(10)
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(4), Duration::from_millis(800));
+        assert_eq!(policy.backoff(5), Duration::from_millis(1000));
+        let delay = Duration::from_millis(100);
+        assert_eq!(policy.jittered(delay, 0.0), delay);
+        assert_eq!(policy.jittered(delay, 1.0), Duration::from_millis(50));
+    }
+
+    /// Check that transient failures are retried until the operation succeeds
+    #[test]
+    fn transient_failures() {
+        let (server, mut client) = blocking::AsyncOp::new(status::PENDING)
+                                                     .split();
+        retry(server, fast_policy(5), custom_errors, |attempt, mut server| {
+            if attempt < 3 {
+                server.update(status::RUNNING);
+                server.update(AsyncOpStatus::Error(
+                    AsyncOpError::CustomError(NO_DETAILS)
+                ));
+            } else {
+                server.update(status::DONE);
+            }
+        });
+        let mut statuses = vec![client.status()];
+        while !status::is_final(statuses.last().unwrap()) {
+            statuses.push(client.wait());
+        }
+        assert_eq!(statuses.last(), Some(&status::DONE));
+        assert!(!statuses.contains(&status::PENDING));
+    }
+
+    /// Check that retries are bounded, and only happen for the right errors
+    #[test]
+    fn giving_up() {
+        // Give up after the maximal number of attempts
+        let (sender, receiver) = mpsc::channel();
+        let (server, mut client) = blocking::AsyncOp::new(status::PENDING)
+                                                     .split();
+        retry(server, fast_policy(3), custom_errors, move |attempt, mut s| {
+            sender.send(attempt).unwrap();
+            s.update(AsyncOpStatus::Error(
+                AsyncOpError::CustomError(NO_DETAILS)
+            ));
+        });
+        let mut status = client.status();
+        while !status::is_final(&status) {
+            status = client.wait();
+        }
+        assert_eq!(status,
+                   AsyncOpStatus::Error(AsyncOpError::CustomError(NO_DETAILS)));
+        assert_eq!(receiver.iter().collect::<Vec<_>>(), vec![1, 2, 3]);
+
+        // Do not retry errors which don't match the predicate
+        let (server, mut client) = polling::AsyncOp::new(status::PENDING)
+                                                    .split();
+        retry(server, fast_policy(3), custom_errors, |attempt, server| {
+            assert_eq!(attempt, 1);
+            ::std::mem::drop(server);
+        });
+        assert_eq!(*client.status(), status::ERROR_SERVER_KILLED);
+    }
+
+    /// Check that cancelled operations are never retried
+    #[test]
+    fn cancellation() {
+        let (sender, receiver) = mpsc::channel();
+        let (server, mut client) = polling::AsyncOp::new(status::PENDING)
+                                                   .split();
+        retry(server, fast_policy(5), custom_errors, move |_, server| {
+            sender.send(server).unwrap();
+        });
+
+        // Cancel the operation, then fail the first attempt
+        let mut attempt = receiver.recv().unwrap();
+        attempt.update(status::RUNNING);
+        client.cancel();
+        assert!(attempt.cancelled());
+        attempt.update(AsyncOpStatus::Error(
+            AsyncOpError::CustomError(NO_DETAILS)
+        ));
+        assert_eq!(*client.status(),
+                   AsyncOpStatus::Error(AsyncOpError::CustomError(NO_DETAILS)));
+
+        // No further attempt should have been launched
+        thread::sleep(Duration::from_millis(20));
+        assert!(receiver.try_recv().is_err());
+    }
+}
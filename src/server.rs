@@ -7,8 +7,26 @@
 //!
 //! Note that in general, this raw abstraction should not be directly exposed to
 //! clients, as doing so would allow arbitrary server code injection.
-
-use status::{self, AsyncOpError, AsyncOpStatus, AsyncOpStatusDetails};
+//!
+//! Servers are generic over their configuration, which depends on how the
+//! client monitors the operation. Code which should not depend on it can use
+//! DynAsyncOpServer instead, into which the server of every monitoring mode
+//! can be converted.
+//!
+//! With the "log" or "tracing" feature, servers also report the lifecycle of
+//! their operation to the corresponding ecosystem (see the instrument module).
+
+use cancellation::CancellationToken;
+use channels::OpChannels;
+use control::AsyncOpControlDetails;
+use instrument::Instrumentation;
+use pause::PauseState;
+use status::{self, AsyncOpError, AsyncOpState, AsyncOpStatus,
+             AsyncOpStatusDetails};
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+use stream::AsyncOpStreamDetails;
 
 
 /// Server interface, used to submit asynchronous operation status updates
@@ -19,6 +37,12 @@ pub struct AsyncOpServer<Configuration: AsyncOpServerConfig> {
     /// Flag indicating that the operation status has reached a final state
     /// and should not change anymore
     reached_final_status: bool,
+
+    /// Toplevel state of the last status which was sent to the client
+    state: AsyncOpState,
+
+    /// Reporting of the operation's lifecycle to logs and traces, if enabled
+    instrumentation: Instrumentation,
 }
 //
 impl<Config: AsyncOpServerConfig> AsyncOpServer<Config> {
@@ -28,8 +52,10 @@ impl<Config: AsyncOpServerConfig> AsyncOpServer<Config> {
         initial_status: &AsyncOpStatus<Config::StatusDetails>
     ) -> Self {
         AsyncOpServer {
-            config: config,
+            config,
             reached_final_status: status::is_final(initial_status),
+            state: status::state(initial_status),
+            instrumentation: Instrumentation::new(initial_status),
         }
     }
 
@@ -39,6 +65,8 @@ impl<Config: AsyncOpServerConfig> AsyncOpServer<Config> {
         // This should only happen if we have not yet reached a final status
         debug_assert!(!self.reached_final_status);
         self.reached_final_status = status::is_final(&status);
+        self.state = status::state(&status);
+        self.instrumentation.record(&status);
 
         // Propagate the new operation status
         self.config.update(status);
@@ -48,6 +76,178 @@ impl<Config: AsyncOpServerConfig> AsyncOpServer<Config> {
     pub fn cancelled(&self) -> bool {
         self.config.cancelled()
     }
+
+    /// Register a callback to be invoked as soon as the client cancels the
+    /// operation, or right away if it has already done so
+    ///
+    /// The callback runs on the thread which requested the cancellation, so
+    /// it should be short and must not block. It is typically used to wake up
+    /// a server which is blocked waiting for something else.
+    ///
+    pub fn on_cancel<F: FnOnce() + Send + 'static>(&self, callback: F) {
+        self.config.cancellation().on_cancel(callback);
+    }
+
+    /// Get a handle to the operation's cancellation flag, which can be waited
+    /// upon, possibly from another thread, instead of being polled
+    pub fn cancellation(&self) -> CancellationToken {
+        self.config.cancellation().clone()
+    }
+
+    /// Check whether the client has requested the operation to pause
+    pub fn pause_requested(&self) -> bool {
+        self.config.channels().pause_requested()
+    }
+
+    /// Check whether the operation status has reached a final state
+    pub fn is_final(&self) -> bool {
+        self.reached_final_status
+    }
+
+    /// Toplevel state of the last status which was sent to the client
+    pub fn state(&self) -> AsyncOpState {
+        self.state
+    }
+
+    /// Access the server configuration, for example in order to query extra
+    /// information provided by a configuration wrapper
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Wrap the server configuration into another one, for example in order
+    /// to intercept status updates on their way to the client
+    ///
+    /// The wrapping function receives the configuration inside of a guard,
+    /// out of which it should take it once it is ready to wrap it. Should the
+    /// function panic before that, the client is told that the server was
+    /// killed, as if the server had been dropped.
+    ///
+    pub fn map_config<NewConfig, F>(self, f: F) -> AsyncOpServer<NewConfig>
+        where NewConfig: AsyncOpServerConfig<
+                  StatusDetails=Config::StatusDetails
+              >,
+              F: FnOnce(ConfigGuard<Config>) -> NewConfig
+    {
+        // Since we implement Drop, the configuration cannot be moved out of
+        // the server directly. Instead, we disarm the destructor and extract
+        // the configuration and instrumentation bitwise. This is safe because
+        // the original ones are never accessed again afterwards.
+        let old_server = ManuallyDrop::new(self);
+        let config = unsafe { ptr::read(&old_server.config) };
+        let instrumentation =
+            unsafe { ptr::read(&old_server.instrumentation) };
+        let reached_final_status = old_server.reached_final_status;
+        AsyncOpServer {
+            config: f(ConfigGuard {
+                config: Some(config),
+                reached_final_status,
+            }),
+            reached_final_status,
+            state: old_server.state,
+            instrumentation,
+        }
+    }
+
+    /// Span of the operation, which server code can enter so that its own
+    /// events are attributed to the operation, until the operation is over
+    #[cfg(feature = "tracing")]
+    pub fn span(&self) -> ::tracing::Span {
+        self.instrumentation.span()
+    }
+
+    /// Instrumentation of the operation, through which configuration wrappers
+    /// report the status updates which they send on their own
+    pub(crate) fn instrumentation(&self) -> Instrumentation {
+        self.instrumentation.clone()
+    }
+
+    /// Erase the type of the server configuration, so that the server can be
+    /// handled without knowing how its client monitors the operation
+    pub fn into_dyn(self) -> DynAsyncOpServer<Config::StatusDetails>
+        where Config: Send + 'static
+    {
+        self.map_config(|config| {
+            Box::new(config.into_inner()) as DynServerConfig<_>
+        })
+    }
+}
+//
+impl<Config> AsyncOpServer<Config>
+    where Config: AsyncOpServerConfig,
+          Config::StatusDetails: AsyncOpControlDetails
+{
+    /// Receive the oldest control message sent by the client which was not
+    /// received yet, if any, without blocking
+    pub fn try_receive_control(&mut self) -> Option<
+        <Config::StatusDetails as AsyncOpControlDetails>::ControlMessage
+    > {
+        self.config.channels().try_receive_control()
+    }
+
+    /// Block until the client sends a control message, and return it, or
+    /// return None if the operation is cancelled first
+    pub fn receive_control(&mut self) -> Option<
+        <Config::StatusDetails as AsyncOpControlDetails>::ControlMessage
+    > {
+        let channels = self.config.channels();
+        channels.control_channel().receive(channels.cancellation())
+    }
+}
+//
+impl<Config> AsyncOpServer<Config>
+    where Config: AsyncOpServerConfig,
+          Config::StatusDetails: AsyncOpStreamDetails
+{
+    /// Push a partial result to the client's result stream
+    ///
+    /// If the client is too far behind, this call blocks until it catches up.
+    /// Returns false if the item was discarded because the operation was
+    /// cancelled in the meantime, or because every client is gone, in which
+    /// case the server should stop.
+    ///
+    pub fn push_item(
+        &mut self,
+        item: <Config::StatusDetails as AsyncOpStreamDetails>::StreamItem
+    ) -> bool {
+        debug_assert!(!self.reached_final_status,
+                      "Cannot push results after the operation is over");
+        let channels = self.config.channels();
+        channels.stream_channel().push(
+            item,
+            <Config::StatusDetails as AsyncOpStreamDetails>::STREAM_CAPACITY,
+            channels.cancellation()
+        )
+    }
+}
+//
+impl<Config> AsyncOpServer<Config>
+    where Config: AsyncOpServerConfig,
+          <Config::StatusDetails as AsyncOpStatusDetails>::RunningDetails:
+              From<PauseState>
+{
+    /// Point where the operation may be paused
+    ///
+    /// If the client has requested a pause, the operation is reported as
+    /// paused, and this call blocks until the client resumes or cancels the
+    /// operation. Returns false if the operation was cancelled, in which case
+    /// the server should stop working.
+    ///
+    pub fn checkpoint(&mut self) -> bool {
+        if self.pause_requested() && !self.cancelled() {
+            self.update(AsyncOpStatus::Running(
+                PauseState { paused: true }.into()
+            ));
+            let channels = self.config.channels();
+            channels.pause_flag().wait_resumed(channels.cancellation());
+            if !self.cancelled() {
+                self.update(AsyncOpStatus::Running(
+                    PauseState { paused: false }.into()
+                ));
+            }
+        }
+        !self.cancelled()
+    }
 }
 //
 impl<Config: AsyncOpServerConfig> Drop for AsyncOpServer<Config> {
@@ -61,6 +261,58 @@ impl<Config: AsyncOpServerConfig> Drop for AsyncOpServer<Config> {
 }
 
 
+/// Server configuration which is being wrapped by AsyncOpServer::map_config()
+///
+/// The configuration can be accessed through this guard, and is taken out of
+/// it with into_inner(). If the guard is dropped before that, which happens
+/// when the wrapping function panics, the client is told that the server was
+/// killed.
+///
+pub struct ConfigGuard<Config: AsyncOpServerConfig> {
+    /// Configuration which is being wrapped, until it is taken out
+    config: Option<Config>,
+
+    /// Truth that the operation has already reached its final status, in
+    /// which case the client needs not be told anything
+    reached_final_status: bool,
+}
+//
+impl<Config: AsyncOpServerConfig> ConfigGuard<Config> {
+    /// Take the configuration out of the guard, in order to wrap it
+    pub fn into_inner(mut self) -> Config {
+        self.config.take().expect("Configuration was already taken")
+    }
+}
+//
+impl<Config: AsyncOpServerConfig> Deref for ConfigGuard<Config> {
+    type Target = Config;
+
+    /// Access the configuration which is being wrapped
+    fn deref(&self) -> &Config {
+        self.config.as_ref().expect("Configuration was already taken")
+    }
+}
+//
+impl<Config: AsyncOpServerConfig> DerefMut for ConfigGuard<Config> {
+    /// Access the configuration which is being wrapped
+    fn deref_mut(&mut self) -> &mut Config {
+        self.config.as_mut().expect("Configuration was already taken")
+    }
+}
+//
+impl<Config: AsyncOpServerConfig> Drop for ConfigGuard<Config> {
+    /// If the configuration was never taken out, the server is gone, so the
+    /// client must be notified in order to prevent it from hanging
+    fn drop(&mut self) {
+        if let Some(mut config) = self.config.take() {
+            if !self.reached_final_status {
+                config.update(AsyncOpStatus::Error(AsyncOpError::ServerKilled));
+            }
+        }
+    }
+}
+
+
 /// Configurable parameters and behaviour of AsyncOpServer
 pub trait AsyncOpServerConfig {
     /// Implementation details of the asynchronous operation status
@@ -69,17 +321,65 @@ pub trait AsyncOpServerConfig {
     /// Method used to send status updates to the client
     fn update(&mut self, status: AsyncOpStatus<Self::StatusDetails>);
 
+    /// Side channels shared with the client, whose result stream should be
+    /// closed once a final status has been sent
+    fn channels(&self) -> &OpChannels;
+
+    /// Token which is cancelled when the client cancels the operation
+    fn cancellation(&self) -> &CancellationToken {
+        self.channels().cancellation()
+    }
+
     /// Method used to query whether the client has cancelled the operation
-    fn cancelled(&self) -> bool;
+    fn cancelled(&self) -> bool {
+        self.cancellation().is_cancelled()
+    }
 }
 
 
+/// Boxed server configurations behave like the configuration which they contain
+impl<C: AsyncOpServerConfig + ?Sized> AsyncOpServerConfig for Box<C> {
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = C::StatusDetails;
+
+    /// Method used to send status updates to the client
+    fn update(&mut self, status: AsyncOpStatus<Self::StatusDetails>) {
+        (**self).update(status)
+    }
+
+    /// Side channels shared with the client
+    fn channels(&self) -> &OpChannels {
+        (**self).channels()
+    }
+
+    /// Token which is cancelled when the client cancels the operation
+    fn cancellation(&self) -> &CancellationToken {
+        (**self).cancellation()
+    }
+
+    /// Method used to query whether the client has cancelled the operation
+    fn cancelled(&self) -> bool {
+        (**self).cancelled()
+    }
+}
+
+
+/// Server configuration of any type, for operations with certain details
+pub type DynServerConfig<Details> =
+    Box<dyn AsyncOpServerConfig<StatusDetails = Details> + Send>;
+
+
+/// Server of an asynchronous operation, whose monitoring mode is unknown
+pub type DynAsyncOpServer<Details> = AsyncOpServer<DynServerConfig<Details>>;
+
+
 /// Unit tests
 #[cfg(test)]
 mod tests {
     use server::*;
     use status::{StandardAsyncOpStatus, NoDetails};
     use std::cell::RefCell;
+    use std::panic::{self, AssertUnwindSafe};
     use std::rc::Rc;
 
 
@@ -93,7 +393,7 @@ mod tests {
         );
         assert_eq!(*pending_server.config.last_status.borrow(), status::PENDING);
         assert_eq!(pending_server.config.update_count, 0);
-        assert_eq!(pending_server.reached_final_status, false);
+        assert!(!pending_server.reached_final_status);
 
         // Test initial server state for a final status
         let final_server = AsyncOpServer::new(
@@ -102,30 +402,30 @@ mod tests {
         );
         assert_eq!(*final_server.config.last_status.borrow(), status::DONE);
         assert_eq!(final_server.config.update_count, 0);
-        assert_eq!(final_server.reached_final_status, true);
+        assert!(final_server.reached_final_status);
     }
 
 
     /// Check that the server update() method works correctly
     #[test]
     fn correct_updates() {
-        /// Start with a server in the pending state
+        // Start with a server in the pending state
         let mut server = AsyncOpServer::new(
             MockServerConfig::new(status::PENDING),
             &status::PENDING
         );
 
-        /// Move it to the running state, check that it works
+        // Move it to the running state, check that it works
         server.update(status::RUNNING);
         assert_eq!(*server.config.last_status.borrow(), status::RUNNING);
         assert_eq!(server.config.update_count, 1);
-        assert_eq!(server.reached_final_status, false);
+        assert!(!server.reached_final_status);
 
-        /// Move it to the done state, check that it works
+        // Move it to the done state, check that it works
         server.update(status::DONE);
         assert_eq!(*server.config.last_status.borrow(), status::DONE);
         assert_eq!(server.config.update_count, 2);
-        assert_eq!(server.reached_final_status, true);
+        assert!(server.reached_final_status);
     }
 
 
@@ -133,13 +433,13 @@ mod tests {
     #[test]
     #[should_panic]
     fn incorrect_update() {
-        /// Start with a server in a final state
+        // Start with a server in a final state
         let mut server = AsyncOpServer::new(
             MockServerConfig::new(status::DONE),
             &status::DONE
         );
 
-        /// Try to update it to another final state, this should fail
+        // Try to update it to another final state, this should fail
         server.update(status::ERROR_SERVER_KILLED);
     }
 
@@ -173,6 +473,90 @@ mod tests {
     }
 
 
+    /// Check that wrapping a server's configuration preserves its state
+    #[test]
+    fn map_config() {
+        // Start with a running server
+        let server = AsyncOpServer::new(
+            MockServerConfig::new(status::RUNNING),
+            &status::RUNNING
+        );
+        let status_ref = server.config.last_status.clone();
+
+        // Wrap its configuration, this should not send any status update
+        let mut server = server.map_config(|config| {
+            MockServerConfig { update_count: 42, ..config.into_inner() }
+        });
+        assert_eq!(*status_ref.borrow(), status::RUNNING);
+        assert_eq!(server.config.update_count, 42);
+        assert!(!server.is_final());
+        assert_eq!(server.state(), AsyncOpState::Running);
+
+        // Status updates should now go through the new configuration
+        server.update(status::DONE);
+        assert_eq!(*status_ref.borrow(), status::DONE);
+        assert_eq!(server.config.update_count, 43);
+        assert!(server.is_final());
+        assert_eq!(server.state(), AsyncOpState::Done);
+    }
+
+
+    /// Check that the client is notified if wrapping the configuration fails
+    #[test]
+    fn map_config_panic() {
+        let server = AsyncOpServer::new(
+            MockServerConfig::new(status::RUNNING),
+            &status::RUNNING
+        );
+        let status_ref = server.config.last_status.clone();
+        let result = panic::catch_unwind(AssertUnwindSafe(move || {
+            server.map_config(|config| -> MockServerConfig {
+                assert_eq!(config.update_count, 0);
+                panic!("Expected panic")
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(*status_ref.borrow(), status::ERROR_SERVER_KILLED);
+    }
+
+
+    /// Check that a single worker can serve operations of any monitoring mode
+    #[test]
+    fn dyn_server() {
+        use executor::inline::InlineCallbackExecutor;
+        use multithread::{blocking, callback, polling};
+
+        /// Non-generic worker function
+        fn work(mut server: DynAsyncOpServer<NoDetails>) {
+            assert!(!server.cancelled());
+            server.update(status::RUNNING);
+            server.update(status::DONE);
+        }
+
+        // Polling mode
+        let (server, mut client) =
+            polling::AsyncOp::new(status::PENDING).split();
+        work(server.into());
+        assert_eq!(*client.status(), status::DONE);
+
+        // Blocking mode
+        let (server, mut client) =
+            blocking::AsyncOp::new(status::PENDING).split();
+        work(server.into());
+        assert_eq!(client.status(), status::DONE);
+
+        // Callback mode
+        let mut executor = InlineCallbackExecutor::new();
+        let (server, _) =
+            callback::new_send_async_op(|s: StandardAsyncOpStatus| {
+                                            assert!(s != status::PENDING)
+                                        },
+                                        &mut executor,
+                                        status::PENDING).split();
+        work(server.into());
+    }
+
+
     /// Mock server configuration, suitable for unit testing
     struct MockServerConfig {
         /// Last status update sent by the server
@@ -180,6 +564,9 @@ mod tests {
 
         /// Number of status updates sent by the server so far
         update_count: i32,
+
+        /// Side channels, which no client will ever use
+        channels: OpChannels,
     }
     //
     impl MockServerConfig {
@@ -188,6 +575,7 @@ mod tests {
             MockServerConfig {
                 last_status: Rc::new(RefCell::new(initial_status)),
                 update_count: 0,
+                channels: OpChannels::new(),
             }
         }
     }
@@ -202,6 +590,11 @@ mod tests {
             self.update_count+= 1;
         }
 
+        /// Side channels shared with the (nonexistent) client
+        fn channels(&self) -> &OpChannels {
+            &self.channels
+        }
+
         /// In this mock, there is no actual client, so no cancellation
         fn cancelled(&self) -> bool {
             false
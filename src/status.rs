@@ -28,7 +28,11 @@ use std::fmt::{self, Debug};
 /// Note that once the asynchronous operation is in either of the Done,
 /// Error or Cancelled state, its state won't change anymore.
 ///
-#[derive(Clone, Debug, PartialEq)]
+/// Statuses can be cloned and compared whenever all of their details can. This
+/// is not required by every monitoring mode, so that large or unique results
+/// can be moved out of a final status instead (see take_result()).
+///
+#[derive(Debug)]
 pub enum AsyncOpStatus<Details: AsyncOpStatusDetails> {
     /// The request has been submitted, but not been processed yet
     Pending(Details::PendingDetails),
@@ -45,6 +49,47 @@ pub enum AsyncOpStatus<Details: AsyncOpStatusDetails> {
     /// The server has failed to process the request
     Error(AsyncOpError<Details>),
 }
+//
+impl<Details> Clone for AsyncOpStatus<Details>
+    where Details: AsyncOpStatusDetails,
+          Details::PendingDetails: Clone,
+          Details::RunningDetails: Clone,
+          Details::DoneDetails: Clone,
+          Details::CancelledDetails: Clone,
+          Details::ErrorDetails: Clone
+{
+    fn clone(&self) -> Self {
+        use self::AsyncOpStatus::*;
+        match *self {
+            Pending(ref details) => Pending(details.clone()),
+            Running(ref details) => Running(details.clone()),
+            Done(ref details) => Done(details.clone()),
+            Cancelled(ref details) => Cancelled(details.clone()),
+            Error(ref error) => Error(error.clone()),
+        }
+    }
+}
+//
+impl<Details> PartialEq for AsyncOpStatus<Details>
+    where Details: AsyncOpStatusDetails,
+          Details::PendingDetails: PartialEq,
+          Details::RunningDetails: PartialEq,
+          Details::DoneDetails: PartialEq,
+          Details::CancelledDetails: PartialEq,
+          Details::ErrorDetails: PartialEq
+{
+    fn eq(&self, other: &Self) -> bool {
+        use self::AsyncOpStatus::*;
+        match (self, other) {
+            (Pending(a), Pending(b)) => a == b,
+            (Running(a), Running(b)) => a == b,
+            (Done(a), Done(b)) => a == b,
+            (Cancelled(a), Cancelled(b)) => a == b,
+            (Error(a), Error(b)) => a == b,
+            _ => false,
+        }
+    }
+}
 
 
 /// Check if an operation status is final (i.e. won't change anymore)
@@ -59,16 +104,114 @@ pub fn is_final<Details: AsyncOpStatusDetails>(
 }
 
 
+/// Toplevel state of an asynchronous operation, without any details
+///
+/// This is useful when statuses must be reported or compared without caring
+/// about, or having access to, their implementation-specific details.
+///
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum AsyncOpState {
+    /// See AsyncOpStatus::Pending
+    Pending,
+
+    /// See AsyncOpStatus::Running
+    Running,
+
+    /// See AsyncOpStatus::Done
+    Done,
+
+    /// See AsyncOpStatus::Cancelled
+    Cancelled,
+
+    /// See AsyncOpStatus::Error
+    Error,
+}
+//
+impl AsyncOpState {
+    /// Check if this state is final (i.e. won't change anymore)
+    pub fn is_final(self) -> bool {
+        use self::AsyncOpState::*;
+        match self {
+            Pending | Running => false,
+            Done | Cancelled | Error => true,
+        }
+    }
+}
+//
+impl fmt::Display for AsyncOpState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Debug::fmt(self, f)
+    }
+}
+
+
+/// Extract the toplevel state of an operation status
+pub fn state<Details: AsyncOpStatusDetails>(
+    s: &AsyncOpStatus<Details>
+) -> AsyncOpState {
+    match *s {
+        AsyncOpStatus::Pending(_) => AsyncOpState::Pending,
+        AsyncOpStatus::Running(_) => AsyncOpState::Running,
+        AsyncOpStatus::Done(_) => AsyncOpState::Done,
+        AsyncOpStatus::Cancelled(_) => AsyncOpState::Cancelled,
+        AsyncOpStatus::Error(_) => AsyncOpState::Error,
+    }
+}
+
+
 /// Support for standard and custom asynchronous operation errors
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Debug)]
 pub enum AsyncOpError<Details: AsyncOpStatusDetails> {
     /// The server was killed before the operation reached a final status
     ServerKilled,
 
+    /// The operation did not reach a final status before its deadline
+    TimedOut,
+
+    /// The server stopped showing signs of life for too long
+    Stalled,
+
+    /// The final status of the operation was moved out by a client (see the
+    /// take_result() method of blocking clients)
+    ResultTaken,
+
     /// An application-specific error has occurred
     #[allow(dead_code)]
     CustomError(Details::ErrorDetails)
 }
+//
+impl<Details> Clone for AsyncOpError<Details>
+    where Details: AsyncOpStatusDetails,
+          Details::ErrorDetails: Clone
+{
+    fn clone(&self) -> Self {
+        use self::AsyncOpError::*;
+        match *self {
+            ServerKilled => ServerKilled,
+            TimedOut => TimedOut,
+            Stalled => Stalled,
+            ResultTaken => ResultTaken,
+            CustomError(ref details) => CustomError(details.clone()),
+        }
+    }
+}
+//
+impl<Details> PartialEq for AsyncOpError<Details>
+    where Details: AsyncOpStatusDetails,
+          Details::ErrorDetails: PartialEq
+{
+    fn eq(&self, other: &Self) -> bool {
+        use self::AsyncOpError::*;
+        match (self, other) {
+            (ServerKilled, ServerKilled)
+            | (TimedOut, TimedOut)
+            | (Stalled, Stalled)
+            | (ResultTaken, ResultTaken) => true,
+            (CustomError(a), CustomError(b)) => a == b,
+            _ => false,
+        }
+    }
+}
 
 
 /// Implementation-specific details on the status of asynchronous operations
@@ -106,8 +249,32 @@ pub trait AsyncOpStatusDetails: AsyncOpStatusTraits {
 }
 
 
+/// Implementation details which can all be cloned
+///
+/// This is implemented automatically, and required by the monitoring modes and
+/// tools which need to copy statuses around. The Clone bounds are expressed as
+/// associated type bounds, so that code which requires this trait does not
+/// need to repeat them, which is why the crate requires Rust 1.79 or newer.
+///
+pub trait AsyncOpCloneableDetails: AsyncOpStatusDetails<
+    PendingDetails: Clone,
+    RunningDetails: Clone,
+    DoneDetails: Clone,
+    CancelledDetails: Clone,
+    ErrorDetails: Clone,
+> {}
+//
+impl<Details> AsyncOpCloneableDetails for Details
+    where Details: AsyncOpStatusDetails,
+          Details::PendingDetails: Clone,
+          Details::RunningDetails: Clone,
+          Details::DoneDetails: Clone,
+          Details::CancelledDetails: Clone,
+          Details::ErrorDetails: Clone {}
+
+
 /// Placeholder for unneeded asynchronous operation details
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct NoDetails {}
 //
 pub const NO_DETAILS: NoDetails = NoDetails {};
@@ -123,7 +290,7 @@ impl Error for NoDetails {
         "<No error details>"
     }
 
-    fn cause(&self) -> Option<&Error> {
+    fn cause(&self) -> Option<&dyn Error> {
         None
     }
 }
@@ -142,6 +309,12 @@ pub const CANCELLED: StandardAsyncOpStatus =
     AsyncOpStatus::Cancelled(NO_DETAILS);
 pub const ERROR_SERVER_KILLED: StandardAsyncOpStatus =
     AsyncOpStatus::Error(AsyncOpError::ServerKilled);
+pub const ERROR_TIMED_OUT: StandardAsyncOpStatus =
+    AsyncOpStatus::Error(AsyncOpError::TimedOut);
+pub const ERROR_STALLED: StandardAsyncOpStatus =
+    AsyncOpStatus::Error(AsyncOpError::Stalled);
+pub const ERROR_RESULT_TAKEN: StandardAsyncOpStatus =
+    AsyncOpStatus::Error(AsyncOpError::ResultTaken);
 //
 impl AsyncOpStatusDetails for NoDetails {
     type PendingDetails = NoDetails;
@@ -153,7 +326,13 @@ impl AsyncOpStatusDetails for NoDetails {
 
 
 /// Trait bounds which every part of the operation status should honor
-pub trait AsyncOpStatusTraits: Clone + Debug + PartialEq + Send {}
+///
+/// Most details are also Clone and PartialEq, which is needed by the polling
+/// monitoring mode, by status queries which return a copy of the status, and
+/// by combinators. But results which are expensive or impossible to copy can
+/// do without, as long as they are only monitored in other ways.
+///
+pub trait AsyncOpStatusTraits: Debug + Send {}
 //
 impl<Details: AsyncOpStatusDetails> AsyncOpStatusTraits
     for AsyncOpStatus<Details> {}
@@ -162,6 +341,11 @@ impl<Details: AsyncOpStatusDetails> AsyncOpStatusTraits
     for AsyncOpError<Details> {}
 //
 impl AsyncOpStatusTraits for NoDetails {}
+//
+impl<A: AsyncOpStatusTraits, B: AsyncOpStatusTraits> AsyncOpStatusTraits
+    for (A, B) {}
+//
+impl<T: AsyncOpStatusTraits> AsyncOpStatusTraits for Vec<T> {}
 
 
 /// Unit tests
@@ -207,5 +391,40 @@ mod tests {
             _ => panic!("ERROR_SERVER_KILLED status is incorrectly defined"),
         }
         assert!(is_final(&ERROR_SERVER_KILLED));
+
+        // Standard "timed out" status
+        match ERROR_TIMED_OUT {
+            AsyncOpStatus::Error(AsyncOpError::TimedOut) => {},
+            _ => panic!("ERROR_TIMED_OUT status is incorrectly defined"),
+        }
+        assert!(is_final(&ERROR_TIMED_OUT));
+
+        // Standard "stalled" status
+        match ERROR_STALLED {
+            AsyncOpStatus::Error(AsyncOpError::Stalled) => {},
+            _ => panic!("ERROR_STALLED status is incorrectly defined"),
+        }
+        assert!(is_final(&ERROR_STALLED));
+
+        // Standard "result taken" status
+        match ERROR_RESULT_TAKEN {
+            AsyncOpStatus::Error(AsyncOpError::ResultTaken) => {},
+            _ => panic!("ERROR_RESULT_TAKEN status is incorrectly defined"),
+        }
+        assert!(is_final(&ERROR_RESULT_TAKEN));
+    }
+
+    /// Test that toplevel states are correctly extracted from statuses
+    #[test]
+    fn states() {
+        assert_eq!(state(&PENDING), AsyncOpState::Pending);
+        assert_eq!(state(&RUNNING), AsyncOpState::Running);
+        assert_eq!(state(&DONE), AsyncOpState::Done);
+        assert_eq!(state(&CANCELLED), AsyncOpState::Cancelled);
+        assert_eq!(state(&ERROR_TIMED_OUT), AsyncOpState::Error);
+        for s in &[PENDING, RUNNING, DONE, CANCELLED, ERROR_STALLED] {
+            assert_eq!(state(s).is_final(), is_final(s));
+        }
+        assert_eq!(AsyncOpState::Running.to_string(), "Running");
     }
 }
@@ -0,0 +1,297 @@
+//! Streams of partial results, produced by running operations
+//!
+//! Some operations produce their output incrementally, such as search hits or
+//! decoded video frames. Reporting such output through the running status
+//! details does not work well, since clients are only guaranteed to see the
+//! latest status: intermediate results would be lost. This module provides a
+//! side channel for such results, which flows alongside the status channel.
+//!
+//! The type of the items which an operation produces is specified by its status
+//! details, through the AsyncOpStreamDetails trait. Servers push items, which
+//! clients consume in order through the IAsyncOpStreamClient interface. That
+//! interface is implemented in every monitoring mode, and allows both checking
+//! for new items and blocking until one is available.
+//!
+//! The stream has a bounded capacity, also specified by the status details:
+//! servers which get too far ahead of their client are blocked until the client
+//! catches up, cancels the operation, or goes away. The stream ends when the
+//! operation reaches a final status, after the client has consumed all pending
+//! items.
+
+use cancellation::{CancelWakeups, CancellationToken};
+use status::AsyncOpStatusDetails;
+use std::any::Any;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+
+
+/// Status details of operations which produce a stream of partial results
+pub trait AsyncOpStreamDetails: AsyncOpStatusDetails {
+    /// Partial results produced by the operation
+    type StreamItem: Send + 'static;
+
+    /// Number of items which the server may push before the client reads them
+    const STREAM_CAPACITY: usize = 16;
+}
+
+
+/// Channel through which partial results flow from a server to a client
+///
+/// Like control channels, stream channels are not typed, and type safety is
+/// enforced by the client and server interfaces instead. These interfaces are
+/// the only way to reach the channel from outside of this crate.
+///
+#[derive(Clone, Default)]
+pub(crate) struct StreamChannel {
+    /// Shared state of the channel
+    shared: Arc<StreamShared>,
+}
+//
+impl StreamChannel {
+    /// Create a new channel, with no pending item
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Push an item, waiting for the client to make room if the channel holds
+    /// `capacity` items or more, and tell whether the item was pushed
+    ///
+    /// Items are discarded if the token is cancelled before there is room for
+    /// them, or if the stream is already over.
+    ///
+    pub fn push<I: Send + 'static>(&self,
+                                   item: I,
+                                   capacity: usize,
+                                   cancellation: &CancellationToken) -> bool {
+        // Make sure that we will be woken up if the token is cancelled
+        let shared = self.shared.clone();
+        self.shared.wakeups.register(cancellation, move || {
+            let _lock = shared.state.lock().unwrap();
+            shared.space_cv.notify_all();
+        });
+
+        // Wait for the client to make room, giving up on cancellation
+        let mut state = self.shared.state.lock().unwrap();
+        while state.items.len() >= capacity.max(1) && !state.closed {
+            if cancellation.is_cancelled() { return false; }
+            state = self.shared.space_cv.wait(state).unwrap();
+        }
+        if state.closed { return false; }
+
+        // Push the item and tell the client about it
+        state.items.push_back(Box::new(item));
+        self.shared.item_cv.notify_all();
+        true
+    }
+
+    /// Mark the end of the stream, once the operation is over
+    pub fn close(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.closed = true;
+        self.shared.item_cv.notify_all();
+        self.shared.space_cv.notify_all();
+    }
+
+    /// Consume the oldest pending item, if any, without blocking
+    pub fn try_next<I: Send + 'static>(&self) -> Option<I> {
+        let mut state = self.shared.state.lock().unwrap();
+        let item = state.items.pop_front();
+        if item.is_some() {
+            self.shared.space_cv.notify_all();
+        }
+        item.map(Self::downcast)
+    }
+
+    /// Consume the oldest pending item, blocking until one is available, or
+    /// return None once the stream is over
+    pub fn wait_next<I: Send + 'static>(&self) -> Option<I> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(item) = state.items.pop_front() {
+                self.shared.space_cv.notify_all();
+                return Some(Self::downcast(item));
+            }
+            if state.closed { return None; }
+            state = self.shared.item_cv.wait(state).unwrap();
+        }
+    }
+
+    /// Check whether the stream is over and all of its items were consumed
+    pub fn is_finished(&self) -> bool {
+        let state = self.shared.state.lock().unwrap();
+        state.closed && state.items.is_empty()
+    }
+
+    /// Recover the concrete type of an item
+    fn downcast<I: Send + 'static>(item: Box<dyn Any + Send>) -> I {
+        *item.downcast::<I>()
+             .expect("Stream items should have the operation's type")
+    }
+}
+//
+impl fmt::Debug for StreamChannel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.shared.state.lock().unwrap();
+        f.debug_struct("StreamChannel")
+         .field("pending", &state.items.len())
+         .field("closed", &state.closed)
+         .finish()
+    }
+}
+
+
+/// Shared state of a stream channel
+#[derive(Default)]
+struct StreamShared {
+    /// Pending items and end-of-stream marker
+    state: Mutex<StreamState>,
+
+    /// Condition variable used to wake up clients waiting for an item
+    item_cv: Condvar,
+
+    /// Condition variable used to wake up servers waiting for room
+    space_cv: Condvar,
+
+    /// Tokens whose cancellation wakes up waiting servers
+    wakeups: CancelWakeups,
+}
+
+
+/// Mutable state of a stream channel
+#[derive(Default)]
+struct StreamState {
+    /// Items which were pushed, but not consumed yet
+    items: VecDeque<Box<dyn Any + Send>>,
+
+    /// Whether the operation is over, so that no item will be pushed anymore
+    closed: bool,
+}
+
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use client::{IAsyncOpClient, IAsyncOpStreamClient};
+    use executor::inline::InlineCallbackExecutor;
+    use multithread::{blocking, callback, polling};
+    use status::{self, AsyncOpStatus, AsyncOpStatusTraits, NoDetails};
+    use std::sync::mpsc;
+    use std::thread;
+    use stream::*;
+
+    /// Status details of operations which stream search hits
+    #[derive(Clone, Debug, PartialEq)]
+    struct SearchDetails {}
+    //
+    impl AsyncOpStatusDetails for SearchDetails {
+        type PendingDetails = NoDetails;
+        type RunningDetails = NoDetails;
+        type DoneDetails = NoDetails;
+        type CancelledDetails = NoDetails;
+        type ErrorDetails = NoDetails;
+    }
+    //
+    impl AsyncOpStatusTraits for SearchDetails {}
+    //
+    impl AsyncOpStreamDetails for SearchDetails {
+        type StreamItem = usize;
+        const STREAM_CAPACITY: usize = 2;
+    }
+
+    /// Running status of our test operations
+    fn running() -> AsyncOpStatus<SearchDetails> {
+        AsyncOpStatus::Running(status::NO_DETAILS)
+    }
+
+    /// Check that items are received in order, and that Done ends the stream
+    #[test]
+    fn ordering() {
+        let (mut server, mut client) =
+            blocking::AsyncOp::new(running()).split();
+        let producer = thread::spawn(move || {
+            for hit in 0..100 {
+                assert!(server.push_item(hit));
+            }
+            server.update(AsyncOpStatus::Done(status::NO_DETAILS));
+        });
+        let hits = (0..).map(|_| client.next_item())
+                        .take_while(Option::is_some)
+                        .map(Option::unwrap)
+                        .collect::<Vec<_>>();
+        assert_eq!(hits, (0..100).collect::<Vec<_>>());
+        assert!(client.stream_finished());
+        producer.join().unwrap();
+    }
+
+    /// Push items from another thread until one is discarded, reporting each
+    /// item which was pushed through a channel
+    fn produce(
+        mut server: polling::AsyncOpServer<SearchDetails>
+    ) -> (thread::JoinHandle<()>, mpsc::Receiver<usize>) {
+        let (sender, receiver) = mpsc::channel();
+        let producer = thread::spawn(move || {
+            for hit in 0..4 {
+                if !server.push_item(hit) { break; }
+                sender.send(hit).unwrap();
+            }
+        });
+        (producer, receiver)
+    }
+
+    /// Check that servers are blocked when the client falls behind, until it
+    /// catches up or cancels the operation
+    #[test]
+    fn backpressure() {
+        let (server, mut client) = polling::AsyncOp::new(running()).split();
+        let (producer, pushed) = produce(server);
+        assert_eq!(pushed.recv(), Ok(0));
+        assert_eq!(pushed.recv(), Ok(1));
+        assert!(pushed.try_recv().is_err());
+        assert_eq!(client.try_next_item(), Some(0));
+        assert_eq!(pushed.recv(), Ok(2));
+
+        // Cancellation should unblock the server
+        client.cancel();
+        producer.join().unwrap();
+        assert!(pushed.try_recv().is_err());
+    }
+
+    /// Check that servers are unblocked once every client is gone
+    #[test]
+    fn client_drop() {
+        let (server, mut client) = polling::AsyncOp::new(running()).split();
+        let subscriber = client.subscribe();
+        let (producer, pushed) = produce(server);
+        assert_eq!(pushed.recv(), Ok(0));
+        assert_eq!(pushed.recv(), Ok(1));
+
+        // Remaining subscribers should keep the stream alive...
+        ::std::mem::drop(client);
+        assert!(pushed.try_recv().is_err());
+
+        // ...but the server should not wait for anyone once they are gone
+        ::std::mem::drop(subscriber);
+        producer.join().unwrap();
+        assert!(pushed.try_recv().is_err());
+    }
+
+    /// Check that callback clients can consume streams too
+    #[test]
+    fn callback() {
+        let mut executor = InlineCallbackExecutor::new();
+        let (mut server, mut client) =
+            callback::new_async_op(|_: AsyncOpStatus<SearchDetails>| {},
+                                   &mut executor,
+                                   running()).split();
+        assert!(server.push_item(42));
+        assert_eq!(client.try_next_item(), Some(42));
+        assert_eq!(client.try_next_item(), None);
+        assert!(!client.stream_finished());
+
+        // Killing the server should end the stream too
+        ::std::mem::drop(server);
+        assert_eq!(client.next_item(), None);
+        assert!(client.stream_finished());
+    }
+}
@@ -0,0 +1,228 @@
+//! Deadlines for asynchronous operations
+//!
+//! Many operations should not be allowed to run forever: a network request may
+//! never get an answer, a device may hang... This module allows putting a
+//! deadline on an operation. If the operation has not reached a final status
+//! by then, its client is notified with the standard `AsyncOpError::TimedOut`
+//! error, and the server is asked to stop working through the usual
+//! cancellation mechanism.
+//!
+//! Deadlines are enforced on the server side, so that they work the same way
+//! in every monitoring mode: polling clients see the error on their next
+//! status query, blocking clients are woken up, and callbacks are called. All
+//! deadlines are managed by a single shared timer thread, which is also why
+//! the server must be Send: for callback operations, this means that they
+//! must be created with `callback::new_send_async_op()`.
+//!
+//! Once the deadline has passed, the server's status updates are discarded,
+//! since the client has already been told about the final operation status.
+
+use channels::OpChannels;
+use server::{AsyncOpServer, AsyncOpServerConfig};
+use status::{self, AsyncOpError, AsyncOpStatus};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use timer::{self, TimerId};
+
+
+/// Put a deadline on an operation, given the server of that operation
+///
+/// If the operation has not reached a final status by the deadline, its client
+/// will be notified that it timed out, and the server will see it as cancelled.
+///
+pub fn with_deadline<Config>(
+    server: AsyncOpServer<Config>,
+    deadline: Instant
+) -> AsyncOpServer<TimeoutServerConfig<Config>>
+    where Config: AsyncOpServerConfig + Send + 'static
+{
+    // Operations which are already over cannot time out
+    let finished = server.is_final();
+    let instrumentation = server.instrumentation();
+    server.map_config(move |config| {
+        let channels =
+            config.channels().with_cancellation(config.cancellation().child());
+        let state = Arc::new(Mutex::new(TimeoutState {
+            inner: config.into_inner(),
+            finished,
+            timed_out: false,
+        }));
+        let timer_state = state.clone();
+        let timer_cancellation = channels.cancellation().clone();
+        let timer = if finished {
+            None
+        } else {
+            Some(timer::schedule(deadline, move || {
+                let mut state = timer_state.lock().unwrap();
+                let timed_out = !state.finished;
+                if timed_out {
+                    state.finished = true;
+                    state.timed_out = true;
+                }
+                ::std::mem::drop(state);
+
+                // Ask the server to stop before notifying the client, which
+                // may otherwise see a timeout that the server does not know
+                // about yet
+                timer_cancellation.cancel();
+                if timed_out {
+                    let status = AsyncOpStatus::Error(AsyncOpError::TimedOut);
+                    instrumentation.record(&status);
+                    timer_state.lock().unwrap().inner.update(status);
+                }
+            }))
+        };
+        TimeoutServerConfig {
+            state,
+            timer,
+            channels,
+        }
+    })
+}
+
+
+/// Put a timeout on an operation, counting from now
+pub fn with_timeout<Config>(
+    server: AsyncOpServer<Config>,
+    timeout: Duration
+) -> AsyncOpServer<TimeoutServerConfig<Config>>
+    where Config: AsyncOpServerConfig + Send + 'static
+{
+    with_deadline(server, Instant::now() + timeout)
+}
+
+
+/// Server configuration wrapper which enforces an operation deadline
+pub struct TimeoutServerConfig<Config: AsyncOpServerConfig> {
+    /// State shared with the timer thread
+    state: Arc<Mutex<TimeoutState<Config>>>,
+
+    /// Timed action which will fail the operation, if any
+    timer: Option<TimerId>,
+
+    /// Side channels of the wrapped configuration, which is behind the mutex,
+    /// with a token which is cancelled by the client or when the deadline
+    /// passes
+    channels: OpChannels,
+}
+//
+impl<Config: AsyncOpServerConfig> TimeoutServerConfig<Config> {
+    /// Check whether the operation's deadline has passed
+    pub fn timed_out(&self) -> bool {
+        self.state.lock().unwrap().timed_out
+    }
+}
+//
+impl<Config: AsyncOpServerConfig> AsyncOpServerConfig
+    for TimeoutServerConfig<Config>
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Config::StatusDetails;
+
+    /// Forward status updates to the client, unless it has timed out
+    fn update(&mut self, status: AsyncOpStatus<Self::StatusDetails>) {
+        let mut state = self.state.lock().unwrap();
+        if state.finished { return; }
+        state.finished = status::is_final(&status);
+        state.inner.update(status);
+
+        // Once the operation is over, its deadline doesn't matter anymore
+        if state.finished {
+            if let Some(timer) = self.timer.take() {
+                timer::cancel(timer);
+            }
+        }
+    }
+
+    /// Side channels shared with the client, whose token also asks the server
+    /// to stop when the deadline passes
+    fn channels(&self) -> &OpChannels {
+        &self.channels
+    }
+}
+
+
+/// State of an operation with a deadline, shared with the timer thread
+struct TimeoutState<Config: AsyncOpServerConfig> {
+    /// Server configuration which we are wrapping
+    inner: Config,
+
+    /// Whether the client has been notified of a final status
+    finished: bool,
+
+    /// Whether the deadline has passed before the operation was over
+    timed_out: bool,
+}
+
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use executor::inline::InlineCallbackExecutor;
+    use multithread::{blocking, callback, polling};
+    use status::{self, StandardAsyncOpStatus};
+    use std::sync::mpsc;
+    use std::thread;
+    use timeout::*;
+
+    /// Check that operations which finish in time are unaffected
+    #[test]
+    fn in_time() {
+        let (server, mut client) =
+            polling::AsyncOp::new(status::PENDING).split();
+        let mut server = with_timeout(server, Duration::from_secs(60));
+        server.update(status::RUNNING);
+        assert_eq!(*client.status(), status::RUNNING);
+        server.update(status::DONE);
+        assert_eq!(*client.status(), status::DONE);
+        assert!(!server.cancelled());
+    }
+
+    /// Check that polling clients see late operations time out, and that the
+    /// server is asked to stop and ignored from then on
+    #[test]
+    fn polling() {
+        let (server, mut client) =
+            polling::AsyncOp::new(status::PENDING).split();
+        let mut server = with_timeout(server, Duration::from_millis(10));
+        server.update(status::RUNNING);
+        while !server.cancelled() {
+            thread::yield_now();
+        }
+        assert_eq!(*client.status(), status::ERROR_TIMED_OUT);
+        server.update(status::DONE);
+        assert_eq!(*client.status(), status::ERROR_TIMED_OUT);
+    }
+
+    /// Check that blocking clients are woken up by timeouts
+    #[test]
+    fn blocking() {
+        let (server, mut client) =
+            blocking::AsyncOp::new(status::PENDING).split();
+        let server = with_timeout(server, Duration::from_millis(10));
+        let mut status = client.status();
+        while !status::is_final(&status) {
+            status = client.wait();
+        }
+        assert_eq!(status, status::ERROR_TIMED_OUT);
+        assert!(server.cancelled());
+    }
+
+    /// Check that callbacks are notified of timeouts
+    #[test]
+    fn callback() {
+        let (sender, receiver) = mpsc::channel();
+        let callback = move |s: StandardAsyncOpStatus| sender.send(s).unwrap();
+        let mut executor = InlineCallbackExecutor::new();
+        let (server, _client) =
+            callback::new_send_async_op(callback,
+                                        &mut executor,
+                                        status::PENDING).split();
+        let server = with_timeout(server, Duration::from_millis(10));
+        assert_eq!(receiver.recv().unwrap(), status::ERROR_TIMED_OUT);
+
+        // Dropping the server should not produce a second notification
+        ::std::mem::drop(server);
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -0,0 +1,199 @@
+//! Shared timer thread, for actions which must happen at a certain time
+//!
+//! Some features, such as operation deadlines, require something to happen at
+//! a given point in time, no matter what the client and server are doing.
+//! Spawning one thread per operation for this purpose would not scale, so all
+//! timed actions are instead handled by a single timer thread, which is lazily
+//! started the first time an action is scheduled.
+//!
+//! Timed actions run on the timer thread, so they should be short and must not
+//! block, as they would otherwise delay every other timed action.
+
+use std::collections::{BTreeMap, HashMap};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Condvar, Mutex, OnceLock};
+use std::thread;
+use std::time::Instant;
+
+
+/// Identifier of a scheduled timed action
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct TimerId(u64);
+
+
+/// Schedule an action to be run by the timer thread at some point in time
+pub fn schedule<F>(deadline: Instant, action: F) -> TimerId
+    where F: FnOnce() + Send + 'static
+{
+    let timer = Timer::get();
+    let mut state = timer.state.lock().unwrap();
+    let id = TimerId(state.next_id);
+    state.next_id += 1;
+    state.deadlines.insert(id, deadline);
+    state.actions.insert((deadline, id), Box::new(action));
+    timer.wakeup.notify_one();
+    id
+}
+
+
+/// Unschedule a timed action, returning true if it had not run yet
+pub fn cancel(id: TimerId) -> bool {
+    let timer = Timer::get();
+    let mut state = timer.state.lock().unwrap();
+    match state.deadlines.remove(&id) {
+        Some(deadline) => state.actions.remove(&(deadline, id)).is_some(),
+        None => false,
+    }
+}
+
+
+/// Timed action, as stored by the timer
+type Action = Box<dyn FnOnce() + Send>;
+
+
+/// Process-wide timer
+struct Timer {
+    /// Scheduled actions
+    state: Mutex<TimerState>,
+
+    /// Condition variable used to wake up the timer thread when an action is
+    /// scheduled, since it may be due before the ones we were waiting for
+    wakeup: Condvar,
+}
+//
+impl Timer {
+    /// Access the process-wide timer, starting its thread if needed
+    fn get() -> &'static Timer {
+        static TIMER: OnceLock<Timer> = OnceLock::new();
+        let mut started = false;
+        let timer = TIMER.get_or_init(|| {
+            started = true;
+            Timer {
+                state: Mutex::new(TimerState {
+                    next_id: 0,
+                    deadlines: HashMap::new(),
+                    actions: BTreeMap::new(),
+                }),
+                wakeup: Condvar::new(),
+            }
+        });
+        if started {
+            thread::Builder::new()
+                .name("async-ops-timer".to_owned())
+                .spawn(move || timer.run())
+                .expect("Failed to start the timer thread");
+        }
+        timer
+    }
+
+    /// Timer thread's main loop: run timed actions as they become due
+    fn run(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            // Collect the actions which are due
+            let now = Instant::now();
+            let mut due = Vec::new();
+            while let Some(entry) = state.actions.first_entry() {
+                let (deadline, id) = *entry.key();
+                if deadline > now { break; }
+                due.push(entry.remove());
+                state.deadlines.remove(&id);
+            }
+
+            // Run them without holding the lock, as they may schedule more
+            if !due.is_empty() {
+                ::std::mem::drop(state);
+                for action in due {
+                    // If an action panics, the timer thread must keep going,
+                    // as every other timed action in the process relies on it
+                    let _ = panic::catch_unwind(AssertUnwindSafe(action));
+                }
+                state = self.state.lock().unwrap();
+                continue;
+            }
+
+            // Wait for the next deadline, or for a new action to be scheduled
+            state = match state.actions.keys().next() {
+                Some(&(deadline, _)) => {
+                    self.wakeup.wait_timeout(state, deadline - now)
+                               .unwrap()
+                               .0
+                },
+                None => self.wakeup.wait(state).unwrap(),
+            };
+        }
+    }
+}
+
+
+/// State of the process-wide timer
+struct TimerState {
+    /// Identifier of the next timed action to be scheduled
+    next_id: u64,
+
+    /// Deadlines of the scheduled actions, used to find them on cancellation
+    deadlines: HashMap<TimerId, Instant>,
+
+    /// Scheduled actions, earliest deadline first
+    actions: BTreeMap<(Instant, TimerId), Action>,
+}
+
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use timer::*;
+
+    /// Check that timed actions run in deadline order
+    #[test]
+    fn ordering() {
+        let (sender, receiver) = mpsc::channel();
+        let now = Instant::now();
+        for &(delay, value) in &[(30, 3), (10, 1), (20, 2)] {
+            let sender = sender.clone();
+            schedule(now + Duration::from_millis(delay), move || {
+                sender.send(value).unwrap();
+            });
+        }
+        let values = receiver.iter().take(3).collect::<Vec<_>>();
+        assert_eq!(values, vec![1, 2, 3]);
+        assert!(Instant::now() >= now + Duration::from_millis(30));
+    }
+
+    /// Check that timed actions can be cancelled
+    #[test]
+    fn cancellation() {
+        let (sender, receiver) = mpsc::channel();
+        let now = Instant::now();
+        let cancelled_sender = sender.clone();
+        let id = schedule(now + Duration::from_secs(10), move || {
+            cancelled_sender.send(1).unwrap();
+        });
+        schedule(now + Duration::from_millis(20), move || {
+            sender.send(2).unwrap();
+        });
+        assert!(cancel(id));
+        assert!(!cancel(id));
+        {
+            let state = Timer::get().state.lock().unwrap();
+            assert!(!state.deadlines.contains_key(&id));
+            assert!(state.actions.keys().all(|&(_, other)| other != id));
+        }
+        assert_eq!(receiver.recv().unwrap(), 2);
+        assert!(receiver.recv().is_err());
+    }
+
+    /// Check that a panicking action does not bring the timer down
+    #[test]
+    fn panicking_action() {
+        let (sender, receiver) = mpsc::channel();
+        let now = Instant::now();
+        schedule(now + Duration::from_millis(10), || panic!("Expected panic"));
+        schedule(now + Duration::from_millis(20), move || {
+            sender.send(()).unwrap();
+        });
+        assert_eq!(receiver.recv(), Ok(()));
+    }
+}
@@ -0,0 +1,193 @@
+//! User events: operations completed by the client itself
+//!
+//! Sometimes, the completion of an operation is not driven by a server thread,
+//! but by application code reacting to some external event, such as user
+//! input, a message from the network, or a callback from a foreign library.
+//! Following OpenCL's terminology, we call such operations user events.
+//!
+//! A user event is the server half of an operation, held by application code,
+//! with convenience methods for walking through the operation state machine.
+//! It can be built on top of an operation in any monitoring mode, and since it
+//! behaves like any other server, it can be plugged into the dependency graph
+//! or any other machinery which works with asynchronous operation servers.
+//!
+//! For tests and fast paths, this module also provides operations which have
+//! already reached their final status upon creation.
+
+use multithread::polling::{self, AsyncOp, AsyncOpClient, PollingServerConfig};
+use server::{AsyncOpServer, AsyncOpServerConfig};
+use status::{self, AsyncOpCloneableDetails, AsyncOpError, AsyncOpStatus,
+             AsyncOpStatusDetails};
+
+
+/// Shorthand for the status details of a server configuration
+type DetailsOf<Config> = <Config as AsyncOpServerConfig>::StatusDetails;
+
+
+/// Operation whose status is set by application code
+pub struct UserEvent<Config: AsyncOpServerConfig> {
+    /// Server interface of the underlying operation
+    server: AsyncOpServer<Config>,
+}
+//
+impl<Config: AsyncOpServerConfig> UserEvent<Config> {
+    /// Turn the server of an operation, in any monitoring mode, into a user
+    /// event whose status will be set by application code
+    pub fn from_server(server: AsyncOpServer<Config>) -> Self {
+        UserEvent { server }
+    }
+
+    /// Switch to a new non-final status
+    pub fn set_status(&mut self, status: AsyncOpStatus<DetailsOf<Config>>) {
+        debug_assert!(!status::is_final(&status),
+                      "Use complete(), fail() or cancel() to finish the event");
+        self.server.update(status);
+    }
+
+    /// Mark the operation as running
+    pub fn set_running(
+        &mut self,
+        details: <DetailsOf<Config> as AsyncOpStatusDetails>::RunningDetails
+    ) {
+        self.server.update(AsyncOpStatus::Running(details));
+    }
+
+    /// Mark the operation as successfully completed
+    pub fn complete(
+        mut self,
+        details: <DetailsOf<Config> as AsyncOpStatusDetails>::DoneDetails
+    ) {
+        self.server.update(AsyncOpStatus::Done(details));
+    }
+
+    /// Mark the operation as failed
+    pub fn fail(
+        mut self,
+        details: <DetailsOf<Config> as AsyncOpStatusDetails>::ErrorDetails
+    ) {
+        self.server.update(
+            AsyncOpStatus::Error(AsyncOpError::CustomError(details))
+        );
+    }
+
+    /// Mark the operation as cancelled
+    pub fn cancel(
+        mut self,
+        details: <DetailsOf<Config> as AsyncOpStatusDetails>::CancelledDetails
+    ) {
+        self.server.update(AsyncOpStatus::Cancelled(details));
+    }
+
+    /// Check whether the client has requested the operation's cancellation
+    pub fn cancel_requested(&self) -> bool {
+        self.server.cancelled()
+    }
+}
+
+
+/// Create a user event with polling-based monitoring, along with its client
+pub fn new<Details: AsyncOpCloneableDetails>(
+    initial_status: AsyncOpStatus<Details>
+) -> (UserEvent<PollingServerConfig<Details>>, AsyncOpClient<Details>) {
+    let (server, client) = AsyncOp::new(initial_status).split();
+    (UserEvent::from_server(server), client)
+}
+
+
+/// Create an operation which has already reached its final status
+pub fn ready<Details: AsyncOpCloneableDetails>(
+    final_status: AsyncOpStatus<Details>
+) -> AsyncOpClient<Details> {
+    assert!(status::is_final(&final_status),
+            "Ready operations must have a final status");
+    let (_, client): (polling::AsyncOpServer<Details>, _) =
+        AsyncOp::new(final_status).split();
+    client
+}
+
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use client::IAsyncOpClient;
+    use dependency::DependencyGraph;
+    use multithread::blocking;
+    use status::{self, NO_DETAILS};
+    use user_event::*;
+
+    /// Check that user events walk through the operation state machine
+    #[test]
+    fn state_machine() {
+        // Successful completion
+        let (mut event, mut client) = new(status::PENDING);
+        assert_eq!(*client.status(), status::PENDING);
+        event.set_running(NO_DETAILS);
+        assert_eq!(*client.status(), status::RUNNING);
+        event.complete(NO_DETAILS);
+        assert_eq!(*client.status(), status::DONE);
+
+        // Failure
+        let (event, mut client) = new(status::RUNNING);
+        event.fail(NO_DETAILS);
+        assert_eq!(*client.status(),
+                   AsyncOpStatus::Error(AsyncOpError::CustomError(NO_DETAILS)));
+
+        // Cancellation, as requested by the client
+        let (event, mut client) = new(status::PENDING);
+        assert!(!event.cancel_requested());
+        client.cancel();
+        assert!(event.cancel_requested());
+        event.cancel(NO_DETAILS);
+        assert_eq!(*client.status(), status::CANCELLED);
+
+        // Forgetting about a user event should not leave its client hanging
+        let (event, mut client) = new(status::PENDING);
+        ::std::mem::drop(event);
+        assert_eq!(*client.status(), status::ERROR_SERVER_KILLED);
+    }
+
+    /// Check that user events work in other monitoring modes
+    #[test]
+    fn other_modes() {
+        let (server, mut client) =
+            blocking::AsyncOp::new(status::PENDING).split();
+        let event = UserEvent::from_server(server);
+        event.complete(NO_DETAILS);
+        assert_eq!(client.wait(), status::DONE);
+    }
+
+    /// Check that user events can be used as prerequisites
+    #[test]
+    fn dependencies() {
+        // Make an operation wait for a user event
+        let graph = DependencyGraph::new();
+        let (server, _client) = AsyncOp::new(status::PENDING).split();
+        let (event_id, event_server) = graph.track(server);
+        let event = UserEvent::from_server(event_server);
+        let (server, mut client) = AsyncOp::new(status::PENDING).split();
+        graph.submit(server, &[event_id], |mut server| {
+            server.update(status::DONE)
+        }).unwrap();
+        assert_eq!(*client.status(), status::PENDING);
+
+        // Completing the user event should release the operation
+        event.complete(NO_DETAILS);
+        assert_eq!(*client.status(), status::DONE);
+    }
+
+    /// Check that ready operations behave as expected
+    #[test]
+    fn ready_ops() {
+        let mut client = ready(status::DONE);
+        assert_eq!(*client.status(), status::DONE);
+        let mut client = ready(status::CANCELLED);
+        assert_eq!(*client.status(), status::CANCELLED);
+    }
+
+    /// Check that ready operations must have a final status
+    #[test]
+    #[should_panic]
+    fn not_ready() {
+        ready(status::RUNNING);
+    }
+}
@@ -0,0 +1,359 @@
+//! Stall detection for asynchronous operations
+//!
+//! From the client's point of view, an operation which is stuck in the Running
+//! state because its server is deadlocked looks exactly like a slow operation.
+//! This module tells them apart by having servers show signs of life: every
+//! status update counts as one, and servers which go for a while without
+//! sending any can call heartbeat() instead.
+//!
+//! A watchdog, run by the shared timer thread, checks that the last sign of
+//! life is not older than some threshold. What happens otherwise depends on
+//! the chosen stall policy:
+//!
+//! - The operation can be flagged as stalled, through a flag which is returned
+//!   along with the server, so that it can be handed to clients. The flag is
+//!   cleared when the server shows signs of life again.
+//! - The operation can fail with the standard `AsyncOpError::Stalled` error,
+//!   in which case the server is asked to stop through the usual cancellation
+//!   mechanism, and its subsequent status updates are discarded.
+//!
+//! Like deadlines, stall detection happens on the server side, so it works the
+//! same way in every monitoring mode.
+
+use channels::OpChannels;
+use instrument::Instrumentation;
+use server::{AsyncOpServer, AsyncOpServerConfig};
+use status::{self, AsyncOpError, AsyncOpStatus};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use timer::{self, TimerId};
+
+
+/// What to do with operations whose server has stalled
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StallPolicy {
+    /// Raise the operation's stall flag, until the server shows signs of life
+    Flag,
+
+    /// Fail the operation with the `AsyncOpError::Stalled` error
+    Fail,
+}
+
+
+/// Watch over an operation, given the server of that operation
+///
+/// If the server goes for longer than `threshold` without sending a status
+/// update or a heartbeat, the operation is considered stalled, and handled
+/// according to the stall policy. The operation's stall flag is returned
+/// along with the server, so that it can be handed to clients.
+///
+pub fn with_watchdog<Config>(
+    server: AsyncOpServer<Config>,
+    threshold: Duration,
+    policy: StallPolicy
+) -> (AsyncOpServer<WatchdogServerConfig<Config>>, StallFlag)
+    where Config: AsyncOpServerConfig + Send + 'static
+{
+    // Operations which are already over cannot stall
+    let finished = server.is_final();
+    let instrumentation = server.instrumentation();
+    let server = server.map_config(move |config| {
+        let channels =
+            config.channels().with_cancellation(config.cancellation().child());
+        let watchdog = Arc::new(Watchdog {
+            channels,
+            stall_flag: StallFlag::default(),
+            instrumentation,
+            state: Mutex::new(WatchdogState {
+                inner: config.into_inner(),
+                threshold,
+                policy,
+                last_beat: Instant::now(),
+                finished,
+                timer: None,
+            }),
+        });
+        if !finished {
+            let mut state = watchdog.state.lock().unwrap();
+            let due = state.last_beat + threshold;
+            state.timer = Some(Watchdog::schedule_check(&watchdog, due));
+        }
+        WatchdogServerConfig { watchdog }
+    });
+    let stall_flag = server.config().stall_flag();
+    (server, stall_flag)
+}
+
+
+/// Flag which tells whether an operation is currently stalled
+///
+/// This object can be cheaply cloned, and all clones share the same state, so
+/// that clients can be handed a copy of it.
+///
+#[derive(Clone, Debug, Default)]
+pub struct StallFlag {
+    /// Whether the operation is currently stalled
+    stalled: Arc<AtomicBool>,
+}
+//
+impl StallFlag {
+    /// Check whether the operation is currently stalled
+    pub fn is_stalled(&self) -> bool {
+        self.stalled.load(Ordering::Acquire)
+    }
+
+    /// Set or clear the stall flag
+    fn set(&self, stalled: bool) {
+        self.stalled.store(stalled, Ordering::Release);
+    }
+}
+
+
+/// Server configuration wrapper which detects stalled servers
+pub struct WatchdogServerConfig<Config: AsyncOpServerConfig> {
+    /// State shared with the timer thread
+    watchdog: Arc<Watchdog<Config>>,
+}
+//
+impl<Config> WatchdogServerConfig<Config>
+    where Config: AsyncOpServerConfig + Send + 'static
+{
+    /// Get a handle to the operation's stall flag, which can be given to
+    /// clients
+    pub fn stall_flag(&self) -> StallFlag {
+        self.watchdog.stall_flag.clone()
+    }
+
+    /// Show that the server is still alive, without sending a status update
+    pub fn heartbeat(&self) {
+        let mut state = self.watchdog.state.lock().unwrap();
+        state.beat(&self.watchdog, Instant::now());
+    }
+}
+//
+impl<Config> AsyncOpServerConfig for WatchdogServerConfig<Config>
+    where Config: AsyncOpServerConfig + Send + 'static
+{
+    /// Implementation details of the asynchronous operation status
+    type StatusDetails = Config::StatusDetails;
+
+    /// Forward status updates to the client, unless the operation failed due
+    /// to a stall, and take them as signs of life
+    fn update(&mut self, status: AsyncOpStatus<Self::StatusDetails>) {
+        let mut state = self.watchdog.state.lock().unwrap();
+        if state.finished { return; }
+        state.finished = status::is_final(&status);
+        state.inner.update(status);
+
+        // Once the operation is over, it cannot stall anymore
+        if state.finished {
+            if let Some(timer) = state.timer.take() {
+                timer::cancel(timer);
+            }
+            self.watchdog.stall_flag.set(false);
+        } else {
+            state.beat(&self.watchdog, Instant::now());
+        }
+    }
+
+    /// Side channels shared with the client, whose token also asks the server
+    /// to stop when it failed to show signs of life in time
+    fn channels(&self) -> &OpChannels {
+        &self.watchdog.channels
+    }
+}
+//
+impl<Config> AsyncOpServer<WatchdogServerConfig<Config>>
+    where Config: AsyncOpServerConfig + Send + 'static
+{
+    /// Show that the server is still alive, without sending a status update
+    pub fn heartbeat(&self) {
+        self.config().heartbeat();
+    }
+}
+
+
+/// State of a watched operation, shared with the timer thread
+struct Watchdog<Config: AsyncOpServerConfig> {
+    /// Side channels of the wrapped configuration, which is behind the mutex,
+    /// with a token which is cancelled by the client or when the server stalls
+    channels: OpChannels,
+
+    /// Flag which tells clients whether the operation is currently stalled
+    stall_flag: StallFlag,
+
+    /// Instrumentation of the operation, which must hear about stalls
+    instrumentation: Instrumentation,
+
+    /// Mutable state of the watched operation
+    state: Mutex<WatchdogState<Config>>,
+}
+//
+impl<Config> Watchdog<Config>
+    where Config: AsyncOpServerConfig + Send + 'static
+{
+    /// Have the timer thread check for signs of life at some point in time
+    fn schedule_check(watchdog: &Arc<Self>, due: Instant) -> TimerId {
+        let watchdog = watchdog.clone();
+        timer::schedule(due, move || Self::check(&watchdog, Instant::now()))
+    }
+
+    /// Check for signs of life, as of some point in time
+    fn check(watchdog: &Arc<Self>, now: Instant) {
+        let mut state = watchdog.state.lock().unwrap();
+        if let Some(timer) = state.timer.take() {
+            timer::cancel(timer);
+        }
+        if state.finished { return; }
+
+        // If the server showed signs of life since the check was scheduled,
+        // check again later on
+        let due = state.last_beat + state.threshold;
+        if now < due {
+            state.timer = Some(Self::schedule_check(watchdog, due));
+            return;
+        }
+
+        // Otherwise, the server has stalled
+        match state.policy {
+            StallPolicy::Flag => watchdog.stall_flag.set(true),
+            StallPolicy::Fail => {
+                // Ask the server to stop before notifying the client, which
+                // may otherwise see a failure that the server does not know
+                // about yet
+                state.finished = true;
+                ::std::mem::drop(state);
+                watchdog.channels.cancellation().cancel();
+                let status = AsyncOpStatus::Error(AsyncOpError::Stalled);
+                watchdog.instrumentation.record(&status);
+                watchdog.state.lock().unwrap().inner.update(status);
+            },
+        }
+    }
+}
+
+
+/// Mutable state of a watched operation
+struct WatchdogState<Config: AsyncOpServerConfig> {
+    /// Server configuration which we are wrapping
+    inner: Config,
+
+    /// Maximal time which the server may spend without showing signs of life
+    threshold: Duration,
+
+    /// What to do when the server has stalled
+    policy: StallPolicy,
+
+    /// Last time at which the server showed signs of life
+    last_beat: Instant,
+
+    /// Whether the client has been notified of a final status
+    finished: bool,
+
+    /// Timed action which will check for signs of life, if any. There is none
+    /// when the operation is over or flagged as stalled.
+    timer: Option<TimerId>,
+}
+//
+impl<Config> WatchdogState<Config>
+    where Config: AsyncOpServerConfig + Send + 'static
+{
+    /// Record a sign of life from the server, at some point in time
+    fn beat(&mut self, watchdog: &Arc<Watchdog<Config>>, now: Instant) {
+        if self.finished { return; }
+        self.last_beat = now;
+        if self.timer.is_none() {
+            watchdog.stall_flag.set(false);
+            let due = self.last_beat + self.threshold;
+            self.timer = Some(Watchdog::schedule_check(watchdog, due));
+        }
+    }
+}
+
+
+/// Unit tests
+#[cfg(test)]
+mod tests {
+    use client::IAsyncOpClient;
+    use multithread::{blocking, polling};
+    use status;
+    use watchdog::*;
+
+    /// Threshold which is long enough for the timer never to get involved
+    const THRESHOLD: Duration = Duration::from_secs(3600);
+
+    /// Check that stalled operations are flagged until they show signs of life
+    #[test]
+    fn flag() {
+        let (server, mut client) =
+            polling::AsyncOp::new(status::PENDING).split();
+        let (mut server, stall_flag) =
+            with_watchdog(server, THRESHOLD, StallPolicy::Flag);
+        let watchdog = server.config().watchdog.clone();
+        Watchdog::check(&watchdog, Instant::now());
+        assert!(!stall_flag.is_stalled());
+        Watchdog::check(&watchdog, Instant::now() + THRESHOLD);
+        assert!(stall_flag.is_stalled());
+
+        // Status updates should clear the flag, and be delivered as usual
+        server.update(status::RUNNING);
+        assert!(!stall_flag.is_stalled());
+        assert_eq!(*client.status(), status::RUNNING);
+        assert!(!server.cancelled());
+
+        // Final statuses should too, and the operation cannot stall anymore
+        Watchdog::check(&watchdog, Instant::now() + THRESHOLD);
+        assert!(stall_flag.is_stalled());
+        server.update(status::DONE);
+        assert!(!stall_flag.is_stalled());
+        Watchdog::check(&watchdog, Instant::now() + THRESHOLD);
+        assert!(!stall_flag.is_stalled());
+    }
+
+    /// Check that heartbeats keep operations alive
+    #[test]
+    fn heartbeat() {
+        let (server, _client) = polling::AsyncOp::new(status::RUNNING).split();
+        let (server, stall_flag) =
+            with_watchdog(server, THRESHOLD, StallPolicy::Fail);
+        server.heartbeat();
+        let watchdog = server.config().watchdog.clone();
+
+        // A server which beats halfway through the threshold is still alive
+        // when the threshold is reached...
+        let start = watchdog.state.lock().unwrap().last_beat;
+        let halfway = start + THRESHOLD / 2;
+        watchdog.state.lock().unwrap().beat(&watchdog, halfway);
+        Watchdog::check(&watchdog, start + THRESHOLD);
+        assert!(!stall_flag.is_stalled());
+        assert!(!server.cancelled());
+
+        // ...but not once the threshold has passed again
+        Watchdog::check(&watchdog, halfway + THRESHOLD);
+        assert!(server.cancelled());
+    }
+
+    /// Check that stalled operations can be failed, that blocking clients are
+    /// woken up by the timer, and that the server is asked to stop and ignored
+    #[test]
+    fn fail() {
+        let (server, mut client) =
+            blocking::AsyncOp::new(status::RUNNING).split();
+        let (mut server, _) = with_watchdog(server,
+                                            Duration::from_millis(10),
+                                            StallPolicy::Fail);
+        assert_eq!(client.wait(), status::RUNNING);
+        assert_eq!(client.wait(), status::ERROR_STALLED);
+        assert!(server.cancelled());
+        server.update(status::DONE);
+        assert_eq!(client.status(), status::ERROR_STALLED);
+
+        // Client cancellation should still reach the server
+        let (server, mut client) =
+            blocking::AsyncOp::new(status::RUNNING).split();
+        let (server, _) = with_watchdog(server, THRESHOLD, StallPolicy::Fail);
+        client.cancel();
+        assert!(server.cancelled());
+    }
+}